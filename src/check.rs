@@ -0,0 +1,252 @@
+use std::{collections::HashMap, fs};
+
+use lsp_types::*;
+use serde_json::{json, Value};
+
+use crate::{includes::IncludeSources, lexer::MacroInfo, line_index::PositionEncoding, memory::Memory, project_files};
+
+/// The diagnostics of one shader, found without an editor.
+pub struct FileReport {
+    /// The path of the shader, relative to the project root.
+    pub path: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+    Sarif,
+}
+
+impl ReportFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(ReportFormat::Text),
+            "json" => Some(ReportFormat::Json),
+            "sarif" => Some(ReportFormat::Sarif),
+            _ => None
+        }
+    }
+}
+
+/// Analyses every shader under `root_dir`, the way the server analyses a
+/// document when it is opened. Includes are only checked through the shaders
+/// that include them, since on their own they lack a shader type.
+pub fn check_project(root_dir: &str, predefined_macros: &HashMap<String, MacroInfo>) -> Vec<FileReport> {
+    // `res://` paths are resolved by replacing the prefix with the root.
+    let root_dir = if root_dir.ends_with('/') { root_dir.to_string() } else { format!("{}/", root_dir) };
+    let include_sources = IncludeSources::new();
+    let mut reports = vec![];
+
+    for path in project_files(&root_dir, &["gdshader"]) {
+        let code = if let Ok(code) = fs::read_to_string(&path) { code } else { continue };
+        let mut memory = Memory::new(&code, Some(root_dir.clone()), PositionEncoding::default());
+        memory.predefined_macros = predefined_macros.clone();
+        memory.include_sources = include_sources.clone();
+        memory.uri = Url::from_file_path(&path).ok();
+        // Hints, such as inactive regions and unused declarations, are only
+        // meant to shade code in the editor.
+        let diagnostics = memory.evaluate_new(None)
+            .iter()
+            .filter(|x| x.severity != Some(DiagnosticSeverity::HINT))
+            .cloned()
+            .collect();
+
+        let relative = path.strip_prefix(&root_dir).unwrap_or(&path);
+        reports.push(FileReport { path: relative.to_string_lossy().replace('\\', "/"), diagnostics });
+    }
+    reports
+}
+
+pub fn has_errors(reports: &[FileReport]) -> bool {
+    reports
+        .iter()
+        .flat_map(|x| &x.diagnostics)
+        .any(|x| x.severity.is_none_or(|x| x == DiagnosticSeverity::ERROR))
+}
+
+pub fn format_reports(reports: &[FileReport], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Text => format_text(reports),
+        ReportFormat::Json => {
+            let files: Vec<Value> = reports
+                .iter()
+                .map(|x| json!({ "path": x.path, "diagnostics": x.diagnostics }))
+                .collect();
+            serde_json::to_string_pretty(&files).unwrap()
+        }
+        ReportFormat::Sarif => serde_json::to_string_pretty(&format_sarif(reports)).unwrap()
+    }
+}
+
+/// One line per diagnostic, with one-based lines and columns so that
+/// terminals and editors can jump to them, followed by a summary.
+fn format_text(reports: &[FileReport]) -> String {
+    let mut output = String::new();
+    let (mut errors, mut warnings) = (0, 0);
+    for report in reports {
+        for diagnostic in &report.diagnostics {
+            let start = diagnostic.range.start;
+            let severity = match diagnostic.severity {
+                Some(DiagnosticSeverity::WARNING) => { warnings += 1; "warning" }
+                Some(DiagnosticSeverity::INFORMATION) | Some(DiagnosticSeverity::HINT) => "info",
+                _ => { errors += 1; "error" }
+            };
+            output.push_str(&format!(
+                "{}:{}:{}: {}: {}\n",
+                report.path, start.line + 1, start.character + 1, severity, diagnostic.message
+            ));
+        }
+    }
+    output.push_str(&format!(
+        "Checked {} shaders: {} errors, {} warnings.\n",
+        reports.len(), errors, warnings
+    ));
+    output
+}
+
+/// A SARIF 2.1.0 log, which code scanning services can show on the lines of
+/// a pull request. Its columns count UTF-16 code units, like the protocol.
+fn format_sarif(reports: &[FileReport]) -> Value {
+    let results: Vec<Value> = reports
+        .iter()
+        .flat_map(|report| report.diagnostics.iter().map(move |x| (report, x)))
+        .map(|(report, diagnostic)| {
+            let level = match diagnostic.severity {
+                Some(DiagnosticSeverity::WARNING) => "warning",
+                Some(DiagnosticSeverity::INFORMATION) | Some(DiagnosticSeverity::HINT) => "note",
+                _ => "error"
+            };
+            let range = diagnostic.range;
+            json!({
+                "level": level,
+                "message": { "text": diagnostic.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": report.path, "uriBaseId": "SRCROOT" },
+                        "region": {
+                            "startLine": range.start.line + 1,
+                            "startColumn": range.start.character + 1,
+                            "endLine": range.end.line + 1,
+                            "endColumn": range.end.character + 1
+                        }
+                    }
+                }]
+            })
+        })
+        .collect();
+
+    json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "gdshader-lsp",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/GodOfAvacyn/gdshader-lsp"
+                }
+            },
+            "columnKind": "utf16CodeUnits",
+            "results": results
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` to a fresh directory under the system's temporary one.
+    fn project(name: &str, files: &[(&str, &str)]) -> String {
+        let root = std::env::temp_dir().join(format!("gdshader-lsp-{}-{}", name, std::process::id()));
+        _ = fs::remove_dir_all(&root);
+        for (path, code) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, code).unwrap();
+        }
+        root.to_string_lossy().to_string()
+    }
+
+    fn report() -> Vec<FileReport> {
+        let diagnostic = |line, severity, message: &str| Diagnostic {
+            range: Range::new(Position::new(line, 4), Position::new(line, 7)),
+            severity: Some(severity),
+            message: message.to_string(),
+            ..Default::default()
+        };
+        vec![
+            FileReport {
+                path: "a.gdshader".to_string(),
+                diagnostics: vec![
+                    diagnostic(1, DiagnosticSeverity::ERROR, "First."),
+                    diagnostic(2, DiagnosticSeverity::WARNING, "Second."),
+                ]
+            },
+            FileReport { path: "sub/b.gdshader".to_string(), diagnostics: vec![] },
+        ]
+    }
+
+    #[test]
+    fn hidden_directories_are_skipped() {
+        let root = project("check-hidden", &[
+            ("a.gdshader", "shader_type spatial;\nvoid vertex() { x = 1; }\n"),
+            ("sub/b.gdshader", "shader_type spatial;\n"),
+            (".godot/c.gdshader", "shader_type spatial;\nvoid vertex() { x = 1; }\n"),
+            ("notes.txt", ""),
+        ]);
+        let reports = check_project(&root, &HashMap::new());
+        let paths: Vec<&str> = reports.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.gdshader", "sub/b.gdshader"]);
+        assert!(has_errors(&reports));
+        assert!(!has_errors(&reports[1..]));
+        _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn only_errors_fail_the_check() {
+        let mut reports = report();
+        assert!(has_errors(&reports));
+        reports[0].diagnostics.remove(0);
+        assert!(!has_errors(&reports));
+    }
+
+    #[test]
+    fn text_reports_have_one_based_positions_and_a_summary() {
+        assert_eq!(
+            format_reports(&report(), ReportFormat::Text),
+            "a.gdshader:2:5: error: First.\n\
+             a.gdshader:3:5: warning: Second.\n\
+             Checked 2 shaders: 1 errors, 1 warnings.\n"
+        );
+    }
+
+    #[test]
+    fn json_reports_list_every_file_with_its_diagnostics() {
+        let output: Value = serde_json::from_str(&format_reports(&report(), ReportFormat::Json)).unwrap();
+        assert_eq!(output[0]["path"], "a.gdshader");
+        assert_eq!(output[0]["diagnostics"][1]["message"], "Second.");
+        assert_eq!(output[0]["diagnostics"][1]["severity"], 2);
+        assert_eq!(output[0]["diagnostics"][0]["range"]["start"], json!({ "line": 1, "character": 4 }));
+        assert_eq!(output[1]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn sarif_reports_locate_results_in_the_project() {
+        let output: Value = serde_json::from_str(&format_reports(&report(), ReportFormat::Sarif)).unwrap();
+        assert_eq!(output["version"], "2.1.0");
+        let results = &output["runs"][0]["results"];
+        assert_eq!(results.as_array().unwrap().len(), 2);
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(results[1]["level"], "warning");
+        assert_eq!(results[0]["message"]["text"], "First.");
+        let location = &results[0]["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "a.gdshader");
+        assert_eq!(
+            location["region"],
+            json!({ "startLine": 2, "startColumn": 5, "endLine": 2, "endColumn": 8 })
+        );
+    }
+}
@@ -1,6 +1,11 @@
 use lsp_types::*;
 
-use crate::{interpreter::evaluate_expression, memory::*, nodes::{ExpressionNode, TypeNode}};
+use crate::{
+    interpreter::evaluate_expression,
+    lexer::{join_token_text, TokenStream},
+    memory::*,
+    nodes::{ExpressionNode, TypeNode}
+};
 
 fn cast_types() -> Vec<String> {
     [
@@ -51,6 +56,9 @@ pub fn get_completion_items(
     cursor: Position,
     element: &CompletionElement
 ) -> Vec<CompletionItem>{
+    if let Some(items) = get_directive_completion_items(memory, cursor) {
+        return items;
+    }
     let scope = memory.scopes.find_scope_from_position(cursor);
     match element {
         CompletionElement::TopLevelKeyword => {
@@ -157,10 +165,12 @@ pub fn get_completion_items(
             [
                 "for",
                 "while",
+                "do",
                 "if",
                 "switch",
                 "continue",
                 "break",
+                "discard",
                 "return",
                 "const",
                 "true",
@@ -179,6 +189,7 @@ pub fn get_completion_items(
                         .filter(|x| !cast_types().contains(&x.label))
                         .collect::<Vec<_>>())
                 .chain(memory.get_variables(scope, false))
+                .chain(memory.get_macros())
                 .collect()
         }
         CompletionElement::Type => {
@@ -200,6 +211,7 @@ pub fn get_completion_items(
                 .chain(memory.get_functions(cursor, *is_const))
                 .chain(memory.get_variables(scope, *is_const))
                 .chain(memory.get_structs())
+                .chain(memory.get_macros())
                 .collect()
         },
         CompletionElement::Member(member) => {
@@ -208,7 +220,13 @@ pub fn get_completion_items(
                 Ok(result) => {
                     let type_info = result.type_info;
                     if type_info.size != 0 {
-                        return vec![];
+                        return vec![CompletionItem {
+                            label: "length".to_string(),
+                            kind: Some(CompletionItemKind::METHOD),
+                            detail: Some("int length()".to_string()),
+                            insert_text: Some("length()".to_string()),
+                            ..Default::default()
+                        }];
                     }
                     match type_info {
                         _ if type_info == TypeInfo::from_str("gvec2_type") => vec!["x","y"],
@@ -231,13 +249,8 @@ pub fn get_completion_items(
             }
         },
         CompletionElement::FunctionName => {
-            match memory.shader_type {
-                ShaderType::Spatial => ["fragment", "vertex", "light"].iter(),
-                ShaderType::CanvasItem => ["fragment", "vertex", "light"].iter(),
-                ShaderType::Particles => ["start", "process"].iter(),
-                ShaderType::Fog => ["sky"].iter(),
-                ShaderType::Sky => ["fog"].iter(),
-            }
+            shader_stages(memory.shader_type)
+                .iter()
                 .map(|x| CompletionItem {
                     label: x.to_string(),
                     kind: Some(CompletionItemKind::KEYWORD),
@@ -268,13 +281,72 @@ pub fn get_completion_items(
     }
 }
 
+/// Preprocessor lines are not parsed, so completion there is decided from the
+/// text before the cursor: directive names after `#`, and macro names after
+/// the directives that take one.
+fn get_directive_completion_items(
+    memory: &Memory,
+    cursor: Position
+) -> Option<Vec<CompletionItem>> {
+    let line = memory.get_source().line_text(cursor.line)?;
+    let before_cursor = &line[..memory.get_source().encoding().column_to_byte(line, cursor.character)];
+    let directive = before_cursor.trim_start().strip_prefix('#')?.trim_start();
+
+    if directive.chars().all(|x| x.is_alphanumeric() || x == '_') {
+        let items = [
+            "define", "undef", "ifdef", "ifndef", "if", "elif", "else", "endif", "error",
+            "pragma", "include"
+        ]
+            .iter()
+            .map(|x| CompletionItem {
+                label: format!("#{}", x),
+                kind: Some(CompletionItemKind::KEYWORD),
+                filter_text: Some(x.to_string()),
+                insert_text: Some(x.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        return Some(items);
+    }
+
+    let name = directive.split(|x: char| !(x.is_alphanumeric() || x == '_')).next()?;
+    match name {
+        "ifdef" | "ifndef" | "undef" | "if" | "elif" => Some(memory.get_macros()
+            .into_iter()
+            .map(|x| CompletionItem { insert_text: Some(x.label.clone()), ..x })
+            .collect()),
+        "include" => None,
+        _ => Some(vec![])
+    }
+}
+
+/// `expansion` is the expanded text of the macro invocation under the cursor,
+/// if there is one.
 pub fn get_hover_description(
     memory: &mut Memory,
     cursor: Position,
     text: &String,
+    expansion: Option<String>
 ) -> Option<HoverContents> {
     let scope = memory.scopes.find_scope_from_position(cursor);
-    if let Some(info) = memory.builtin_types.get(text) {
+    if let Some(info) = memory.macros.get(text) {
+        let expansion = expansion.or_else(|| {
+            if info.params.is_some() { return None }
+            let encoding = memory.get_source().encoding();
+            let stream = TokenStream::preprocessed(text, None, encoding, None, &memory.include_sources, memory.macros.clone());
+            let tokens: Vec<_> = stream.tokens
+                .iter()
+                .map(|&x| (x.kind, stream.get_source().token_text(x)))
+                .collect();
+            Some(join_token_text(&tokens))
+        });
+        let expansion = expansion.map_or("".to_string(), |x| format!("\n\nExpands to: `{}`", x));
+        let origin = if info.range.is_none() { "\n\nPredefined by the client." } else { "" };
+        Some(HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: format!("```gdshader\n{}\n```{}{}", info.definition(text), expansion, origin)
+        }))
+    } else if let Some(info) = memory.builtin_types.get(text) {
         Some(HoverContents::Markup(MarkupContent {
             kind: MarkupKind::PlainText,
             value: format!("{}\n\n{}", text.clone(), info.description)
@@ -302,15 +374,7 @@ pub fn get_hover_description(
         }))
     } else if let Some(function) = memory.functions.get(text) {
         let signatures = function.signatures.iter().map(|x| {
-            let params = x.params.iter().map(|y| {
-                let qualifier = match y.qualifier {
-                    Some(FunctionParamQualifier::In) => "in ",
-                    Some(FunctionParamQualifier::Out) => "out ",
-                    Some(FunctionParamQualifier::InOut) => "inout ",
-                    None => ""
-                }.to_string();
-                format!("{}{} {}", qualifier, y.ty.to_string(), y.name)
-            }).collect::<Vec<_>>().join(", ");
+            let params = x.params.iter().map(|y| y.label()).collect::<Vec<_>>().join(", ");
             format!("{} {} ({})", x.return_type.to_string(), text, params)
         }).collect::<Vec<_>>().join("\n");
         let description = function.description.clone().map_or("".to_string(), |x| x);
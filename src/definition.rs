@@ -0,0 +1,47 @@
+use lsp_types::*;
+
+use crate::{completion::CompletionElement, interpreter::evaluate_expression, memory::*};
+
+pub fn get_definition(
+    memory: &mut Memory,
+    cursor: Position,
+    element: &CompletionElement,
+    text: &str,
+) -> Option<SymbolLocation> {
+    let scope = memory.scopes.find_scope_from_position(cursor);
+    if let CompletionElement::Member(member) = element {
+        memory.scopes.force_scope(scope);
+        let type_info = evaluate_expression(memory, *member.clone()).ok()?.type_info;
+        if type_info.size != 0 {
+            return None;
+        }
+        let struct_info = memory.structs.get(&type_info.base)?;
+        let field = struct_info.fields.iter().find(|x| x.name == text)?;
+        return Some(SymbolLocation {
+            include_path: struct_info.include_path.clone(),
+            range: field.range
+        });
+    }
+
+    if let Some(variable) = memory.scopes.collect_scopes_from(scope)
+        .iter()
+        .rev()
+        .find_map(|x| x.get(text)) {
+        variable.range.map(|range| SymbolLocation {
+            include_path: variable.include_path.clone(),
+            range
+        })
+    } else if let Some(struct_info) = memory.structs.get(text) {
+        Some(SymbolLocation {
+            include_path: struct_info.include_path.clone(),
+            range: struct_info.range
+        })
+    } else if let Some(function) = memory.functions.get(text) {
+        function.range.map(|range| SymbolLocation {
+            include_path: function.include_path.clone(),
+            range
+        })
+    } else {
+        None
+    }
+}
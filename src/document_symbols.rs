@@ -0,0 +1,220 @@
+use lsp_types::*;
+
+use crate::{memory::Memory, nodes::*, parse_tokens, source_code::SourceDocument};
+
+/// Builds the outline of a document from its parse tree. Uniforms are
+/// nested under their `group_uniforms` group and subgroup, fields under
+/// their struct, and parameters and locals under their function.
+pub fn get_document_symbols(memory: &Memory) -> Vec<DocumentSymbol> {
+    let mut stream = memory.token_stream(None);
+    let tree = parse_tokens(&mut stream);
+    let source = stream.get_source();
+
+    let mut symbols: Vec<DocumentSymbol> = vec![];
+    // Index of the current group in `symbols`, and whether it has an open subgroup.
+    let mut group: Option<(usize, bool)> = None;
+    for node in tree {
+        match node {
+            TopLevelNode::ShaderType(x) => symbols.push(create_symbol(
+                source.token_text(x.shader_type),
+                Some("shader_type".to_string()),
+                SymbolKind::MODULE,
+                Range::new(x.keyword.range.start, x.shader_type.range.end),
+                x.shader_type.range
+            )),
+            TopLevelNode::GroupUniforms(x) => {
+                group = None;
+                let group_token = if let Some(token) = x.group { token } else { continue };
+                let name = source.token_text(group_token);
+                let existing = symbols.iter().rposition(|symbol| {
+                    symbol.kind == SymbolKind::NAMESPACE && symbol.name == name
+                });
+                let group_idx = match existing.filter(|_| x.subgroup.is_some()) {
+                    Some(idx) => idx,
+                    None => {
+                        symbols.push(create_symbol(
+                            name,
+                            Some("group_uniforms".to_string()),
+                            SymbolKind::NAMESPACE,
+                            Range::new(x.keyword.range.start, group_token.range.end),
+                            group_token.range
+                        ));
+                        symbols.len() - 1
+                    }
+                };
+                if let Some(subgroup) = x.subgroup {
+                    let subgroup_symbol = create_symbol(
+                        source.token_text(subgroup),
+                        Some("group_uniforms".to_string()),
+                        SymbolKind::NAMESPACE,
+                        Range::new(x.keyword.range.start, subgroup.range.end),
+                        subgroup.range
+                    );
+                    push_child(&mut symbols[group_idx], subgroup_symbol);
+                }
+                group = Some((group_idx, x.subgroup.is_some()));
+            }
+            TopLevelNode::Uniform(x) => {
+                let start = x.global_instance.unwrap_or(x.keyword).range.start;
+                let end = x.expression.as_ref().map_or(x.value.range.end, |x| x.range().end);
+                let qualifier = x.global_instance
+                    .map_or("".to_string(), |x| format!("{} ", source.token_text(x)));
+                let detail = format!("{}uniform {}", qualifier, x.value.type_node.info.to_string());
+                let symbol = create_value_symbol(source, &x.value, detail, SymbolKind::VARIABLE, start, end);
+
+                match group {
+                    Some((idx, has_subgroup)) => {
+                        let parent = &mut symbols[idx];
+                        parent.range.end = parent.range.end.max(end);
+                        let parent = match parent.children.as_mut().and_then(|x| x.last_mut()) {
+                            Some(subgroup) if has_subgroup => subgroup,
+                            _ => parent
+                        };
+                        push_child(parent, symbol);
+                    }
+                    None => symbols.push(symbol)
+                }
+            }
+            TopLevelNode::Varying(x) => {
+                let detail = format!("varying {}", x.value.type_node.info.to_string());
+                let (start, end) = (x.keyword.range.start, x.value.range.end);
+                symbols.push(create_value_symbol(source, &x.value, detail, SymbolKind::VARIABLE, start, end));
+            }
+            TopLevelNode::Const(x) => {
+                let detail = format!("const {}", x.value.type_node.info.to_string());
+                let (start, end) = (x.keyword.range.start, x.expression.range().end);
+                symbols.push(create_value_symbol(source, &x.value, detail, SymbolKind::CONSTANT, start, end));
+            }
+            TopLevelNode::Struct(x) => {
+                let fields = x.fields.iter().map(|field| {
+                    let detail = field.type_node.info.to_string();
+                    let (start, end) = (field.range.start, field.range.end);
+                    create_value_symbol(source, field, detail, SymbolKind::FIELD, start, end)
+                }).collect::<Vec<_>>();
+                let end = x.fields.last().map_or(x.identifier.range.end, |x| x.range.end);
+                let mut symbol = create_symbol(
+                    source.token_text(x.identifier),
+                    Some("struct".to_string()),
+                    SymbolKind::STRUCT,
+                    Range::new(x.keyword.range.start, end),
+                    x.identifier.range
+                );
+                symbol.children = Some(fields);
+                symbols.push(symbol);
+            }
+            TopLevelNode::Function(x) => symbols.push(create_function_symbol(source, &x)),
+            TopLevelNode::RenderMode(_) | TopLevelNode::Include(_) => {}
+        }
+    }
+    symbols
+}
+
+fn create_function_symbol(source: &SourceDocument, node: &FunctionNode) -> DocumentSymbol {
+    let mut children = vec![];
+    for param in &node.params {
+        let value = &param.value_node;
+        let detail = value.type_node.info.to_string();
+        let first = param.const_keyword.or(param.qualifier);
+        let (start, end) = (first.map_or(value.range.start, |x| x.range.start), value.range.end);
+        children.push(create_value_symbol(source, value, detail, SymbolKind::VARIABLE, start, end));
+    }
+    for statement in &node.block.statements {
+        collect_locals(source, statement, &mut children);
+    }
+
+    let params = node.params
+        .iter()
+        .map(|x| format!(
+            "{} {}",
+            x.value_node.type_node.info.to_string(),
+            source.token_text(x.value_node.identifier)
+        ))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut symbol = create_symbol(
+        source.token_text(node.identifier),
+        Some(format!("{} ({})", node.type_node.info.to_string(), params)),
+        SymbolKind::FUNCTION,
+        Range::new(node.type_node.range.start, node.block.range.end),
+        node.identifier.range
+    );
+    symbol.children = Some(children);
+    symbol
+}
+
+fn collect_locals(source: &SourceDocument, statement: &StatementNode, locals: &mut Vec<DocumentSymbol>) {
+    match statement {
+        StatementNode::VarDeclaration(x) => {
+            let kind = if x.is_const { SymbolKind::CONSTANT } else { SymbolKind::VARIABLE };
+            let detail = x.value.type_node.info.to_string();
+            let start = x.keyword.map_or(x.value.range.start, |x| x.range.start);
+            let end = x.expression.as_ref().map_or(x.value.range.end, |x| x.range().end);
+            locals.push(create_value_symbol(source, &x.value, detail, kind, start, end));
+        }
+        StatementNode::If(x) => {
+            collect_locals(source, &x.action, locals);
+            if let Some(alternate) = &x.alternate {
+                collect_locals(source, &alternate.action, locals);
+            }
+        }
+        StatementNode::While(x) => collect_locals(source, &x.action, locals),
+        StatementNode::DoWhile(x) => collect_locals(source, &x.action, locals),
+        StatementNode::For(x) => {
+            collect_locals(source, &x.initializer, locals);
+            collect_locals(source, &x.action, locals);
+        }
+        StatementNode::Switch(x) => {
+            for statement in x.cases.iter().flat_map(|x| x.statements.iter()) {
+                collect_locals(source, statement, locals);
+            }
+        }
+        StatementNode::Block(x) => {
+            for statement in &x.statements {
+                collect_locals(source, statement, locals);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn create_value_symbol(
+    source: &SourceDocument,
+    value: &ValueNode,
+    detail: String,
+    kind: SymbolKind,
+    start: Position,
+    end: Position
+) -> DocumentSymbol {
+    create_symbol(
+        source.token_text(value.identifier),
+        Some(detail),
+        kind,
+        Range::new(start, end),
+        value.identifier.range
+    )
+}
+
+fn push_child(parent: &mut DocumentSymbol, child: DocumentSymbol) {
+    parent.range.end = parent.range.end.max(child.range.end);
+    parent.children.get_or_insert(vec![]).push(child);
+}
+
+#[allow(deprecated)]
+fn create_symbol(
+    name: String,
+    detail: Option<String>,
+    kind: SymbolKind,
+    range: Range,
+    selection_range: Range
+) -> DocumentSymbol {
+    DocumentSymbol {
+        name,
+        detail,
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range,
+        children: None
+    }
+}
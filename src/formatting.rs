@@ -0,0 +1,582 @@
+use lsp_types::*;
+
+use crate::{
+    lexer::{lex_tokens, TokenKind},
+    line_index::{LineIndex, PositionEncoding}
+};
+
+/// A token, comment or preprocessor directive of the document. Comments are
+/// not tokens, so they are found in the gaps between tokens and kept as trivia.
+struct Item {
+    kind: ItemKind,
+    text: String,
+    /// The line breaks between the previous item and this one.
+    newlines_before: usize,
+    start_line: u32,
+    end_line: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ItemKind {
+    Token(TokenKind),
+    LineComment,
+    BlockComment,
+    Directive,
+}
+
+/// Formats the document in the style of Godot's shader editor: one statement
+/// per line, braces on the line that opens the block, single spaces around
+/// binary operators and after commas, and the hints of consecutive uniforms
+/// aligned. Line breaks the author put inside a statement are kept, as are
+/// single blank lines. Formatting the result again changes nothing.
+///
+/// With a `range`, only the lines holding the items it touches are replaced,
+/// widened so that every replaced line is replaced whole.
+pub fn get_formatting_edits(
+    code: &str,
+    options: &FormattingOptions,
+    range: Option<Range>,
+    encoding: PositionEncoding
+) -> Result<Vec<TextEdit>, String> {
+    let items = collect_items(code, encoding)?;
+    let mut formatter = Formatter::new(options);
+    for item in &items {
+        formatter.emit(item);
+    }
+    let (lines, placements) = formatter.finish();
+    let newline = if code.contains("\r\n") { "\r\n" } else { "\n" };
+    let index = LineIndex::new(code, encoding);
+    let end_of_text = index.position(code, code.len());
+
+    let (start, end, new_text) = match range {
+        None => {
+            let new_text = lines.iter().map(|x| format!("{}{}", x, newline)).collect::<String>();
+            (Position::new(0, 0), end_of_text, new_text)
+        }
+        Some(range) => {
+            let last_line = if range.end.line > range.start.line && range.end.character == 0 {
+                range.end.line - 1
+            } else {
+                range.end.line
+            };
+            let touched: Vec<usize> = (0..items.len())
+                .filter(|&x| items[x].end_line >= range.start.line && items[x].start_line <= last_line)
+                .collect();
+            let (mut first, mut last) = match (touched.first(), touched.last()) {
+                (Some(&first), Some(&last)) => (first, last),
+                _ => return Ok(vec![])
+            };
+            while first > 0
+                && (items[first - 1].end_line == items[first].start_line
+                    || placements[first - 1].1 == placements[first].0)
+            {
+                first -= 1;
+            }
+            while last + 1 < items.len()
+                && (items[last + 1].start_line == items[last].end_line
+                    || placements[last + 1].0 == placements[last].1)
+            {
+                last += 1;
+            }
+            let new_text = lines[placements[first].0..=placements[last].1]
+                .iter()
+                .map(|x| format!("{}{}", x, newline))
+                .collect::<String>();
+            let end_line = items[last].end_line + 1;
+            let end = if (end_line as usize) < index.line_count() { Position::new(end_line, 0) } else { end_of_text };
+            (Position::new(items[first].start_line, 0), end, new_text)
+        }
+    };
+
+    let start_offset = index.offset(code, start);
+    let end_offset = index.offset(code, end);
+    if code.get(start_offset..end_offset) == Some(new_text.as_str()) {
+        return Ok(vec![]);
+    }
+    Ok(vec![TextEdit { range: Range::new(start, end), new_text }])
+}
+
+/// Splits the document into tokens, the comments between them, and whole
+/// preprocessor directives, which are kept as written.
+fn collect_items(code: &str, encoding: PositionEncoding) -> Result<Vec<Item>, String> {
+    let index = LineIndex::new(code, encoding);
+    let mut items = vec![];
+    let mut consumed = 0;
+
+    for token in lex_tokens(code, encoding) {
+        let start = index.offset(code, token.range.start);
+        if start < consumed {
+            continue;
+        }
+        if token.kind == TokenKind::Error {
+            let message = format!("Cannot format the unexpected character on line {}.", token.range.start.line + 1);
+            return Err(message);
+        }
+        let newlines_before = collect_trivia(code, &index, consumed, start, &mut items);
+        let line_start = index.offset(code, Position::new(token.range.start.line, 0));
+        let starts_line = code[line_start..start].trim().is_empty();
+
+        if starts_line && matches!(token.kind, TokenKind::Hash | TokenKind::Include) {
+            // A directive runs to the end of its line, and on to the next
+            // while the line ends with a backslash.
+            let mut end_line = token.range.start.line;
+            while index.line_text(code, end_line).is_some_and(|x| x.trim_end().ends_with('\\'))
+                && (end_line as usize + 1) < index.line_count()
+            {
+                end_line += 1;
+            }
+            let end = index.offset(code, Position::new(end_line, u32::MAX));
+            let text = code[start..end].lines().map(|x| x.trim_end()).collect::<Vec<_>>().join("\n");
+            items.push(Item {
+                kind: ItemKind::Directive,
+                text,
+                newlines_before,
+                start_line: token.range.start.line,
+                end_line
+            });
+            consumed = end;
+            continue;
+        }
+
+        let end = index.offset(code, token.range.end);
+        items.push(Item {
+            kind: ItemKind::Token(token.kind),
+            text: code[start..end].to_string(),
+            newlines_before,
+            start_line: token.range.start.line,
+            end_line: token.range.start.line
+        });
+        consumed = end;
+    }
+    collect_trivia(code, &index, consumed, code.len(), &mut items);
+    Ok(items)
+}
+
+/// Adds the comments in `code[from..to]` to `items`, and returns the line
+/// breaks after the last of them.
+fn collect_trivia(code: &str, index: &LineIndex, from: usize, to: usize, items: &mut Vec<Item>) -> usize {
+    let mut newlines = 0;
+    let mut idx = from;
+    while idx < to {
+        let rest = &code[idx..to];
+        let comment = if rest.starts_with("//") {
+            Some((ItemKind::LineComment, rest.find('\n').unwrap_or(rest.len())))
+        } else if rest.starts_with("/*") {
+            Some((ItemKind::BlockComment, rest.find("*/").map_or(rest.len(), |x| x + 2)))
+        } else {
+            None
+        };
+        match comment {
+            Some((kind, length)) => {
+                let text = rest[..length].lines().map(|x| x.trim_end()).collect::<Vec<_>>().join("\n");
+                items.push(Item {
+                    kind,
+                    text,
+                    newlines_before: newlines,
+                    start_line: index.position(code, idx).line,
+                    end_line: index.position(code, idx + length).line
+                });
+                newlines = 0;
+                idx += length;
+            }
+            None => {
+                let x = rest.chars().next().unwrap_or_default();
+                if x == '\n' {
+                    newlines += 1;
+                }
+                idx += x.len_utf8().max(1);
+            }
+        }
+    }
+    newlines
+}
+
+/// A pair of braces that is still open.
+struct Frame {
+    /// The braces of an array initializer rather than of a block.
+    initializer: bool,
+    switch: bool,
+    do_block: bool,
+    /// Inside a switch, whether a case label has been seen, after which
+    /// statements are indented one level further.
+    after_case: bool,
+    /// A block opened right after a case label, which takes the place of
+    /// that extra level.
+    case_block: bool,
+}
+
+struct Formatter {
+    indent_unit: String,
+    lines: Vec<String>,
+    /// The line being written, if one has been started.
+    line: Option<String>,
+    /// For each item, the first and last output line it was written on.
+    placements: Vec<(usize, usize)>,
+    frames: Vec<Frame>,
+    paren_depth: usize,
+    break_pending: bool,
+    statement_start: bool,
+    case_label: bool,
+    after_case_label: bool,
+    pending_switch: bool,
+    pending_do: bool,
+    previous: Option<ItemKind>,
+    previous_is_operand: bool,
+    previous_is_prefix: bool,
+    previous_closed_do: bool,
+    previous_closed_case_block: bool,
+}
+
+impl Formatter {
+    fn new(options: &FormattingOptions) -> Self {
+        let indent_unit = if options.insert_spaces {
+            " ".repeat(options.tab_size as usize)
+        } else {
+            "\t".to_string()
+        };
+        Self {
+            indent_unit,
+            lines: vec![],
+            line: None,
+            placements: vec![],
+            frames: vec![],
+            paren_depth: 0,
+            break_pending: false,
+            statement_start: true,
+            case_label: false,
+            after_case_label: false,
+            pending_switch: false,
+            pending_do: false,
+            previous: None,
+            previous_is_operand: false,
+            previous_is_prefix: false,
+            previous_closed_do: false,
+            previous_closed_case_block: false,
+        }
+    }
+
+    fn finish(mut self) -> (Vec<String>, Vec<(usize, usize)>) {
+        if let Some(line) = self.line.take() {
+            self.lines.push(line.trim_end().to_string());
+        }
+        align_uniform_hints(&mut self.lines);
+        (self.lines, self.placements)
+    }
+
+    fn emit(&mut self, item: &Item) {
+        match item.kind {
+            ItemKind::Directive => {
+                self.start_line(item, Some(0));
+                let first = self.lines.len();
+                self.append_text(&item.text);
+                self.placements.push((first, self.lines.len()));
+                self.break_pending = true;
+            }
+            ItemKind::LineComment | ItemKind::BlockComment => {
+                // A comment written after code stays on that line.
+                if self.line.is_some() && item.newlines_before == 0 {
+                    self.push_str(" ");
+                } else {
+                    self.start_line(item, None);
+                }
+                let first = self.lines.len();
+                self.append_text(&item.text);
+                self.placements.push((first, self.lines.len()));
+                if item.kind == ItemKind::LineComment {
+                    self.break_pending = true;
+                }
+            }
+            ItemKind::Token(kind) => self.emit_token(item, kind),
+        }
+        self.previous = Some(item.kind);
+    }
+
+    fn emit_token(&mut self, item: &Item, kind: TokenKind) {
+        let is_prefix = matches!(kind, TokenKind::Bang | TokenKind::Tilde)
+            || matches!(kind, TokenKind::Dash | TokenKind::Plus | TokenKind::Increment | TokenKind::Decrement)
+                && !self.previous_is_operand;
+        let is_postfix = matches!(kind, TokenKind::Increment | TokenKind::Decrement) && !is_prefix;
+        let opens_initializer = kind == TokenKind::LeftBrace && (
+            matches!(self.previous, Some(ItemKind::Token(
+                TokenKind::Equal | TokenKind::Comma | TokenKind::LeftParen | TokenKind::Return
+            )))
+            || self.previous == Some(ItemKind::Token(TokenKind::LeftBrace))
+                && self.frames.last().is_some_and(|x| x.initializer)
+        );
+        let closed = if kind == TokenKind::RightBrace { self.frames.pop() } else { None };
+        let closes_block = kind == TokenKind::RightBrace && closed.as_ref().is_none_or(|x| !x.initializer);
+        let follows_block = self.previous == Some(ItemKind::Token(TokenKind::RightBrace)) && (
+            kind == TokenKind::Else
+                || kind == TokenKind::Semicolon
+                || kind == TokenKind::While && self.previous_closed_do
+                || kind == TokenKind::Break && self.previous_closed_case_block
+        );
+
+        let new_line = if self.line.is_none() {
+            true
+        } else if kind == TokenKind::LeftBrace && !opens_initializer {
+            self.previous == Some(ItemKind::LineComment)
+        } else if follows_block {
+            false
+        } else {
+            closes_block || self.break_pending || item.newlines_before > 0
+        };
+        if new_line {
+            let depth = match kind {
+                TokenKind::Case | TokenKind::Default => Some(self.depth(None, true)),
+                TokenKind::LeftBrace | TokenKind::RightBrace => Some(self.depth(closed.as_ref(), false)),
+                _ => None
+            };
+            self.start_line(item, depth);
+        } else if self.needs_space(kind, is_postfix, closed.as_ref()) {
+            self.push_str(" ");
+        }
+        let line = self.lines.len();
+        self.push_str(&item.text);
+        self.placements.push((line, line));
+        self.break_pending = false;
+
+        let statement_start = self.statement_start;
+        let after_case_label = std::mem::take(&mut self.after_case_label);
+        self.statement_start = false;
+        match kind {
+            TokenKind::LeftParen | TokenKind::LeftBracket => self.paren_depth += 1,
+            TokenKind::RightParen | TokenKind::RightBracket => self.paren_depth = self.paren_depth.saturating_sub(1),
+            TokenKind::LeftBrace => {
+                self.frames.push(Frame {
+                    initializer: opens_initializer,
+                    switch: std::mem::take(&mut self.pending_switch),
+                    do_block: std::mem::take(&mut self.pending_do),
+                    after_case: false,
+                    case_block: after_case_label
+                });
+                if !opens_initializer {
+                    self.break_pending = true;
+                    self.statement_start = true;
+                }
+            }
+            TokenKind::RightBrace if closes_block => {
+                self.break_pending = true;
+                self.statement_start = true;
+            }
+            TokenKind::Semicolon if self.paren_depth == 0 => {
+                self.break_pending = true;
+                self.statement_start = true;
+                self.pending_do = false;
+            }
+            TokenKind::Case | TokenKind::Default if statement_start => self.case_label = true,
+            TokenKind::Colon if self.case_label => {
+                self.case_label = false;
+                self.after_case_label = true;
+                self.break_pending = true;
+                self.statement_start = true;
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.after_case = true;
+                }
+            }
+            TokenKind::Switch => self.pending_switch = true,
+            TokenKind::Do => self.pending_do = true,
+            _ => {}
+        }
+
+        self.previous_is_operand = matches!(kind,
+            TokenKind::Identifier | TokenKind::IntConstant | TokenKind::UintConstant
+                | TokenKind::FloatConstant | TokenKind::BoolConstant | TokenKind::String
+                | TokenKind::RightParen | TokenKind::RightBracket
+        ) || is_postfix || kind == TokenKind::RightBrace && !closes_block;
+        self.previous_is_prefix = is_prefix;
+        self.previous_closed_do = closed.as_ref().is_some_and(|x| x.do_block);
+        self.previous_closed_case_block = closed.is_some_and(|x| x.case_block);
+    }
+
+    fn needs_space(&self, kind: TokenKind, is_postfix: bool, closed: Option<&Frame>) -> bool {
+        let previous = match self.previous {
+            Some(ItemKind::Token(x)) => x,
+            _ => return true
+        };
+        let after_opening = matches!(previous, TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::Dot)
+            || previous == TokenKind::LeftBrace && self.frames.last().is_some_and(|x| x.initializer);
+        if after_opening || self.previous_is_prefix {
+            return false;
+        }
+        match kind {
+            TokenKind::Comma | TokenKind::Semicolon | TokenKind::RightParen
+                | TokenKind::RightBracket | TokenKind::Dot | TokenKind::LeftBracket => false,
+            TokenKind::LeftParen => !matches!(previous, TokenKind::Identifier | TokenKind::RightBracket),
+            // The lexer has no compound assignments for these operators.
+            TokenKind::Equal => !matches!(previous,
+                TokenKind::LeftOp | TokenKind::RightOp | TokenKind::Ampersand
+                    | TokenKind::VerticalBar | TokenKind::Caret
+            ),
+            TokenKind::RightBrace => closed.is_none_or(|x| !x.initializer),
+            TokenKind::Colon => !self.case_label,
+            _ => !is_postfix
+        }
+    }
+
+    /// Ends the current line and starts another, indented to `depth` or to
+    /// where the item belongs. A blank line the author left before the item
+    /// is kept, unless it would open or close a block.
+    fn start_line(&mut self, item: &Item, depth: Option<usize>) {
+        if let Some(line) = self.line.take() {
+            self.lines.push(line.trim_end().to_string());
+            let after_opening = self.lines.last().is_some_and(|x| x.ends_with('{'));
+            let is_closing = item.kind == ItemKind::Token(TokenKind::RightBrace);
+            if item.newlines_before > 1 && !after_opening && !is_closing {
+                self.lines.push(String::new());
+            }
+        }
+        let depth = depth.unwrap_or_else(|| {
+            let continues = !self.statement_start && self.frames.last().is_none_or(|x| !x.initializer);
+            self.depth(None, false) + continues as usize
+        });
+        self.line = Some(self.indent_unit.repeat(depth));
+    }
+
+    /// The indentation inside the open braces. Statements after a case label
+    /// sit one level deeper than the label, unless they are in a block opened
+    /// by the label. `next` is a frame being closed, which counts as the
+    /// innermost one.
+    fn depth(&self, next: Option<&Frame>, is_label: bool) -> usize {
+        let mut depth = 0;
+        for (idx, frame) in self.frames.iter().enumerate() {
+            let is_innermost = idx + 1 == self.frames.len();
+            let inner = if is_innermost { next } else { self.frames.get(idx + 1) };
+            let in_case = frame.switch && frame.after_case && !(is_innermost && is_label);
+            depth += 1 + (in_case && !inner.is_some_and(|x| x.case_block)) as usize;
+        }
+        depth
+    }
+
+    fn push_str(&mut self, text: &str) {
+        self.line.get_or_insert_with(String::new).push_str(text);
+    }
+
+    /// Writes text that may span lines, such as a block comment, keeping the
+    /// lines after the first as they were written.
+    fn append_text(&mut self, text: &str) {
+        let mut lines = text.split('\n');
+        self.push_str(lines.next().unwrap_or_default());
+        for line in lines {
+            if let Some(current) = self.line.take() {
+                self.lines.push(current.trim_end().to_string());
+            }
+            self.line = Some(line.to_string());
+        }
+    }
+}
+
+/// Pads the declarations of consecutive uniforms so that the colons before
+/// their hints line up.
+fn align_uniform_hints(lines: &mut [String]) {
+    let hint_colon = |line: &str| -> Option<usize> {
+        let is_uniform = ["uniform ", "global uniform ", "instance uniform "].iter().any(|x| line.starts_with(x));
+        let colon = line.find(" : ")?;
+        let before = &line[..colon];
+        (is_uniform && !before.contains('=') && !before.contains('(')).then_some(colon)
+    };
+
+    let mut idx = 0;
+    while idx < lines.len() {
+        let group_end = (idx..lines.len()).find(|&x| hint_colon(&lines[x]).is_none()).unwrap_or(lines.len());
+        if group_end - idx > 1 {
+            let width = lines[idx..group_end].iter().filter_map(|x| hint_colon(x)).max().unwrap_or(0);
+            for line in &mut lines[idx..group_end] {
+                let colon = hint_colon(line).unwrap_or(width);
+                line.insert_str(colon, &" ".repeat(width - colon));
+            }
+        }
+        idx = group_end.max(idx + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "shader_type canvas_item;
+uniform sampler2D screen:hint_screen_texture,filter_linear_mipmap;
+uniform int mode: hint_enum(\"A\",\"B\") = 0;
+/* A block comment
+   over two lines */
+#define SCALE(x) (x*2.0)
+void fragment(){
+float a[3]=float[3](1.0,-2.0,3.0);
+float b=a[-1+2]*-1.0; // trailing
+b=b>0.0?b:-b;
+if(!(b<1.0)&&b>=0.0||false){return;}
+int i=~0<<2;
+i<<=1;i%=3;
+
+
+do{i++;}while(i<10);
+COLOR.rgb = texture(screen, SCREEN_UV).rgb*SCALE(b);
+}
+";
+
+    fn format(code: &str, range: Option<Range>) -> String {
+        let options = FormattingOptions { tab_size: 4, insert_spaces: false, ..Default::default() };
+        let edits = get_formatting_edits(code, &options, range, PositionEncoding::Utf16).unwrap();
+        let edit = if let Some(edit) = edits.first() { edit } else { return code.to_string() };
+        let index = LineIndex::new(code, PositionEncoding::Utf16);
+        let start = index.offset(code, edit.range.start);
+        let end = index.offset(code, edit.range.end);
+        format!("{}{}{}", &code[..start], edit.new_text, &code[end..])
+    }
+
+    fn without_whitespace(code: &str) -> String {
+        code.chars().filter(|x| !x.is_whitespace()).collect()
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let once = format(MESSY, None);
+        assert_ne!(once, MESSY);
+        assert_eq!(format(&once, None), once);
+        assert_eq!(without_whitespace(&once), without_whitespace(MESSY));
+    }
+
+    #[test]
+    fn formatting_keeps_crlf_and_is_idempotent() {
+        let code = MESSY.replace('\n', "\r\n");
+        let once = format(&code, None);
+        assert!(!once.replace("\r\n", "").contains('\n'));
+        assert_eq!(format(&once, None), once);
+    }
+
+    #[test]
+    fn range_formatting_after_block_comment_touches_only_its_lines() {
+        let code = "shader_type spatial;\n/* one\ntwo\nthree */\nvoid vertex(){\nfloat x=1.0;\n}\n";
+        let line = Position::new(5, 0);
+        let formatted = format(code, Some(Range::new(line, line)));
+        assert_eq!(formatted, "shader_type spatial;\n/* one\ntwo\nthree */\nvoid vertex(){\n\tfloat x = 1.0;\n}\n");
+    }
+
+    #[test]
+    fn messy_code_is_formatted_in_godot_style() {
+        let expected = "shader_type canvas_item;
+uniform sampler2D screen : hint_screen_texture, filter_linear_mipmap;
+uniform int mode         : hint_enum(\"A\", \"B\") = 0;
+/* A block comment
+   over two lines */
+#define SCALE(x) (x*2.0)
+void fragment() {
+\tfloat a[3] = float[3](1.0, -2.0, 3.0);
+\tfloat b = a[-1 + 2] * -1.0; // trailing
+\tb = b > 0.0 ? b : -b;
+\tif (!(b < 1.0) && b >= 0.0 || false) {
+\t\treturn;
+\t}
+\tint i = ~0 << 2;
+\ti <<= 1;
+\ti %= 3;
+
+\tdo {
+\t\ti++;
+\t} while (i < 10);
+\tCOLOR.rgb = texture(screen, SCREEN_UV).rgb * SCALE(b);
+}
+";
+        assert_eq!(format(MESSY, None), expected);
+    }
+}
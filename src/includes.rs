@@ -0,0 +1,48 @@
+use std::{cell::RefCell, collections::HashMap, fs, path::{Path, PathBuf}, rc::Rc};
+
+/// The text of the shader includes, shared by every open document. An
+/// include that is open in the editor is read from its buffer, and any other
+/// is read from disk once and kept until it is invalidated.
+#[derive(Clone, Default)]
+pub struct IncludeSources {
+    open: Rc<RefCell<HashMap<String, String>>>,
+    disk: Rc<RefCell<HashMap<String, Option<String>>>>,
+}
+
+impl IncludeSources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self, path: &str) -> Option<String> {
+        let path = normalize_path(path);
+        if let Some(text) = self.open.borrow().get(&path) {
+            return Some(text.clone());
+        }
+        self.disk
+            .borrow_mut()
+            .entry(path.clone())
+            .or_insert_with(|| fs::read_to_string(&path).ok())
+            .clone()
+    }
+
+    /// Makes `text` the contents of `path` until the buffer is closed.
+    pub fn set_open(&self, path: &str, text: &str) {
+        self.open.borrow_mut().insert(normalize_path(path), text.to_string());
+    }
+
+    pub fn close(&self, path: &str) {
+        self.open.borrow_mut().remove(&normalize_path(path));
+    }
+
+    /// Forgets the contents of `path` on disk, so they are read again.
+    pub fn invalidate(&self, path: &str) {
+        self.disk.borrow_mut().remove(&normalize_path(path));
+    }
+}
+
+/// Removes repeated separators, which appear when `res://` is replaced by a
+/// root directory that ends with a slash.
+pub fn normalize_path(path: &str) -> String {
+    Path::new(path).components().collect::<PathBuf>().to_string_lossy().to_string()
+}
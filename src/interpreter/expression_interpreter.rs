@@ -2,7 +2,18 @@ use lsp_types::Range;
 use crate::{
 
     lexer::{MaybeOperator, OperationType, Token, TokenKind},
-    memory::{Memory, Primitive, TypeInfo},
+    memory::{
+        BuiltinUse,
+        FunctionParamQualifier,
+        FunctionSignature,
+        Memory,
+        Primitive,
+        ReferenceKind,
+        SymbolLocation,
+        TypeInfo,
+        shader_stages,
+        stage_variables
+    },
     nodes::*
 };
 
@@ -52,10 +63,14 @@ fn eval_increment_expr(
     increment: IncrementNode,
     range: lsp_types::Range
 ) -> ExprEvalResult {
+    let written = assigned_tokens(&increment.arg);
     let result = evaluate_expression(memory, *increment.arg)?;
+    for token in &written {
+        memory.mark_written(token.range);
+    }
     if !result.is_assignable || result.is_const {
-        let message = "Cannot change a constant value.";
-        return Err(memory.alert_error(message, range))
+        let message = read_only_message(memory, &written, "Cannot change a constant value.");
+        return Err(memory.alert_error(&message, range))
     }
     eval_operation(memory, OperationType::Number, result.type_info.clone(), range)?;
     Ok(ExpressionEvaluation::new(result.type_info, result.is_const, false))
@@ -68,11 +83,15 @@ fn eval_assignment_expr(
 ) -> ExprEvalResult {
     let left_range = assignment.left.range();
     let right_range = assignment.right.range();
+    let written = assigned_tokens(&assignment.left);
     let left = evaluate_expression(memory, *assignment.left)?;
+    for token in &written {
+        memory.mark_written(token.range);
+    }
     let right = evaluate_expression(memory, *assignment.right)?;
     if !left.is_assignable || left.is_const {
-        let message = "Cannot assign to a constant value.";
-        return Err(memory.alert_error(message, left_range))
+        let message = read_only_message(memory, &written, "Cannot assign to a constant value.");
+        return Err(memory.alert_error(&message, left_range))
     }
 
     let mut type_mismatch = || {
@@ -189,9 +208,31 @@ fn eval_member_access(
     let member_access_str = memory.get_token_text(member_access.member);
     let argument_result = evaluate_expression(memory, *member_access.argument)?;
     if argument_result.type_info.size != 0 {
+        // The length of an array is known when it is declared, so it is
+        // always a constant.
+        return match (member_access_str.as_str(), &member_access.args) {
+            ("length", Some(args)) if args.is_empty() => {
+                Ok(ExpressionEvaluation::new(TypeInfo::from_str("int"), true, false))
+            }
+            ("length", Some(_)) => Err(memory.alert_error("'length' takes no arguments.", range)),
+            ("length", None) => {
+                let message = "'length' is a method, and must be called as 'length()'.";
+                Err(memory.alert_error(message, range))
+            }
+            _ => {
+                let message = format!(
+                    "Cannot access member of array type {}",
+                    argument_result.type_info.to_string()
+                );
+                Err(memory.alert_error(&message, range))
+            }
+        };
+    }
+    if member_access.args.is_some() {
         let message = format!(
-            "Cannot access member of array type {}",
-            argument_result.type_info.to_string()
+            "Type {} has no method {}",
+            argument_result.type_info.to_string(),
+            member_access_str
         );
         return Err(memory.alert_error(&message, range))
     }
@@ -227,15 +268,26 @@ fn eval_member_access(
             return Err(memory.alert_error(&message, range))
         }
     }
-    memory.structs
+    let field = memory.structs
         .get(&argument_result.type_info.base)
-        .and_then(|x| x.fields.iter().find(|x| x.name.as_str() == member).map(|x| x.ty.clone()))
-        .ok_or_else(|| memory.alert_error(&message, range))
-        .map(|ty| ExpressionEvaluation::new(
+        .and_then(|x| x.fields
+            .iter()
+            .find(|x| x.name.as_str() == member)
+            .map(|field| (field.ty.clone(), SymbolLocation {
+                include_path: x.include_path.clone(),
+                range: field.range
+            }))
+        );
+    if let Some((ty, declaration)) = field {
+        memory.add_reference(declaration, member_access.member.range, ReferenceKind::Read);
+        Ok(ExpressionEvaluation::new(
             ty,
             argument_result.is_const,
             argument_result.is_assignable
         ))
+    } else {
+        Err(memory.alert_error(&message, range))
+    }
 }
 
 fn eval_array_access(
@@ -259,12 +311,7 @@ fn eval_array_access(
     }
     let new_eval = ExpressionEvaluation::new;
     match argument_result.type_info.base.as_str() {
-        "vec2" => Ok(new_eval(TypeInfo::from_str("float"), is_const, is_assignable)),
-        "vec3" => Ok(new_eval(TypeInfo::from_str("float"), is_const, is_assignable)),
-        "vec4" => Ok(new_eval(TypeInfo::from_str("float"), is_const, is_assignable)),
-        "mat2" => Ok(new_eval(TypeInfo::from_str("vec2"), is_const, is_assignable)),
-        "mat3" => Ok(new_eval(TypeInfo::from_str("vec3"), is_const, is_assignable)),
-        "mat4" => Ok(new_eval(TypeInfo::from_str("vec4"), is_const, is_assignable)),
+        // Indexing an array gives an element, whatever its type.
         _ if argument_result.type_info.size != 0 => {
             Ok(new_eval(
                 TypeInfo { base: argument_result.type_info.base, size: 0 },
@@ -272,6 +319,12 @@ fn eval_array_access(
                 is_assignable
             ))
         },
+        "vec2" => Ok(new_eval(TypeInfo::from_str("float"), is_const, is_assignable)),
+        "vec3" => Ok(new_eval(TypeInfo::from_str("float"), is_const, is_assignable)),
+        "vec4" => Ok(new_eval(TypeInfo::from_str("float"), is_const, is_assignable)),
+        "mat2" => Ok(new_eval(TypeInfo::from_str("vec2"), is_const, is_assignable)),
+        "mat3" => Ok(new_eval(TypeInfo::from_str("vec3"), is_const, is_assignable)),
+        "mat4" => Ok(new_eval(TypeInfo::from_str("vec4"), is_const, is_assignable)),
         _ => {
             let message = format!(
                 "Type {} cannot be indexed.",
@@ -290,17 +343,26 @@ fn eval_call_expr(
     let call_name = memory.get_token_text(call.identifier);
     let mut is_const = true;
     let mut arg_types = vec![];
+    let mut arg_written = vec![];
+    let mut arg_read_only = vec![];
 
     for arg in call.args {
+        arg_written.push(assigned_tokens(&arg.expression));
+        let arg_range = arg.expression.range();
         let result = evaluate_expression(memory, arg.expression)?;
         if !result.is_const{
             is_const = false
         }
+        arg_read_only.push((!result.is_assignable || result.is_const).then_some(arg_range));
         arg_types.push(result.type_info);
     }
 
     let maybe_struct = memory.structs.get(&call_name);
     if let Some(struct_info) = maybe_struct {
+        let declaration = SymbolLocation {
+            include_path: struct_info.include_path.clone(),
+            range: struct_info.range
+        };
         let correct_types = struct_info.fields
             .iter()
             .map(|x| &x.ty)
@@ -312,6 +374,7 @@ fn eval_call_expr(
                 return Err(memory.alert_error(&message, range));
             }
         }
+        memory.add_reference(declaration, call.identifier.range, ReferenceKind::Read);
         return Ok(ExpressionEvaluation::new(
             TypeInfo::from_str(&call_name),
             is_const,
@@ -319,63 +382,157 @@ fn eval_call_expr(
         ));
     }
 
-    let maybe_function = memory.functions.get(&call_name);
-    if let Some(function) = maybe_function {
-        if !function.is_const {
-            is_const = false;
+    let maybe_function = memory.functions.get(&call_name).map(|function| {
+        let declaration = function.range.map(|range| SymbolLocation {
+            include_path: function.include_path.clone(),
+            range
+        });
+        let matched = function.signatures.iter().find_map(|signature| {
+            match_signature(signature, &arg_types).map(|ty| {
+                let qualifiers = signature.params
+                    .iter()
+                    .map(|x| (x.qualifier.clone(), x.name.clone()))
+                    .collect::<Vec<_>>();
+                (ty, qualifiers, signature.stages)
+            })
+        });
+        (declaration, function.is_const, matched)
+    });
+    if let Some((declaration, function_is_const, matched)) = maybe_function {
+        if let Some(declaration) = declaration {
+            memory.add_reference(declaration, call.identifier.range, ReferenceKind::Read);
         }
-        for signature in &function.signatures {
-            let correct_types = signature.params
-                .iter()
-                .map(|x| x.ty.clone())
-                .collect::<Vec<_>>();
-            if arg_types != correct_types {
-                continue;
+        if let Some((ty, qualifiers, stages)) = matched {
+            if let Some(tags) = stages {
+                memory.check_call_stages(&call_name, tags, call.identifier.range);
             }
-            let old_return_type = &signature.return_type;
-
-            if let Some(primitive_type) = old_return_type.is_generically_sized() {
-                let generic_args: Vec<_> = correct_types
-                    .iter()
-                    .zip(arg_types.iter())
-                    .filter_map(|(generic, arg)| {
-                        if generic.is_generically_sized().is_some() { Some(arg) }
-                        else { None }
-                    })
-                    .collect();
-
-                let generic_size = generic_args[0].get_generic_size();
-                if generic_args.iter().all(|x| x.get_generic_size() == generic_size) {
-                    let ty = TypeInfo::from_pieces(primitive_type, generic_size.unwrap());
-                    return Ok(ExpressionEvaluation::new(ty, is_const, false));
+            for ((written, read_only), (qualifier, name)) in arg_written.iter().zip(arg_read_only).zip(qualifiers) {
+                let qualifier = match qualifier {
+                    Some(FunctionParamQualifier::Out) => "out",
+                    Some(FunctionParamQualifier::InOut) => "inout",
+                    _ => continue
+                };
+                for token in written {
+                    memory.mark_written(token.range);
                 }
-            } else if let Some(generic_size) = old_return_type.is_generically_typed() {
-                let generic_args: Vec<_> = correct_types
-                    .iter()
-                    .zip(arg_types.iter())
-                    .filter_map(|(generic, arg)| {
-                        if generic.is_generically_typed().is_some() { Some(arg) }
-                        else { None }
-                    })
-                    .collect();
-
-                let generic_type = generic_args[0].get_generic_type();
-                if generic_args.iter().all(|x| x.get_generic_type() == generic_type) {
-                    let ty = TypeInfo::from_pieces(generic_type.unwrap(), generic_size);
-                    return Ok(ExpressionEvaluation::new(ty, is_const, false));
+                if let Some(arg_range) = read_only {
+                    let fallback = format!("Cannot pass a constant value to the '{}' parameter '{}'.", qualifier, name);
+                    let message = read_only_message(memory, written, &fallback);
+                    _ = memory.alert_error(&message, arg_range);
                 }
-            } else {
-                return Ok(ExpressionEvaluation::new(old_return_type.clone(), is_const, false));
             }
+            Ok(ExpressionEvaluation::new(ty, is_const && function_is_const, false))
+        } else {
+            let message = format!("Invalid arguments for function '{}'", call_name);
+            Err(memory.alert_error(&message, range))
         }
-        let message = format!("Invalid arguments for function '{}'", call_name);
-        Err(memory.alert_error(&message, range))
     } else {
         let message = format!("Function '{}' does not exist.", call_name);
         Err(memory.alert_error(&message, range))
     }
 }
 
+/// Returns the type that a call to `signature` evaluates to, if `arg_types`
+/// are valid arguments for it.
+pub fn match_signature(signature: &FunctionSignature, arg_types: &[TypeInfo]) -> Option<TypeInfo> {
+    let correct_types = signature.params
+        .iter()
+        .map(|x| x.ty.clone())
+        .collect::<Vec<_>>();
+    if arg_types != correct_types {
+        return None;
+    }
+    let old_return_type = &signature.return_type;
+
+    if let Some(primitive_type) = old_return_type.is_generically_sized() {
+        let generic_args: Vec<_> = correct_types
+            .iter()
+            .zip(arg_types.iter())
+            .filter_map(|(generic, arg)| {
+                if generic.is_generically_sized().is_some() { Some(arg) }
+                else { None }
+            })
+            .collect();
+
+        let generic_size = generic_args[0].get_generic_size();
+        if generic_args.iter().all(|x| x.get_generic_size() == generic_size) {
+            Some(TypeInfo::from_pieces(primitive_type, generic_size.unwrap()))
+        } else {
+            None
+        }
+    } else if let Some(generic_size) = old_return_type.is_generically_typed() {
+        let generic_args: Vec<_> = correct_types
+            .iter()
+            .zip(arg_types.iter())
+            .filter_map(|(generic, arg)| {
+                if generic.is_generically_typed().is_some() { Some(arg) }
+                else { None }
+            })
+            .collect();
+
+        let generic_type = generic_args[0].get_generic_type();
+        if generic_args.iter().all(|x| x.get_generic_type() == generic_type) {
+            Some(TypeInfo::from_pieces(generic_type.unwrap(), generic_size))
+        } else {
+            None
+        }
+    } else {
+        Some(old_return_type.clone())
+    }
+}
+
+/// Whether `arg_types` could be the first arguments of a complete call to
+/// `signature`. Arguments whose type is unknown match any parameter.
+pub fn match_signature_prefix(signature: &FunctionSignature, arg_types: &[Option<TypeInfo>]) -> bool {
+    arg_types.len() <= signature.params.len() && signature.params
+        .iter()
+        .zip(arg_types.iter())
+        .all(|(param, arg)| arg.as_ref().is_none_or(|x| param.ty == *x))
+}
+
+/// The identifier and member tokens that are written to when `expr` is
+/// assigned to.
+fn assigned_tokens(expr: &ExpressionNode) -> Vec<Token> {
+    match expr {
+        ExpressionNode::Identifier(x) => vec![*x],
+        ExpressionNode::Paren(x) => assigned_tokens(x),
+        ExpressionNode::ArrayAccess(x) => assigned_tokens(&x.argument),
+        ExpressionNode::MemberAccess(x) => {
+            let mut tokens = assigned_tokens(&x.argument);
+            tokens.push(x.member);
+            tokens
+        }
+        _ => vec![]
+    }
+}
+
+/// Explains why a value cannot be written. A builtin that another stage may
+/// write names those stages, and anything else gets `fallback`.
+fn read_only_message(memory: &Memory, written: &[Token], fallback: &str) -> String {
+    let (stage, name) = match (&memory.current_function, written.first()) {
+        (Some(stage), Some(token)) => (stage.clone(), memory.get_token_text(*token)),
+        _ => return fallback.to_string()
+    };
+    let is_builtin = memory.scopes.collect_scopes()
+        .iter()
+        .rev()
+        .find_map(|scope| scope.get(&name))
+        .is_some_and(|x| x.range.is_none());
+    let writable_in: Vec<String> = shader_stages(memory.shader_type)
+        .iter()
+        .filter(|x| **x != stage)
+        .filter(|x| stage_variables(memory.shader_type, x).iter().any(|(x, info)| *x == name && info.editable))
+        .map(|x| format!("'{}'", x))
+        .collect();
+    if !is_builtin || writable_in.is_empty() {
+        return fallback.to_string();
+    }
+    format!(
+        "'{}' is read-only in the '{}' function. It can be written in {}.",
+        name, stage, writable_in.join(" and ")
+    )
+}
+
 fn eval_conditional(
     memory: &mut Memory,
     conditional: ConditionalNode,
@@ -500,14 +657,21 @@ fn eval_identifier_expr(
     range: lsp_types::Range
 ) -> ExprEvalResult {
     let identifier = memory.get_token_text(value);
-    for scope in memory.scopes.collect_scopes() {
-        if let Some(value) = scope.get(&identifier) {
-            return Ok(ExpressionEvaluation::new(
-                value.ty.clone(),
-                value.is_const,
-                true
-            ))
+    let found = memory.scopes.collect_scopes()
+        .iter()
+        .rev()
+        .find_map(|scope| scope.get(&identifier))
+        .cloned();
+    if let Some(info) = found {
+        if let Some(declaration) = info.range {
+            let declaration = SymbolLocation { include_path: info.include_path, range: declaration };
+            memory.add_reference(declaration, value.range, ReferenceKind::Read);
         }
+        return Ok(ExpressionEvaluation::new(info.ty, info.is_const, info.editable))
+    }
+    if let Some(info) = memory.find_stage_builtin(&identifier) {
+        memory.builtin_uses.push(BuiltinUse { name: identifier, range: value.range, written: false, call_stages: None });
+        return Ok(ExpressionEvaluation::new(info.ty, false, true))
     }
     let message = format!("Identifier '{}' is undefined.", identifier);
     Err(memory.alert_error(&message, range))
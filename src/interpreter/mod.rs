@@ -1,9 +1,9 @@
-use lsp_types::Range;
+use lsp_types::{Position, Range};
 use regex::Regex;
 
 use crate::{
     lexer::{OperationType, Token},
-    memory::{Memory, Primitive, TypeInfo},
+    memory::{Memory, Primitive, ReferenceKind, SymbolLocation, TypeInfo},
     nodes::{ExpressionNode, TypeNode, ValueNode}
 };
 
@@ -41,10 +41,30 @@ pub fn ensure_valid_type(
         let message = format!("Invalid type '{}'.", ty.info.to_string());
         Err(memory.alert_error(&message, ty.range))
     } else {
+        add_type_reference(memory, ty);
         Ok(())
     }
 }
 
+/// Records a use of a struct in a type position, e.g. `MyStruct x;`.
+pub fn add_type_reference(memory: &mut Memory, ty: &TypeNode) {
+    if let Some(struct_info) = memory.structs.get(&ty.info.base) {
+        let declaration = SymbolLocation {
+            include_path: struct_info.include_path.clone(),
+            range: struct_info.range
+        };
+        let start = ty.range.start;
+        let end = Position::new(start.line, start.character + ty.info.base.len() as u32);
+        memory.add_reference(declaration, Range::new(start, end), ReferenceKind::Read);
+    }
+}
+
+/// Records the declaration site of a symbol declared in the current document.
+pub fn add_declaration(memory: &mut Memory, declaration: Range, identifier: Token) {
+    let location = SymbolLocation { include_path: None, range: declaration };
+    memory.add_reference(location, identifier.range, ReferenceKind::Declaration);
+}
+
 
 pub fn ensure_valid_value(
     memory: &mut Memory,
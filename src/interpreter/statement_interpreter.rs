@@ -1,6 +1,6 @@
 use crate::{lexer::Token, memory::*, nodes::*};
 
-use super::{ensure_valid_value, evaluate_expression, EvaluateError};
+use super::{add_declaration, ensure_valid_value, evaluate_expression, EvaluateError};
 
 pub fn eval_block(
     memory: &mut Memory,
@@ -19,11 +19,15 @@ pub fn evaluate_statement(
         StatementNode::VarDeclaration(x) => evaluate_var_declaration(memory, x),
         StatementNode::If(x) => evaluate_if_statement(x, memory),
         StatementNode::While(x) => evaluate_while_statement(x, memory),
+        StatementNode::DoWhile(x) => evaluate_do_while_statement(x, memory),
         StatementNode::For(x) => evaluate_for_statement(x, memory),
         StatementNode::Switch(x) => evaluate_switch_statement(x, memory),
         StatementNode::Expression(x) => evaluate_expression(memory, *x).map(|_| ()),
         StatementNode::Continue(x) => evaluate_continue_statement(memory, x),
         StatementNode::Break(x) => evaluate_break_statement(memory, x),
+        // Where `discard` may be used depends on the stages calling the
+        // function, which are only known once the document is evaluated.
+        StatementNode::Discard(_) => Ok(()),
         StatementNode::Return(x) => evaluate_return_statement(memory, x),
         StatementNode::Block(x) => {
             eval_block(memory, x);
@@ -91,11 +95,14 @@ fn evaluate_switch_statement(
     }
 
 
+    // The cases share one scope, which `break` may leave as it leaves a loop.
+    memory.scopes.enter_scope(ScopeType::Loop, node.range);
     for case in node.cases {
         for statement in case.statements {
             _ = evaluate_statement(memory, statement);
         }
     }
+    memory.scopes.leave_scope();
     Ok(())
 }
 
@@ -158,6 +165,30 @@ fn evaluate_while_statement(
     Ok(())
 }
 
+fn evaluate_do_while_statement(
+    node: DoWhileNode,
+    memory: &mut Memory
+) -> Result<(), EvaluateError> {
+    let block_range = match *node.action {
+        StatementNode::Block(ref b) => Some(b.range),
+        _ => None
+    };
+    if let Some(range) = block_range {
+        memory.scopes.enter_scope(ScopeType::Loop, range)
+    }
+    _ = evaluate_statement(memory, *node.action);
+    if block_range.is_some() {
+        memory.scopes.leave_scope();
+    }
+    let condition_range = node.condition.range();
+    let condition_result = evaluate_expression(memory, *node.condition)?;
+    if condition_result.type_info != TypeInfo::from_str("bool") {
+        let message = "Do-while loop condition must be a boolean expression.";
+        memory.alert_error(message, condition_range);
+    }
+    Ok(())
+}
+
 fn evaluate_if_statement(
     node: IfNode,
     memory: &mut Memory
@@ -165,7 +196,6 @@ fn evaluate_if_statement(
     let condition_range = node.condition.range();
     let condition_result = evaluate_expression(memory, *node.condition)?;
     if condition_result.type_info != TypeInfo::from_str("bool") {
-        eprintln!("this is what it was {:?}", condition_result.type_info);
         let message = "If statement condition must be a boolean expression.";
         memory.alert_error(message, condition_range);
     } 
@@ -210,11 +240,13 @@ fn evaluate_var_declaration(
     let ty = value.type_node.info.clone();
     let range = value.range;
     let (is_const, editable) = (node.is_const, !node.is_const);
+    add_declaration(memory, range, value.identifier);
     memory.scopes.insert( name, ValueInfo {
         ty,
         is_const,
         editable,
         range: Some(range),
+        include_path: None,
         description: None
     });
     Ok(())
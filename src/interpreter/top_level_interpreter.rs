@@ -1,6 +1,6 @@
-use std::{collections::HashSet, fs, mem};
+use std::{collections::{HashMap, HashSet}, mem};
 
-use crate::{evaluate_tree, lexer::TokenStream, memory::*, nodes::*, parse_tokens, parser::parse_int};
+use crate::{evaluate_tree, includes::IncludeSources, lexer::{MacroInfo, TokenStream}, line_index::PositionEncoding, memory::*, nodes::*, parse_tokens, parser::parse_int, resolve_include_path};
 
 use super::*;
 
@@ -68,11 +68,13 @@ pub fn evaluate_const(memory: &mut Memory, node: ConstNode) -> EvaluateResult {
     let ty = value.type_node.info.clone();
     let range = value.range;
     let (is_const, editable) = (true, false);
+    add_declaration(memory, range, value.identifier);
     memory.scopes.insert( name, ValueInfo {
         ty,
         is_const,
         editable,
         range: Some(range),
+        include_path: None,
         description: None
     });
     Ok(())
@@ -86,11 +88,13 @@ pub fn evaluate_varying(memory: &mut Memory, node: VaryingNode) -> EvaluateResul
     let ty = value.type_node.info.clone();
     let range = value.range;
     let (is_const, editable) = (false, true);
+    add_declaration(memory, range, value.identifier);
     memory.scopes.insert( name, ValueInfo {
         ty,
         is_const,
         editable,
         range: Some(range),
+        include_path: None,
         description: None
     });
     Ok(())
@@ -125,11 +129,13 @@ pub fn evaluate_uniform(memory: &mut Memory, node: UniformNode) -> EvaluateResul
     }
 
     let (is_const, editable) = (false, false);
+    add_declaration(memory, range, value.identifier);
     memory.scopes.insert( name, ValueInfo {
         ty,
         is_const,
         editable,
         range: Some(range),
+        include_path: None,
         description: None
     });
     Ok(())
@@ -146,16 +152,19 @@ pub fn evaluate_struct(memory: &mut Memory, node: StructNode) -> EvaluateResult
             return Err(memory.alert_error(&message, field.range)); 
         }
         ensure_valid_type(memory, &field.type_node)?;
+        add_declaration(memory, field.range, field.identifier);
         fields.push(StructField{
             name: field_name,
             ty: field.type_node.info.clone(),
             range: field.range
         })
     }
+    add_declaration(memory, node.identifier.range, node.identifier);
     memory.structs.insert(
         memory.get_token_text(node.identifier), StructInfo {
             fields,
-            range: node.identifier.range
+            range: node.identifier.range,
+            include_path: None
         }
     );
     Ok(())
@@ -167,6 +176,8 @@ pub fn evaluate_function(
 ) -> Result<(), EvaluateError> {
     let function_name = memory.get_token_text(node.identifier);
     ensure_valid_id(memory, node.identifier)?;
+    add_declaration(memory, node.identifier.range, node.identifier);
+    add_type_reference(memory, &node.type_node);
     let mut params: Vec<(FunctionParam, Range)> = vec![];
     for param in &node.params {
         let param_name = memory.get_token_text(param.value_node.identifier);
@@ -175,13 +186,38 @@ pub fn evaluate_function(
             return Err(memory.alert_error(&message, param.value_node.range)); 
         }
         ensure_valid_type(memory, &param.value_node.type_node)?;
+        let qualifier = param.qualifier.map(FunctionParamQualifier::from);
+        if let (Some(keyword), Some(FunctionParamQualifier::Out | FunctionParamQualifier::InOut)) = (param.const_keyword, &qualifier) {
+            let message = "A 'const' parameter cannot be 'out' or 'inout'.";
+            return Err(memory.alert_error(message, keyword.range));
+        }
+        add_declaration(memory, param.value_node.range, param.value_node.identifier);
         params.push((FunctionParam {
             name: param_name,
             ty: param.value_node.type_node.info.clone(),
-            qualifier: param.qualifier.clone().map(|x| FunctionParamQualifier::from(x))
+            qualifier,
+            is_const: param.const_keyword.is_some()
         }, param.value_node.range))
     }
 
+    // Registered before the body is evaluated, so that a call to the function
+    // from its own body resolves and is reported as recursion.
+    memory.functions.insert(
+        function_name.clone(), FunctionInfo {
+            signatures: vec![
+                FunctionSignature {
+                    return_type: node.type_node.info.clone(),
+                    params: params.iter().map(|(x, _)| x.clone()).collect(),
+                    stages: None
+                }
+            ],
+            range: Some(node.identifier.range),
+            include_path: None,
+            description: None,
+            is_const: false
+        }
+    );
+
     let expected = match node.type_node.info.clone() {
         ty if ty.base == "void" => None, 
         ty => Some(ty)
@@ -199,63 +235,23 @@ pub fn evaluate_function(
             ValueInfo {
                 ty: param.0.ty.clone(),
                 is_const: false,
-                editable: true,
+                editable: !param.0.is_const,
                 range: Some(param.1),
+                include_path: None,
                 description: None
             }
         );
     }
-    match memory.shader_type {
-        ShaderType::Spatial => match function_name.as_str() {
-            "vertex" => memory.scopes.extend(spatial_vertex_vars()),
-            "fragment" => memory.scopes.extend(spatial_fragment_vars()),
-            "light" => memory.scopes.extend(spatial_light_vars()),
-            _ => {}
-        }
-        ShaderType::CanvasItem => match function_name.as_str() {
-            "vertex" => memory.scopes.extend(canvas_item_vertex_vars()),
-            "fragment" => memory.scopes.extend(canvas_item_fragment_vars()),
-            "light" => memory.scopes.extend(canvas_item_light_vars()),
-            _ => {}
-        }
-        ShaderType::Particles => match function_name.as_str() {
-            "start" => {
-                memory.scopes.extend(particle_start_process());
-                memory.scopes.extend(particle_start());
-            }
-            "process" => {
-                memory.scopes.extend(particle_start_process());
-                memory.scopes.extend(particle_process());
-            }
-            _ => {}
-        }
-        ShaderType::Sky => if function_name == "fog" {
-            memory.scopes.extend(sky_stuff())
-        }
-        ShaderType::Fog => if function_name == "sky" {
-            memory.scopes.extend(fog_stuff())
-        },
-    }
+    memory.scopes.extend(stage_variables(memory.shader_type, &function_name));
+    memory.current_function = Some(function_name);
     eval_block(memory, node.block);
+    memory.current_function = None;
     if !memory.scopes.assert_returned() { 
         let message = format!("Expected return type '{}'", node.type_node.info.to_string());
         _ = memory.alert_error(&message, node.identifier.range);
     }
     memory.scopes.leave_scope();
 
-    memory.functions.insert(
-        function_name, FunctionInfo {
-            signatures: vec![
-                FunctionSignature {
-                    return_type: node.type_node.info,
-                    params: params.into_iter().map(|(x, _)| x).collect()
-                }
-            ],
-            range: Some(node.identifier.range),
-            description: None,
-            is_const: false
-        }
-    );
     Ok(())
 }
 
@@ -265,32 +261,61 @@ fn evaluate_include(memory: &mut Memory, node: IncludeNode) -> Result<(), Evalua
     } else {
         return Ok(());
     };
-    let include_path = memory.get_token_text(node.path)
-        .replace("res://", &root)
-        .trim_matches(|x| x == '"').to_string();
+    let include_path = resolve_include_path(&root, &memory.get_token_text(node.path));
 
-    let include_text = if let Ok(text) = fs::read_to_string(include_path.clone()) {
+    let include_text = if let Some(text) = memory.include_sources.read(&include_path) {
         text
     } else {
         let message = "invalid shader include directory";
         return Err(memory.alert_error(message, node.path.range));
     };
-    
-    let mut include_stream = TokenStream::new(&include_text, None);
-    let mut include_memory = Memory::new(&include_text, None);
-    let include_tree = parse_tokens(&mut include_stream);
-    evaluate_tree(&mut include_memory, include_tree);
+    let macros = memory.macros_at_includes
+        .iter()
+        .find(|(range, _)| *range == node.path.range)
+        .map_or(HashMap::new(), |(_, macros)| macros.clone());
+    let include_memory = evaluate_include_source(&include_text, memory.get_source().encoding(), macros);
 
     for (key, value) in &include_memory.scopes.scopes[0].values {
-        memory.scopes.insert(key.clone(), value.clone());
+        if value.range.is_none() {
+            continue;
+        }
+        let mut value = value.clone();
+        value.include_path.get_or_insert(include_path.clone());
+        memory.scopes.insert(key.clone(), value);
     }
     for (key, value) in &include_memory.functions {
-        memory.functions.insert(key.clone(), value.clone());
+        if value.range.is_none() {
+            continue;
+        }
+        let mut value = value.clone();
+        value.include_path.get_or_insert(include_path.clone());
+        memory.functions.insert(key.clone(), value);
+    }
+    for (key, value) in &include_memory.structs {
+        let mut value = value.clone();
+        value.include_path.get_or_insert(include_path.clone());
+        memory.structs.insert(key.clone(), value);
     }
 
     Ok(())
 }
 
+/// Evaluates the contents of a shader include on its own, starting from the
+/// macros defined where it is included. Includes are not followed from here,
+/// since the memory has no root directory.
+pub fn evaluate_include_source(
+    include_text: &str,
+    encoding: PositionEncoding,
+    macros: HashMap<String, MacroInfo>
+) -> Memory {
+    let mut include_stream = TokenStream::preprocessed(include_text, None, encoding, None, &IncludeSources::new(), macros);
+    let mut include_memory = Memory::new(include_text, None, encoding);
+    include_memory.load_preprocessor_state(&include_stream);
+    let include_tree = parse_tokens(&mut include_stream);
+    evaluate_tree(&mut include_memory, include_tree);
+    include_memory
+}
+
 
 
 
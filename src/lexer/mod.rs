@@ -1,7 +1,9 @@
 mod token;
 mod token_stream;
 mod operations;
+mod preprocessor;
 
 pub use token::*;
 pub use token_stream::*;
 pub use operations::*;
+pub use preprocessor::*;
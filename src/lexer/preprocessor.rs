@@ -0,0 +1,717 @@
+use std::{collections::HashMap, mem};
+
+use lsp_types::{Position, Range};
+
+use crate::{includes::IncludeSources, line_index::PositionEncoding, resolve_include_path, source_code::SourceDocument};
+use super::{int_literal_value, lex_tokens, Token, TokenKind};
+use TokenKind::{
+    Ampersand, And, Bang, BoolConstant, Caret, Colon, Comma, Dash, EqOp, GeqOp, Hash,
+    Identifier, Include, IntConstant, LeftAngle, LeftOp, LeftParen, LeqOp, NeqOp, Or,
+    Percent, Plus, Question, RightAngle, RightOp, RightParen, Slash, Star, Tilde,
+    UintConstant, VerticalBar, RightBracket, LeftBracket, Dot, Semicolon
+};
+
+/// A macro created with `#define`.
+#[derive(Clone, Debug)]
+pub struct MacroInfo {
+    /// Parameter names of a function-like macro, or `None` for an object-like one.
+    pub params: Option<Vec<String>>,
+    /// The replacement list, as the kind and text of each token.
+    pub body: Vec<(TokenKind, String)>,
+    /// The range of the macro name in its `#define`.
+    pub range: Option<Range>,
+    pub include_path: Option<String>,
+}
+
+impl MacroInfo {
+    /// An object-like macro defined outside of any source, such as one
+    /// given by the client.
+    pub fn predefined(value: &str) -> Self {
+        let value = value.to_string();
+        // Only the text of the tokens is kept, so any encoding will do.
+        let encoding = PositionEncoding::default();
+        let body = lex_tokens(&value, encoding)
+            .iter()
+            .map(|x| (x.kind, x.text(&value, encoding)))
+            .collect();
+        Self { params: None, body, range: None, include_path: None }
+    }
+
+    pub fn body_text(&self) -> String {
+        join_token_text(&self.body)
+    }
+
+    /// The macro as it would be written in its `#define`.
+    pub fn definition(&self, name: &str) -> String {
+        let params = self.params.as_ref().map_or("".to_string(), |x| format!("({})", x.join(", ")));
+        format!("#define {}{} {}", name, params, self.body_text())
+    }
+}
+
+/// Joins token text back into readable code, only putting spaces where
+/// they are usually written.
+pub fn join_token_text(tokens: &[(TokenKind, String)]) -> String {
+    let mut result = String::new();
+    let mut previous = None;
+    for (kind, text) in tokens {
+        let attached = matches!(kind, RightParen | RightBracket | Comma | Dot | Semicolon)
+            || matches!(previous, Some(LeftParen | LeftBracket | Dot))
+            || *kind == LeftParen && previous == Some(Identifier);
+        if previous.is_some() && !attached {
+            result.push(' ');
+        }
+        result.push_str(text);
+        previous = Some(*kind);
+    }
+    result
+}
+
+pub struct PreprocessorResult {
+    pub tokens: Vec<Token>,
+    pub macros: HashMap<String, MacroInfo>,
+    pub inactive_ranges: Vec<Range>,
+    /// Paths of the includes that were read, directly or through other includes.
+    pub include_paths: Vec<String>,
+    /// The macros defined where each include of the source is, by the range
+    /// of its path.
+    pub macros_at_includes: Vec<(Range, HashMap<String, MacroInfo>)>,
+}
+
+struct Conditional {
+    parent_active: bool,
+    /// True once one of the branches of this conditional has been taken.
+    taken: bool,
+    active: bool,
+    seen_else: bool,
+    range: Range,
+}
+
+struct Preprocessor<'a> {
+    source: &'a mut SourceDocument,
+    root_dir: Option<&'a str>,
+    includes: &'a IncludeSources,
+    include_path: Option<String>,
+    include_stack: Vec<String>,
+    include_paths: Vec<String>,
+    macros: HashMap<String, MacroInfo>,
+    macros_at_includes: Vec<(Range, HashMap<String, MacroInfo>)>,
+    conditionals: Vec<Conditional>,
+    inactive_start: Option<u32>,
+    inactive_ranges: Vec<Range>,
+}
+
+/// Evaluates the preprocessor directives in `tokens` and expands macros.
+/// Expanded tokens keep the range of their invocation, and their text is
+/// stored in the expansion table of `source`.
+pub fn preprocess(
+    tokens: Vec<Token>,
+    source: &mut SourceDocument,
+    root_dir: Option<&str>,
+    includes: &IncludeSources,
+    macros: HashMap<String, MacroInfo>
+) -> PreprocessorResult {
+    Preprocessor::new(source, root_dir, includes, macros).run(tokens)
+}
+
+impl<'a> Preprocessor<'a> {
+    fn new(
+        source: &'a mut SourceDocument,
+        root_dir: Option<&'a str>,
+        includes: &'a IncludeSources,
+        macros: HashMap<String, MacroInfo>
+    ) -> Self {
+        Self {
+            source,
+            root_dir,
+            includes,
+            include_path: None,
+            include_stack: vec![],
+            include_paths: vec![],
+            macros,
+            macros_at_includes: vec![],
+            conditionals: vec![],
+            inactive_start: None,
+            inactive_ranges: vec![],
+        }
+    }
+
+    fn run(mut self, tokens: Vec<Token>) -> PreprocessorResult {
+        let mut output = vec![];
+        let mut idx = 0;
+        while let Some(&token) = tokens.get(idx) {
+            if token.kind == Hash {
+                let (args, end) = self.directive_args(&tokens, idx);
+                self.directive(token, &args);
+                idx = end;
+            } else if !self.is_active() {
+                idx += 1;
+            } else if let Some((expanded, next)) = self.expand_at(&tokens, idx, &mut vec![]) {
+                output.extend(expanded);
+                idx = next;
+            } else {
+                if token.kind == Include {
+                    if let Some(&path) = tokens.get(idx + 1).filter(|x| x.kind == TokenKind::String) {
+                        self.macros_at_includes.push((path.range, self.macros.clone()));
+                        self.include_macros(path);
+                    }
+                }
+                output.push(token);
+                idx += 1;
+            }
+        }
+
+        while let Some(conditional) = self.conditionals.pop() {
+            let message = "Unterminated conditional directive.";
+            self.source.push_error(message, conditional.range, ());
+        }
+        let line_count = self.source.get_code().lines().count() as u32;
+        self.close_inactive(line_count + 1);
+
+        PreprocessorResult {
+            tokens: output,
+            macros: self.macros,
+            inactive_ranges: self.inactive_ranges,
+            include_paths: self.include_paths,
+            macros_at_includes: self.macros_at_includes
+        }
+    }
+
+    /// The tokens after the `#` at `tokens[idx]`, up to the end of its line.
+    /// A backslash at the end of a line continues the directive on the next
+    /// one, and is left out. Also returns the index after the directive.
+    fn directive_args(&self, tokens: &[Token], idx: usize) -> (Vec<Token>, usize) {
+        let mut line = tokens[idx].range.start.line;
+        let mut args = vec![];
+        let mut end = idx + 1;
+        while let Some(&token) = tokens.get(end).filter(|x| x.range.start.line == line) {
+            end += 1;
+            let ends_line = tokens.get(end).is_none_or(|x| x.range.start.line != line);
+            if ends_line && token.kind == TokenKind::Error && self.source.token_text(token) == "\\" {
+                line += 1;
+            } else {
+                args.push(token);
+            }
+        }
+        (args, end)
+    }
+
+    fn is_active(&self) -> bool {
+        self.conditionals.last().is_none_or(|x| x.active)
+    }
+
+    fn close_inactive(&mut self, line: u32) {
+        if let Some(start) = self.inactive_start.take() {
+            if start < line {
+                self.inactive_ranges.push(Range::new(
+                    Position::new(start, 0),
+                    Position::new(line, 0)
+                ));
+            }
+        }
+    }
+
+    fn directive(&mut self, hash: Token, args: &[Token]) {
+        let line = hash.range.start.line;
+        let range = Range::new(
+            hash.range.start,
+            args.last().map_or(hash.range.end, |x| x.range.end)
+        );
+        let name = args.first().map_or("".to_string(), |&x| self.source.token_text(x));
+        let rest = args.get(1..).unwrap_or(&[]);
+        let was_active = self.is_active();
+
+        match name.as_str() {
+            "if" | "ifdef" | "ifndef" => {
+                let condition = was_active && match name.as_str() {
+                    "if" => self.evaluate_condition(rest, range),
+                    "ifdef" => self.is_defined(rest, range),
+                    _ => !self.is_defined(rest, range)
+                };
+                self.conditionals.push(Conditional {
+                    parent_active: was_active,
+                    taken: condition,
+                    active: condition,
+                    seen_else: false,
+                    range
+                });
+            }
+            "elif" | "else" => {
+                let (parent_active, taken, seen_else) = match self.conditionals.last() {
+                    Some(x) => (x.parent_active, x.taken, x.seen_else),
+                    None => {
+                        let message = format!("#{} without #if.", name);
+                        return self.source.push_error(&message, range, ());
+                    }
+                };
+                if seen_else {
+                    let message = format!("#{} after #else.", name);
+                    return self.source.push_error(&message, range, ());
+                }
+                let condition = parent_active && !taken
+                    && (name == "else" || self.evaluate_condition(rest, range));
+                if let Some(conditional) = self.conditionals.last_mut() {
+                    conditional.active = condition;
+                    conditional.taken |= condition;
+                    conditional.seen_else = name == "else";
+                }
+            }
+            "endif" => {
+                if self.conditionals.pop().is_none() {
+                    self.source.push_error("#endif without #if.", range, ());
+                }
+            }
+            _ if !was_active => {}
+            "define" => self.define(rest, range),
+            "undef" => {
+                if let Some(name) = self.macro_name(rest, range) {
+                    self.macros.remove(&name);
+                }
+            }
+            "error" => {
+                let message = rest.iter()
+                    .map(|&x| self.source.token_text(x))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.source.push_error(&format!("#error {}", message), range, ());
+            }
+            "pragma" => {}
+            _ => self.source.push_error("Unknown preprocessor directive.", range, ())
+        }
+
+        let is_active = self.is_active();
+        if was_active && !is_active {
+            self.inactive_start = Some(range.end.line + 1);
+        } else if !was_active && is_active {
+            self.close_inactive(line);
+        }
+    }
+
+    fn macro_name(&mut self, args: &[Token], range: Range) -> Option<String> {
+        match args.first() {
+            Some(&x) if x.kind == Identifier => Some(self.source.token_text(x)),
+            _ => self.source.push_error("Expected macro name.", range, None)
+        }
+    }
+
+    fn is_defined(&mut self, args: &[Token], range: Range) -> bool {
+        self.macro_name(args, range).is_some_and(|x| self.macros.contains_key(&x))
+    }
+
+    fn define(&mut self, args: &[Token], range: Range) {
+        let name = if let Some(x) = self.macro_name(args, range) { x } else { return };
+        let name_range = args[0].range;
+
+        let mut body_start = 1;
+        let params = match args.get(1) {
+            Some(x) if x.kind == LeftParen && x.range.start == name_range.end => {
+                let mut params = vec![];
+                let mut idx = 2;
+                if args.get(idx).map(|x| x.kind) == Some(RightParen) {
+                    idx += 1;
+                } else {
+                    loop {
+                        let param = args.get(idx).filter(|x| x.kind == Identifier);
+                        let separator = args.get(idx + 1).map(|x| x.kind);
+                        match (param, separator) {
+                            (Some(&param), Some(Comma | RightParen)) => {
+                                params.push(self.source.token_text(param));
+                                idx += 2;
+                                if separator == Some(RightParen) { break }
+                            }
+                            _ => {
+                                let message = "Invalid macro parameter list.";
+                                return self.source.push_error(message, range, ());
+                            }
+                        }
+                    }
+                }
+                body_start = idx;
+                Some(params)
+            }
+            _ => None
+        };
+
+        let body = args.get(body_start..)
+            .unwrap_or(&[])
+            .iter()
+            .map(|&x| (x.kind, self.source.token_text(x)))
+            .collect();
+
+        self.macros.insert(name, MacroInfo {
+            params,
+            body,
+            range: Some(name_range),
+            include_path: self.include_path.clone()
+        });
+    }
+
+    /// Macros defined in an include are visible to the rest of the document.
+    fn include_macros(&mut self, path: Token) {
+        let root = if let Some(root) = self.root_dir { root } else { return };
+        let include_path = resolve_include_path(root, &self.source.token_text(path));
+        if self.include_stack.contains(&include_path) {
+            return;
+        }
+        if !self.include_paths.contains(&include_path) {
+            self.include_paths.push(include_path.clone());
+        }
+        let include_text = if let Some(text) = self.includes.read(&include_path) { text }
+        else { return };
+
+        let encoding = self.source.encoding();
+        let mut include_source = SourceDocument::new(&include_text, encoding);
+        let macros = mem::take(&mut self.macros);
+        let mut preprocessor = Preprocessor::new(&mut include_source, self.root_dir, self.includes, macros);
+        preprocessor.include_stack = self.include_stack.clone();
+        preprocessor.include_stack.push(include_path.clone());
+        preprocessor.include_path = Some(include_path);
+        let result = preprocessor.run(lex_tokens(&include_text, encoding));
+        self.macros = result.macros;
+        for path in result.include_paths {
+            if !self.include_paths.contains(&path) {
+                self.include_paths.push(path);
+            }
+        }
+    }
+
+    /// Expands every macro in `tokens`.
+    fn expand(&mut self, tokens: &[Token], hidden: &mut Vec<String>) -> Vec<Token> {
+        let mut output = vec![];
+        let mut idx = 0;
+        while let Some(&token) = tokens.get(idx) {
+            if let Some((expanded, next)) = self.expand_at(tokens, idx, hidden) {
+                output.extend(expanded);
+                idx = next;
+            } else {
+                output.push(token);
+                idx += 1;
+            }
+        }
+        output
+    }
+
+    /// Expands the macro invocation at `tokens[idx]`, if there is one, and
+    /// returns the expanded tokens along with the index after the invocation.
+    /// `hidden` holds the macros currently being expanded, which are not
+    /// expanded again.
+    fn expand_at(
+        &mut self,
+        tokens: &[Token],
+        idx: usize,
+        hidden: &mut Vec<String>
+    ) -> Option<(Vec<Token>, usize)> {
+        let token = tokens[idx];
+        if token.kind != Identifier {
+            return None;
+        }
+        let name = self.source.token_text(token);
+        if hidden.contains(&name) {
+            return None;
+        }
+        let info = self.macros.get(&name)?.clone();
+
+        let (args, end) = match &info.params {
+            None => (vec![], idx + 1),
+            Some(params) => {
+                if tokens.get(idx + 1).map(|x| x.kind) != Some(LeftParen) {
+                    return None;
+                }
+                let (args, end) = match collect_arguments(tokens, idx + 2) {
+                    Some(x) => x,
+                    None => {
+                        let message = "Unterminated macro invocation.";
+                        return self.source.push_error(message, token.range, None);
+                    }
+                };
+                let empty_call = params.is_empty() && args.len() == 1 && args[0].is_empty();
+                if args.len() != params.len() && !empty_call {
+                    let message = format!(
+                        "Macro '{}' expects {} arguments, found {}.",
+                        name,
+                        params.len(),
+                        args.len()
+                    );
+                    let range = Range::new(token.range.start, tokens[end - 1].range.end);
+                    return self.source.push_error(&message, range, Some((vec![], end)));
+                }
+                (args, end)
+            }
+        };
+
+        let range = Range::new(token.range.start, tokens[end - 1].range.end);
+        let params = info.params.unwrap_or_default();
+        let mut body = vec![];
+        for (kind, text) in info.body {
+            let param = params.iter().position(|x| *x == text).filter(|_| kind == Identifier);
+            if let Some(param) = param {
+                body.extend(self.expand(&args[param], hidden));
+            } else {
+                let expansion = Some(self.source.push_expansion(text));
+                body.push(Token { kind, range, expansion });
+            }
+        }
+
+        hidden.push(name);
+        let expanded = self.expand(&body, hidden);
+        hidden.pop();
+        Some((expanded, end))
+    }
+
+    fn evaluate_condition(&mut self, args: &[Token], range: Range) -> bool {
+        let mut tokens = vec![];
+        let mut idx = 0;
+        while let Some(&token) = args.get(idx) {
+            if token.kind != Identifier || self.source.token_text(token) != "defined" {
+                tokens.push(token);
+                idx += 1;
+                continue;
+            }
+            let kinds: Vec<_> = args[idx + 1..].iter().take(3).map(|x| x.kind).collect();
+            let (name, next) = match kinds.as_slice() {
+                [LeftParen, Identifier, RightParen, ..] => (args[idx + 2], idx + 4),
+                [Identifier, ..] => (args[idx + 1], idx + 2),
+                _ => return self.source.push_error("Expected macro name.", range, false)
+            };
+            let defined = self.macros.contains_key(&self.source.token_text(name));
+            let text = if defined { "1" } else { "0" };
+            let expansion = Some(self.source.push_expansion(text.to_string()));
+            tokens.push(Token { kind: IntConstant, range: token.range, expansion });
+            idx = next;
+        }
+
+        let tokens = self.expand(&tokens, &mut vec![]);
+        let mut parser = ConditionParser { tokens: &tokens, idx: 0, source: self.source };
+        match parser.expression() {
+            Some(value) if parser.idx == tokens.len() => value != 0,
+            _ => self.source.push_error("Invalid preprocessor expression.", range, false)
+        }
+    }
+}
+
+/// Splits the arguments of a function-like macro call, starting after its
+/// opening parenthesis. Returns the arguments and the index after the
+/// closing parenthesis.
+fn collect_arguments(tokens: &[Token], start: usize) -> Option<(Vec<Vec<Token>>, usize)> {
+    let mut args = vec![vec![]];
+    let mut depth = 0;
+    for (idx, &token) in tokens.iter().enumerate().skip(start) {
+        match token.kind {
+            RightParen if depth == 0 => return Some((args, idx + 1)),
+            Comma if depth == 0 => {
+                args.push(vec![]);
+                continue;
+            }
+            LeftParen => depth += 1,
+            RightParen => depth -= 1,
+            _ => {}
+        }
+        args.last_mut()?.push(token);
+    }
+    None
+}
+
+/// Evaluates the integer expression of an `#if` or `#elif`, after macros
+/// have been expanded.
+struct ConditionParser<'a> {
+    tokens: &'a [Token],
+    idx: usize,
+    source: &'a SourceDocument,
+}
+
+impl ConditionParser<'_> {
+    fn peek(&self) -> Option<TokenKind> {
+        self.tokens.get(self.idx).map(|x| x.kind)
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Option<()> {
+        if self.peek() != Some(kind) {
+            return None;
+        }
+        self.idx += 1;
+        Some(())
+    }
+
+    fn expression(&mut self) -> Option<i64> {
+        let condition = self.binary(0)?;
+        if self.expect(Question).is_none() {
+            return Some(condition);
+        }
+        let if_true = self.expression()?;
+        self.expect(Colon)?;
+        let if_false = self.expression()?;
+        Some(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Option<i64> {
+        let mut left = self.unary()?;
+        while let Some(kind) = self.peek() {
+            let precedence = match binary_precedence(kind) {
+                Some(x) if x >= min_precedence => x,
+                _ => break
+            };
+            self.idx += 1;
+            let right = self.binary(precedence + 1)?;
+            left = apply_binary(kind, left, right)?;
+        }
+        Some(left)
+    }
+
+    fn unary(&mut self) -> Option<i64> {
+        let token = *self.tokens.get(self.idx)?;
+        self.idx += 1;
+        match token.kind {
+            Bang => Some((self.unary()? == 0) as i64),
+            Dash => self.unary()?.checked_neg(),
+            Plus => self.unary(),
+            Tilde => Some(!self.unary()?),
+            LeftParen => {
+                let value = self.expression()?;
+                self.expect(RightParen)?;
+                Some(value)
+            }
+            IntConstant | UintConstant => int_literal_value(&self.source.token_text(token))
+                .and_then(|x| i64::try_from(x).ok()),
+            BoolConstant => Some((self.source.token_text(token) == "true") as i64),
+            Identifier => Some(0),
+            _ => None
+        }
+    }
+}
+
+fn binary_precedence(kind: TokenKind) -> Option<u8> {
+    match kind {
+        Or => Some(1),
+        And => Some(2),
+        VerticalBar => Some(3),
+        Caret => Some(4),
+        Ampersand => Some(5),
+        EqOp | NeqOp => Some(6),
+        LeftAngle | RightAngle | LeqOp | GeqOp => Some(7),
+        LeftOp | RightOp => Some(8),
+        Plus | Dash => Some(9),
+        Star | Slash | Percent => Some(10),
+        _ => None
+    }
+}
+
+fn apply_binary(kind: TokenKind, left: i64, right: i64) -> Option<i64> {
+    match kind {
+        Or => Some((left != 0 || right != 0) as i64),
+        And => Some((left != 0 && right != 0) as i64),
+        VerticalBar => Some(left | right),
+        Caret => Some(left ^ right),
+        Ampersand => Some(left & right),
+        EqOp => Some((left == right) as i64),
+        NeqOp => Some((left != right) as i64),
+        LeftAngle => Some((left < right) as i64),
+        RightAngle => Some((left > right) as i64),
+        LeqOp => Some((left <= right) as i64),
+        GeqOp => Some((left >= right) as i64),
+        LeftOp => left.checked_shl(u32::try_from(right).ok()?),
+        RightOp => left.checked_shr(u32::try_from(right).ok()?),
+        Plus => left.checked_add(right),
+        Dash => left.checked_sub(right),
+        Star => left.checked_mul(right),
+        Slash => left.checked_div(right),
+        Percent => left.checked_rem(right),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::TokenStream, line_index::PositionEncoding, memory::Memory};
+    use super::*;
+
+    fn preprocessed(code: &str) -> TokenStream {
+        TokenStream::new(code, None, PositionEncoding::default())
+    }
+
+    fn token_texts(stream: &TokenStream) -> Vec<String> {
+        stream.tokens.iter().map(|&x| stream.get_source().token_text(x)).collect()
+    }
+
+    #[test]
+    fn if_and_elif_take_the_first_true_branch() {
+        let stream = preprocessed("#define A 2
+#if A > 1 && A < 2
+first
+#elif A == 2
+second
+#elif A
+third
+#else
+fourth
+#endif
+#if defined(B) || A == 1
+b
+#elif !defined B && (A * 2) % 3 == 1
+c
+#endif
+");
+        assert_eq!(token_texts(&stream), vec!["second", "c"]);
+        assert!(stream.get_source().get_diagnostics().is_empty());
+        let inactive: Vec<_> = stream.inactive_ranges.iter().map(|x| (x.start.line, x.end.line)).collect();
+        assert_eq!(inactive, vec![(2, 3), (6, 9), (11, 12)]);
+    }
+
+    #[test]
+    fn function_like_macros_expand_their_arguments() {
+        let stream = preprocessed("#define MUL(a, b) ((a) * (b))
+#define SQUARE(x) MUL(x, x)
+#define NOT_A_FUNCTION (x)
+SQUARE(1 + f(2, 3)) NOT_A_FUNCTION MUL
+");
+        assert_eq!(
+            token_texts(&stream).join(" "),
+            "( ( 1 + f ( 2 , 3 ) ) * ( 1 + f ( 2 , 3 ) ) ) ( x ) MUL"
+        );
+    }
+
+    #[test]
+    fn a_backslash_continues_a_directive() {
+        let stream = preprocessed("#define SUM(a, b) \\
+    a + \\
+    b
+#if SUM(1, \\
+    1) == 2
+SUM(x, y);
+#endif
+");
+        assert_eq!(token_texts(&stream).join(" "), "x + y ;");
+        assert!(stream.get_source().get_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn includes_see_the_macros_defined_before_them() {
+        let includes = IncludeSources::new();
+        includes.set_open("/project/lib.gdshaderinc", "#ifdef HAS_SCALE
+#define INCLUDED_SCALE 2.0
+#endif
+TYPE scaled(TYPE x) { return x * SCALE; }
+");
+        let code = "shader_type spatial;
+#define HAS_SCALE
+#define TYPE float
+#define SCALE 3.0
+#include \"res://lib.gdshaderinc\"
+void fragment() { ALBEDO = vec3(scaled(INCLUDED_SCALE)); }
+";
+        let stream = TokenStream::preprocessed(
+            code,
+            None,
+            PositionEncoding::default(),
+            Some("/project/"),
+            &includes,
+            HashMap::new()
+        );
+        assert!(token_texts(&stream).contains(&"2.0".to_string()));
+        assert!(stream.macros_at_includes[0].1.contains_key("SCALE"));
+
+        let mut memory = Memory::new(code, Some("/project/".to_string()), PositionEncoding::default());
+        memory.include_sources = includes;
+        let diagnostics = memory.evaluate_new(None);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert!(memory.functions.contains_key("scaled"));
+    }
+}
@@ -2,12 +2,18 @@ use std::usize;
 
 use lsp_types::{Position, Range};
 use strum_macros::AsRefStr;
-use logos::{skip, Logos, Skip, Source};
+use logos::{skip, Logos, Skip};
+
+use crate::line_index::PositionEncoding;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Token {
     pub kind: TokenKind,
-    pub range: Range
+    pub range: Range,
+    /// For tokens produced by a macro expansion, the index of their text in
+    /// the expansion table of the `SourceDocument`. Their `range` is then the
+    /// range of the macro invocation.
+    pub expansion: Option<usize>
 }
 
 impl Token {
@@ -15,17 +21,16 @@ impl Token {
         self.kind.as_ref()
     } 
 
-    pub fn text(&self, source: &String) -> String {
-        let line = &source.lines().nth(self.range.start.line as usize);
-        if let Some(line) = line {
-            let start = self.range.start.character as usize;
-            let end = self.range.end.character as usize;
-            let span = start .. end;
+    pub fn text(&self, source: &String, encoding: PositionEncoding) -> String {
+        let line = source.lines().nth(self.range.start.line as usize);
+        line.map_or("".to_string(), |x| self.text_in_line(x, encoding))
+    }
 
-            line.slice(span).map_or("".to_string(), |x| x.to_string())
-        } else {
-            "".to_string()
-        }
+    /// The text of the token, given the line it starts on.
+    pub fn text_in_line(&self, line: &str, encoding: PositionEncoding) -> String {
+        let start = encoding.column_to_byte(line, self.range.start.character);
+        let end = encoding.column_to_byte(line, self.range.end.character);
+        line.get(start..end).unwrap_or_default().to_string()
     }
 }
 
@@ -66,10 +71,11 @@ impl ExtraRange for Range {
 }
 
 #[derive(AsRefStr, Copy, Clone, Debug, Eq, Logos, PartialEq)]
-#[logos(extras=Vec<usize>, skip r"[ \t\f]+")]
+#[logos(extras=Vec<usize>, skip r"[ \t\f\r]+")]
 pub enum TokenKind {
     // Preprocessors
     #[token("#include")] Include,
+    #[token("#")] Hash,
     // Top Level Keywords
     #[token("shader_type")] ShaderType,
     #[token("render_mode")] RenderMode,
@@ -144,18 +150,18 @@ pub enum TokenKind {
     // Other
     #[regex("[a-zA-Z_][a-zA-Z0-9_]*")]
     Identifier,
-    #[regex("[0-9]+")]
+    #[regex("[0-9]+|0[xX][0-9a-fA-F]+")]
     IntConstant,
-    #[regex("[0-9]+u")]
+    #[regex("([0-9]+|0[xX][0-9a-fA-F]+)[uU]")]
     UintConstant,
-    #[regex("[0-9]*\\.[0-9]+|[0-9]+\\.[0-9]*(f)?")]
+    #[regex("([0-9]+\\.[0-9]*|\\.[0-9]+)([eE][+-]?[0-9]+)?[fF]?|[0-9]+[eE][+-]?[0-9]+[fF]?")]
     FloatConstant,
     #[regex("true|false")]
     BoolConstant,
     #[regex("\"(?:[^\"]|\\\\\")*\"")]
     String,
     #[regex("//[^\n]*", skip)]
-    #[regex("/\\*(?:[^*]|\\*[^/])*\\*/", skip)]
+    #[regex("/\\*(?:[^*]|\\*[^/])*\\*/", block_comment_callback)]
     Comment,
     #[regex(r"\n", newline_callback)]
     Newline,
@@ -167,3 +173,21 @@ fn newline_callback(lex: &mut logos::Lexer<TokenKind>) -> Skip {
     Skip
 }
 
+/// Block comments may span lines, which still have to be counted.
+fn block_comment_callback(lex: &mut logos::Lexer<TokenKind>) -> Skip {
+    let start = lex.span().start;
+    let newlines: Vec<usize> = lex.slice().match_indices('\n').map(|(idx, _)| start + idx + 1).collect();
+    lex.extras.extend(newlines);
+    Skip
+}
+
+
+/// The value of an int or uint constant, written in decimal or hexadecimal,
+/// or `None` if it does not even fit in 64 bits.
+pub fn int_literal_value(text: &str) -> Option<u64> {
+    let text = text.trim_end_matches(['u', 'U']);
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok()
+    }
+}
@@ -1,7 +1,9 @@
+use std::collections::HashMap;
+
 use logos::{Logos, Lexer};
 use lsp_types::{Position, Range};
-use crate::{completion::CompletionElement, source_code::SourceDocument};
-use super::{ExtraRange, Token, TokenKind};
+use crate::{completion::CompletionElement, includes::IncludeSources, line_index::{LineIndex, PositionEncoding}, source_code::SourceDocument};
+use super::{join_token_text, preprocess, ExtraRange, MacroInfo, Token, TokenKind};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenError {
@@ -11,9 +13,18 @@ pub enum TokenError {
 
 pub type TokenResult = Result<Token, TokenError>;
 
+#[derive(Clone)]
 pub struct TokenStream {
     current_idx: usize,
     pub tokens: Vec<Token>,
+    /// The macros that are still defined at the end of the source.
+    pub macros: HashMap<String, MacroInfo>,
+    /// Lines skipped by `#if` and friends.
+    pub inactive_ranges: Vec<Range>,
+    /// Paths of the includes read by the preprocessor.
+    pub include_paths: Vec<String>,
+    /// The macros defined where each include is, by the range of its path.
+    pub macros_at_includes: Vec<(Range, HashMap<String, MacroInfo>)>,
     source: SourceDocument,
     cursor: Option<Position>,
     pub cursor_element: CompletionElement,
@@ -24,26 +35,45 @@ pub struct TokenStream {
 }
 
 impl TokenStream {
-    pub fn new(source_str: &str, cursor: Option<Position>) -> Self {
-        let mut lexer = <TokenKind as Logos>::lexer(&source_str); 
-        let mut tokens = vec![];
-        let mut next_kind = lexer.next();
-        let mut range = get_lexer_current_range(&lexer);
-
-        while let Some(maybe_kind) = next_kind {
-            match maybe_kind {
-                Ok(kind) => tokens.push(Token { kind, range }),
-                Err(_) => tokens.push(Token { kind: TokenKind::Error, range }),
-            }
-            next_kind = lexer.next();
-            range = get_lexer_current_range(&lexer);
-        };
+    pub fn new(source_str: &str, cursor: Option<Position>, encoding: PositionEncoding) -> Self {
+        Self::preprocessed(source_str, cursor, encoding, None, &IncludeSources::new(), HashMap::new())
+    }
+
+    /// Lexes the source and runs it through the preprocessor, starting from
+    /// the given macros. `root_dir` is used to find macros in includes, which
+    /// are read from `includes`.
+    pub fn preprocessed(
+        source_str: &str,
+        cursor: Option<Position>,
+        encoding: PositionEncoding,
+        root_dir: Option<&str>,
+        includes: &IncludeSources,
+        macros: HashMap<String, MacroInfo>
+    ) -> Self {
+        let raw_tokens = lex_tokens(source_str, encoding);
+        Self::from_raw_tokens(source_str, raw_tokens, cursor, encoding, root_dir, includes, macros)
+    }
 
-        let source = SourceDocument::new(source_str);
+    /// Like `preprocessed`, for a source that has already been lexed.
+    pub fn from_raw_tokens(
+        source_str: &str,
+        raw_tokens: Vec<Token>,
+        cursor: Option<Position>,
+        encoding: PositionEncoding,
+        root_dir: Option<&str>,
+        includes: &IncludeSources,
+        macros: HashMap<String, MacroInfo>
+    ) -> Self {
+        let mut source = SourceDocument::new(source_str, encoding);
+        let result = preprocess(raw_tokens, &mut source, root_dir, includes, macros);
 
         Self {
             current_idx: 0,
-            tokens,
+            tokens: result.tokens,
+            macros: result.macros,
+            inactive_ranges: result.inactive_ranges,
+            include_paths: result.include_paths,
+            macros_at_includes: result.macros_at_includes,
             source,
             cursor,
             cursor_element: CompletionElement::TopLevelKeyword,
@@ -54,6 +84,26 @@ impl TokenStream {
         }
     }
 
+    /// A stream over some of this stream's tokens, used to parse a fragment
+    /// of the source on its own. Errors in the fragment are not recorded.
+    pub fn sub_stream(&self, tokens: &[Token]) -> Self {
+        Self {
+            current_idx: 0,
+            tokens: tokens.to_vec(),
+            macros: HashMap::new(),
+            inactive_ranges: vec![],
+            include_paths: vec![],
+            macros_at_includes: vec![],
+            source: self.source.clone(),
+            cursor: None,
+            cursor_element: CompletionElement::TopLevelKeyword,
+            cursor_element_queue: CompletionElement::TopLevelKeyword,
+            parsing_const: false,
+            parsing_statement: false,
+            record_errors: false,
+        }
+    }
+
     pub fn destroy(self) -> (Vec<Token>, SourceDocument) {
         (self.tokens, self.source)
     }
@@ -69,6 +119,10 @@ impl TokenStream {
         }
     }
 
+    pub fn set_cursor(&mut self, cursor: Option<Position>) {
+        self.cursor = cursor;
+    }
+
     pub fn current_idx(&self) -> usize {
         self.current_idx
     }
@@ -155,28 +209,166 @@ impl TokenStream {
         data
     }
 
+    /// Tokens written out in the source take priority over tokens expanded
+    /// from a macro, which all share the range of the invocation.
+    /// Macro invocations and directives are not part of the stream, so for
+    /// those the text written in the source is used.
     pub fn find_cursor_text(&self) -> Option<String> {
-        if let Some(cursor) = self.cursor {
-            for token in &self.tokens {
-                if token.range.contains_position(cursor) {
-                    return Some(token.text(&self.source.get_code()))
-                }
+        let cursor = self.cursor?;
+        let token = self.tokens
+            .iter()
+            .find(|x| x.expansion.is_none() && x.range.contains_position(cursor))
+            .copied()
+            .or_else(|| lex_tokens(self.source.get_code(), self.source.encoding())
+                .into_iter()
+                .find(|x| x.range.contains_position(cursor))
+            )?;
+        Some(self.source.token_text(token))
+    }
+
+    /// The fully expanded text of the innermost macro invocation under the
+    /// cursor.
+    pub fn find_cursor_expansion(&self) -> Option<String> {
+        let cursor = self.cursor?;
+        let range = self.tokens
+            .iter()
+            .filter(|x| x.expansion.is_some() && x.range.contains_position(cursor))
+            .map(|x| x.range)
+            .reduce(|a, b| if a.contains_position(b.start) && a.contains_position(b.end) { b } else { a })?;
+        let tokens: Vec<_> = self.tokens
+            .iter()
+            .filter(|x| range.contains_position(x.range.start) && range.contains_position(x.range.end))
+            .map(|&x| (x.kind, self.source.token_text(x)))
+            .collect();
+        Some(join_token_text(&tokens))
+    }
+}
+
+/// Lexes source code into raw tokens, before any preprocessing.
+pub fn lex_tokens(source_str: &str, encoding: PositionEncoding) -> Vec<Token> {
+    lex_from(source_str, 0, Position::default(), encoding).collect()
+}
+
+/// Lexes an edited source, reusing the tokens lexed from `old_source` outside
+/// of the edit. Lexing restarts at the last token that ends before the first
+/// changed byte, and stops at the first token below the edit that lines up
+/// with an old one, since the lexer finds the same tokens from there on.
+pub fn relex_tokens(
+    old_source: &str,
+    old_tokens: &[Token],
+    source_str: &str,
+    encoding: PositionEncoding
+) -> Vec<Token> {
+    let mut prefix = old_source.bytes().zip(source_str.bytes()).take_while(|(a, b)| a == b).count();
+    while !old_source.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let mut suffix = old_source.bytes().rev().zip(source_str.bytes().rev())
+        .take(old_source.len().min(source_str.len()) - prefix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old_source.is_char_boundary(old_source.len() - suffix) {
+        suffix -= 1;
+    }
+    let old_index = LineIndex::new(old_source, encoding);
+    let index = LineIndex::new(source_str, encoding);
+    let change_start = old_index.position(old_source, prefix);
+    let old_end_line = old_index.position(old_source, old_source.len() - suffix).line;
+    let end_line = index.position(source_str, source_str.len() - suffix).line;
+    let line_delta = end_line as i64 - old_end_line as i64;
+    let shift = |mut token: Token| {
+        token.range.start.line = (token.range.start.line as i64 + line_delta) as u32;
+        token.range.end.line = (token.range.end.line as i64 + line_delta) as u32;
+        token
+    };
+
+    // The last token ending before the edit is lexed again too, in case the
+    // edit extends it.
+    let kept = old_tokens.partition_point(|x| x.range.end < change_start).saturating_sub(1);
+    let mut tokens = old_tokens[..kept].to_vec();
+    let (start, position) = match old_tokens.get(kept) {
+        Some(x) => (old_index.offset(old_source, x.range.start), x.range.start),
+        None => (0, Position::default())
+    };
+    let mut following = old_tokens.partition_point(|x| x.range.start.line <= old_end_line);
+    for token in lex_from(source_str, start, position, encoding) {
+        if token.range.start.line > end_line {
+            while old_tokens.get(following).is_some_and(|&x| shift(x).range.start < token.range.start) {
+                following += 1;
+            }
+            if old_tokens.get(following).is_some_and(|&x| x.kind == token.kind && shift(x).range == token.range) {
+                tokens.extend(old_tokens[following..].iter().map(|&x| shift(x)));
+                return tokens;
             }
         }
-        None
+        tokens.push(token);
     }
+    tokens
 }
 
-fn get_lexer_current_range<'a>(lexer: &Lexer<'a, TokenKind>) -> Range {
+/// Lexes `source_str` from the byte `start`, which is at `position`.
+fn lex_from(
+    source_str: &str,
+    start: usize,
+    position: Position,
+    encoding: PositionEncoding
+) -> impl Iterator<Item = Token> + '_ {
+    let mut lexer = <TokenKind as Logos>::lexer(&source_str[start..]);
+    std::iter::from_fn(move || {
+        let kind = lexer.next()?.unwrap_or(TokenKind::Error);
+        Some(Token { kind, range: get_lexer_current_range(&lexer, position, encoding), expansion: None })
+    })
+}
+
+fn get_lexer_current_range<'a>(
+    lexer: &Lexer<'a, TokenKind>,
+    start: Position,
+    encoding: PositionEncoding
+) -> Range {
     let span = lexer.span();
     let lines = &lexer.extras;
-    let current_line = lines.iter().filter(|&&c| c <= span.start).count();
+    let current_line = lines.partition_point(|&c| c <= span.start);
     let last_line_end = if current_line == 0 { 0 } else { lines[current_line - 1] };
-    let current_character = span.start - last_line_end;
-    let last_character = current_character + span.len();
+    let mut current_character = encoding.text_width(&lexer.source()[last_line_end..span.start]);
+    if current_line == 0 {
+        current_character += start.character;
+    }
+    let last_character = current_character + encoding.text_width(lexer.slice());
+    let line = start.line + current_line as u32;
 
     Range::new(
-        Position::new(current_line as u32, current_character as u32),
-        Position::new(current_line as u32, last_character as u32)
+        Position::new(line, current_character),
+        Position::new(line, last_character)
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = "shader_type spatial;\n#define GREETING \"hi\"\nuniform float a = 1.0;\n/* line one\nline two */\nvoid vertex() {\n\tfloat é = a * 2.0; // é\n\tVERTEX.y += é;\n}\n";
+
+    fn edit(code: &str, start: usize, end: usize, text: &str) {
+        let old_tokens = lex_tokens(code, PositionEncoding::Utf16);
+        let new_code = format!("{}{}{}", &code[..start], text, &code[end..]);
+        let expected = lex_tokens(&new_code, PositionEncoding::Utf16);
+        let tokens = relex_tokens(code, &old_tokens, &new_code, PositionEncoding::Utf16);
+        let summary = |tokens: &[Token]| tokens.iter().map(|x| (x.kind, x.range)).collect::<Vec<_>>();
+        assert_eq!(summary(&tokens), summary(&expected), "editing {:?} into {:?}", text, new_code);
+    }
+
+    #[test]
+    fn relexing_matches_lexing_everything() {
+        let offset = |text: &str| CODE.find(text).unwrap();
+        edit(CODE, 0, 0, "\n\n");
+        edit(CODE, offset("uniform"), offset("uniform"), "varying vec3 n;\n");
+        edit(CODE, offset("uniform"), offset("/*"), "");
+        edit(CODE, offset(" = 1.0"), offset(" = 1.0"), "bc");
+        edit(CODE, offset("line two"), offset("line two"), "*/ int x; /*\n");
+        edit(CODE, offset("/*"), offset("/*") + 2, "");
+        edit(CODE, offset("\"hi\""), offset("\"hi\"") + 1, "");
+        edit(CODE, offset("a * 2"), offset("a * 2") + 1, "éa\r\n");
+        edit(CODE, CODE.len(), CODE.len(), "void fragment() {}");
+        edit(CODE, 0, CODE.len(), "");
+    }
+}
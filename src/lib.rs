@@ -1,5 +1,8 @@
+use std::path::PathBuf;
+
 use interpreter::evaluate_top_level_node;
 use lexer::TokenStream;
+use line_index::{LineIndex, PositionEncoding};
 use lsp_types::{Position, TextDocumentContentChangeEvent};
 use memory::Memory;
 use nodes::TopLevelNode;
@@ -7,11 +10,22 @@ use parser::parse_top_level;
 
 pub mod lexer;
 pub mod source_code;
+pub mod includes;
+pub mod line_index;
 pub mod nodes;
 pub mod parser;
 pub mod interpreter;
 pub mod memory;
+pub mod check;
 pub mod completion;
+pub mod definition;
+pub mod document_symbols;
+pub mod formatting;
+pub mod references;
+pub mod rename;
+pub mod semantic_tokens;
+pub mod signature_help;
+pub mod workspace;
 
 pub fn parse_tokens(
     stream: &mut TokenStream,
@@ -38,60 +52,79 @@ pub fn evaluate_tree(
         _ = evaluate_top_level_node(top_level, memory);
     }
 }
-pub fn apply_change(source: &mut String, change: &TextDocumentContentChangeEvent){
-    if let Some(range) = change.range {
-        let start_byte = get_byte_offset_from_position(source, range.start);
-        let end_byte = get_byte_offset_from_position(source, range.end);
-        let new_text = &change.text;
-        if start_byte <= source.len() && end_byte <= source.len() && start_byte <= end_byte {
-            source.replace_range(start_byte..end_byte, new_text);
-        }
-    }
+/// Turns the quoted `res://` path of an include into a path on disk.
+pub fn resolve_include_path(root_dir: &str, include: &str) -> String {
+    include.replace("res://", root_dir).trim_matches(|x| x == '"').to_string()
+}
+
+/// The files under `root_dir` with one of `extensions`, in name order.
+/// Hidden directories, like the `.godot` cache and `.git`, are skipped.
+pub fn project_files(root_dir: &str, extensions: &[&str]) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(root_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|x| x.depth() == 0 || !x.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file())
+        .map(|x| x.into_path())
+        .filter(|x| x.extension().is_some_and(|x| extensions.iter().any(|y| x == *y)))
+        .collect()
 }
 
-pub fn get_byte_offset_from_position(source: &String, position: Position) -> usize {
-    let mut byte_offset = 0;
-    for (i, line) in source.lines().enumerate() {
-        if i as u32 == position.line {
-            byte_offset += position.character as usize;
-            break;
+pub fn apply_change(source: &mut String, change: &TextDocumentContentChangeEvent, encoding: PositionEncoding){
+    match change.range {
+        Some(range) => {
+            let start_byte = get_byte_offset_from_position(source, range.start, encoding);
+            let end_byte = get_byte_offset_from_position(source, range.end, encoding).max(start_byte);
+            source.replace_range(start_byte..end_byte, &change.text);
         }
-        byte_offset += line.len() + 1;
+        None => *source = change.text.clone()
     }
-    byte_offset
 }
 
-pub fn calculate_new_end_position(start: Position, new_text: &str) -> Position {
-    let new_lines = new_text.lines().collect::<Vec<&str>>();
-    let line_count = new_lines.len();
-
-    let new_end_line = if line_count > 1 {
-        start.line + line_count as u32 - 1
-    } else {
-        start.line
-    };
-    let new_end_character = if line_count > 1 {
-        new_lines.last().unwrap().len() as u32
-    } else {
-        start.character + new_text.len() as u32
-    };
+/// The byte offset of `position`, whose `character` is counted in `encoding`.
+pub fn get_byte_offset_from_position(source: &String, position: Position, encoding: PositionEncoding) -> usize {
+    LineIndex::new(source, encoding).offset(source, position)
+}
 
-    Position {
-        line: new_end_line,
-        character: new_end_character,
+pub fn calculate_new_end_position(start: Position, new_text: &str, encoding: PositionEncoding) -> Position {
+    match new_text.rsplit_once('\n') {
+        Some((before, last_line)) => Position {
+            line: start.line + before.matches('\n').count() as u32 + 1,
+            character: encoding.text_width(last_line),
+        },
+        None => Position {
+            line: start.line,
+            character: start.character + encoding.text_width(new_text),
+        }
     }
 }
 
-pub const DID_OPEN: &'static str = "textDocument/didOpen";
-pub const DID_CHANGE: &'static str = "textDocument/didChange";
-pub const DID_CLOSE: &'static str = "textDocument/didClose";
-pub const DID_SAVE: &'static str = "textDocument/didSave";
-pub const DID_MOUSE_MOVE: &'static str = "textDocument/didSave";
-pub const PUBLISH_DIAGNOSTICS: &'static str = "textDocument/publishDiagnostics";
-pub const INITIALIZE: &'static str = "initialize";
-pub const SHUTDOWN: &'static str = "shutdown";
-pub const EXIT: &'static str = "exit";
-pub const COMPLETION: &'static str = "textDocument/completion";
-pub const HOVER: &'static str = "textDocument/hover";
-pub const DEFINITION: &'static str = "textDocument/definition";
+pub const DID_OPEN: &str = "textDocument/didOpen";
+pub const DID_CHANGE: &str = "textDocument/didChange";
+pub const DID_CLOSE: &str = "textDocument/didClose";
+pub const DID_SAVE: &str = "textDocument/didSave";
+pub const DID_MOUSE_MOVE: &str = "textDocument/didSave";
+pub const PUBLISH_DIAGNOSTICS: &str = "textDocument/publishDiagnostics";
+pub const INITIALIZE: &str = "initialize";
+pub const SHUTDOWN: &str = "shutdown";
+pub const EXIT: &str = "exit";
+pub const COMPLETION: &str = "textDocument/completion";
+pub const HOVER: &str = "textDocument/hover";
+pub const DEFINITION: &str = "textDocument/definition";
+pub const REFERENCES: &str = "textDocument/references";
+pub const DOCUMENT_HIGHLIGHT: &str = "textDocument/documentHighlight";
+pub const PREPARE_RENAME: &str = "textDocument/prepareRename";
+pub const RENAME: &str = "textDocument/rename";
+pub const SEMANTIC_TOKENS_FULL: &str = "textDocument/semanticTokens/full";
+pub const SEMANTIC_TOKENS_RANGE: &str = "textDocument/semanticTokens/range";
+pub const FORMATTING: &str = "textDocument/formatting";
+pub const RANGE_FORMATTING: &str = "textDocument/rangeFormatting";
+pub const DOCUMENT_SYMBOL: &str = "textDocument/documentSymbol";
+pub const WORKSPACE_SYMBOL: &str = "workspace/symbol";
+pub const SIGNATURE_HELP: &str = "textDocument/signatureHelp";
+pub const INITIALIZED: &str = "initialized";
+pub const DID_CHANGE_WATCHED_FILES: &str = "workspace/didChangeWatchedFiles";
+pub const DID_CHANGE_CONFIGURATION: &str = "workspace/didChangeConfiguration";
+pub const REGISTER_CAPABILITY: &str = "client/registerCapability";
 
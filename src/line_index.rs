@@ -0,0 +1,161 @@
+use lsp_types::{Position, PositionEncodingKind};
+
+/// How the `character` of a position counts the text before it on its line.
+/// UTF-16 code units are the default of the protocol, and UTF-8 bytes are used
+/// when the client offers them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PositionEncoding {
+    Utf8,
+    #[default]
+    Utf16,
+}
+
+impl PositionEncoding {
+    /// Picks UTF-8 if the client lists it, and UTF-16 otherwise.
+    pub fn negotiate(offered: &[PositionEncodingKind]) -> Self {
+        if offered.contains(&PositionEncodingKind::UTF8) {
+            PositionEncoding::Utf8
+        } else {
+            PositionEncoding::Utf16
+        }
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            PositionEncoding::Utf8 => PositionEncodingKind::UTF8,
+            PositionEncoding::Utf16 => PositionEncodingKind::UTF16,
+        }
+    }
+
+    /// The number of columns `text` takes up.
+    pub fn text_width(self, text: &str) -> u32 {
+        if text.is_ascii() {
+            return text.len() as u32;
+        }
+        match self {
+            PositionEncoding::Utf8 => text.len() as u32,
+            PositionEncoding::Utf16 => text.encode_utf16().count() as u32,
+        }
+    }
+
+    /// The byte offset within `line` of the column `character`. Columns past
+    /// the end of the line, or inside a character, are moved back to a boundary.
+    pub fn column_to_byte(self, line: &str, character: u32) -> usize {
+        if line.is_ascii() {
+            return line.len().min(character as usize);
+        }
+        let mut column = 0;
+        for (idx, x) in line.char_indices() {
+            column += match self {
+                PositionEncoding::Utf8 => x.len_utf8() as u32,
+                PositionEncoding::Utf16 => x.len_utf16() as u32,
+            };
+            if column > character {
+                return idx;
+            }
+        }
+        line.len()
+    }
+}
+
+
+/// Where each line of a text starts, for turning positions into byte offsets
+/// and back. Lines end at `\n`, and a `\r` before it is not part of the line.
+#[derive(Clone, Debug, Default)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+    encoding: PositionEncoding,
+}
+
+impl LineIndex {
+    pub fn new(text: &str, encoding: PositionEncoding) -> Self {
+        let newlines = text.match_indices('\n').map(|(idx, _)| idx + 1);
+        Self { line_starts: std::iter::once(0).chain(newlines).collect(), encoding }
+    }
+
+    pub fn encoding(&self) -> PositionEncoding {
+        self.encoding
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// The text of a line, without its line ending.
+    pub fn line_text<'a>(&self, text: &'a str, line: u32) -> Option<&'a str> {
+        let start = *self.line_starts.get(line as usize)?;
+        let end = self.line_starts.get(line as usize + 1).map_or(text.len(), |&x| x - 1);
+        let line_text = text.get(start..end)?;
+        Some(line_text.strip_suffix('\r').unwrap_or(line_text))
+    }
+
+    /// The byte offset of `position`. Positions past the end of a line are
+    /// moved to its end, and positions past the last line to the end of the text.
+    pub fn offset(&self, text: &str, position: Position) -> usize {
+        match self.line_text(text, position.line) {
+            Some(line) => self.line_starts[position.line as usize] + self.encoding.column_to_byte(line, position.character),
+            None => text.len()
+        }
+    }
+
+    pub fn position(&self, text: &str, offset: usize) -> Position {
+        let offset = offset.min(text.len());
+        let line = self.line_starts.partition_point(|&x| x <= offset) - 1;
+        let start = self.line_starts[line];
+        let line_text = self.line_text(text, line as u32).unwrap_or_default();
+        let column = line_text.get(..offset - start).unwrap_or(line_text);
+        Position::new(line as u32, self.encoding.text_width(column))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The emoji takes two UTF-16 code units, a surrogate pair, and four bytes.
+    const TEXT: &str = "float x;\r\nvec2 😀 = a;\r\n\r\nend";
+
+    #[test]
+    fn surrogate_pairs_take_two_utf16_columns() {
+        assert_eq!(PositionEncoding::Utf16.text_width("a😀b"), 4);
+        assert_eq!(PositionEncoding::Utf8.text_width("a😀b"), 6);
+        assert_eq!(PositionEncoding::Utf16.column_to_byte("a😀b", 3), 5);
+        assert_eq!(PositionEncoding::Utf8.column_to_byte("a😀b", 5), 5);
+    }
+
+    #[test]
+    fn columns_inside_a_surrogate_pair_move_back_to_the_character() {
+        assert_eq!(PositionEncoding::Utf16.column_to_byte("a😀b", 2), 1);
+        assert_eq!(PositionEncoding::Utf8.column_to_byte("a😀b", 3), 1);
+    }
+
+    #[test]
+    fn positions_round_trip_through_offsets() {
+        for encoding in [PositionEncoding::Utf8, PositionEncoding::Utf16] {
+            let index = LineIndex::new(TEXT, encoding);
+            for (offset, _) in TEXT.char_indices().filter(|&(_, x)| x != '\r' && x != '\n') {
+                let position = index.position(TEXT, offset);
+                assert_eq!(index.offset(TEXT, position), offset, "{:?} at {}", encoding, offset);
+            }
+        }
+        let index = LineIndex::new(TEXT, PositionEncoding::Utf16);
+        let after_emoji = TEXT.find(" = a").unwrap();
+        assert_eq!(index.position(TEXT, after_emoji), Position::new(1, 7));
+        let index = LineIndex::new(TEXT, PositionEncoding::Utf8);
+        assert_eq!(index.position(TEXT, after_emoji), Position::new(1, 9));
+    }
+
+    #[test]
+    fn crlf_is_not_part_of_the_line() {
+        let index = LineIndex::new(TEXT, PositionEncoding::Utf16);
+        assert_eq!(index.line_count(), 4);
+        assert_eq!(index.line_text(TEXT, 0), Some("float x;"));
+        assert_eq!(index.line_text(TEXT, 2), Some(""));
+        assert_eq!(index.line_text(TEXT, 3), Some("end"));
+        // Columns past the end of a line stop before its `\r`.
+        assert_eq!(index.offset(TEXT, Position::new(0, 100)), 8);
+        assert_eq!(index.offset(TEXT, Position::new(3, 0)), TEXT.len() - 3);
+        assert_eq!(index.offset(TEXT, Position::new(9, 0)), TEXT.len());
+        assert_eq!(index.position(TEXT, 9), Position::new(0, 8));
+    }
+}
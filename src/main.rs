@@ -1,49 +1,315 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, VecDeque}, fs, io::Write, path::Path, time::{Duration, Instant}};
 
 use gdshader_lsp::{
+    check::{check_project, format_reports, has_errors, ReportFormat},
     completion::{get_completion_items, get_hover_description},
-    lexer::TokenStream,
-    memory::Memory,
+    definition::get_definition,
+    document_symbols::get_document_symbols,
+    formatting::get_formatting_edits,
+    references::{find_declaration_at, get_document_highlights, get_references},
+    rename::{get_rename_edits, prepare_rename, validate_new_name},
+    semantic_tokens::{get_semantic_token_legend, get_semantic_tokens},
+    signature_help::get_signature_help,
+    includes::{normalize_path, IncludeSources},
+    interpreter::evaluate_include_source,
+    lexer::MacroInfo,
+    line_index::PositionEncoding,
+    memory::{Memory, SymbolLocation},
     source_code::send_errors,
+    workspace::{WorkspaceIndex, PROJECT_FILE},
     *
 };
-use lsp_server::{Connection, Message, Request, Response};
+use lsp_server::{Connection, ErrorCode, Message, Request, Response};
 use lsp_types::*;
 use serde_json::Value;
 
 const TEST : bool = false;
+/// How long to wait after an edit before diagnosing the document, so that
+/// analyses are not queued up for every keystroke.
+const DIAGNOSTICS_DELAY: Duration = Duration::from_millis(250);
+
+/// A document that can see a declaration, and the declaration as it refers
+/// to it.
+struct SeeingDocument {
+    uri: Url,
+    target: SymbolLocation,
+    memory: SeenBy,
+}
+
+enum SeenBy {
+    /// An open document, by its key in `Server::memories`.
+    Open(String),
+    /// An include that is not open, evaluated on its own.
+    Closed(Memory),
+}
 
 pub struct Server<'a> {
     memories: HashMap<String, Memory>,
     root_dir: Option<String>,
+    predefined_macros: HashMap<String, MacroInfo>,
+    include_sources: IncludeSources,
+    workspace: WorkspaceIndex,
+    /// How the columns of positions are counted, as agreed with the client
+    /// when it initializes the server.
+    position_encoding: PositionEncoding,
+    /// Whether the client lets us register file watchers.
+    watch_files: bool,
+    /// Documents whose diagnostics are out of date, and when to publish them.
+    pending_diagnostics: HashMap<String, Instant>,
+    /// Messages read ahead while looking for newer edits, handled before any
+    /// other message.
+    queued_messages: VecDeque<Message>,
     connection: &'a Connection
 }
 impl<'a> Server<'a> {
     pub fn open_document(&mut self, params: DidOpenTextDocumentParams) {
-        let mut memory = Memory::new(&params.text_document.text, self.root_dir.clone());
-        let diagnostics = memory.evaluate_new(None).clone();
-        send_errors(&self.connection, &params.text_document.uri, diagnostics);
-        
-        self.memories.insert(params.text_document.uri.to_string(), memory);
+        let uri = params.text_document.uri;
+        let text = params.text_document.text;
+        self.index_document(&uri, &text);
+        if let Some(path) = get_include_path(&uri) {
+            self.include_sources.set_open(&path, &text);
+            self.refresh_dependents(&path);
+        }
+
+        let memory = self.evaluate_document(&uri, &text, Some(params.text_document.version));
+        self.pending_diagnostics.remove(&uri.to_string());
+        self.memories.insert(uri.to_string(), memory);
     } 
 
+    /// Applies the edits and schedules new diagnostics for the document, and
+    /// for the documents that include it. A later edit pushes the schedule
+    /// back, and cancels the analysis of the version it replaces.
     pub fn change_document(&mut self, params: DidChangeTextDocumentParams) {
-        let maybe_memory = self.memories.get_mut(&params.text_document.uri.to_string()); 
+        let uri = params.text_document.uri;
+        let maybe_memory = self.memories.get_mut(&uri.to_string()); 
         if let Some(memory) = maybe_memory {
             memory.root_dir = self.root_dir.clone();
+            memory.version = Some(params.text_document.version);
             for change in params.content_changes {
                 memory.apply_change(change);
             }
+            let deadline = Instant::now() + DIAGNOSTICS_DELAY;
+            self.pending_diagnostics.insert(uri.to_string(), deadline);
+            if let Some(path) = get_include_path(&uri) {
+                self.include_sources.set_open(&path, memory.get_source().get_code());
+                for dependent in self.find_dependents(&path) {
+                    self.pending_diagnostics.insert(dependent, deadline);
+                }
+            }
         }
     }
 
     pub fn save_document(&mut self, params: DidSaveTextDocumentParams) {
-        let maybe_memory = self.memories.get_mut(&params.text_document.uri.to_string()); 
-        if let Some(old_memory) = maybe_memory {
-            *old_memory = Memory::new(&old_memory.get_source().get_code(), self.root_dir.clone());
-            old_memory.root_dir = self.root_dir.clone();
-            let diagnostics = old_memory.evaluate_new(None).clone();
-            send_errors(&self.connection, &params.text_document.uri, diagnostics);
+        let uri = params.text_document.uri;
+        if self.memories.contains_key(&uri.to_string()) {
+            self.rediagnose(&uri.to_string());
+            let code = self.memories[&uri.to_string()].get_source().get_code().clone();
+            self.index_document(&uri, &code);
+        }
+        if let Some(path) = get_include_path(&uri) {
+            self.include_sources.invalidate(&path);
+        }
+    }
+
+    /// Forgets a closed document. What is on disk is indexed again, since
+    /// the buffer may have been closed without saving.
+    pub fn close_document(&mut self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        self.memories.remove(&uri.to_string());
+        self.pending_diagnostics.remove(&uri.to_string());
+        send_errors(&self.connection, &uri, vec![], None);
+        if let Ok(path) = uri.to_file_path() {
+            self.reindex_from_disk(&path.to_string_lossy());
+        }
+        if let Some(path) = get_include_path(&uri) {
+            self.include_sources.close(&path);
+            self.refresh_dependents(&path);
+        }
+    }
+
+    pub fn change_watched_files(&mut self, params: DidChangeWatchedFilesParams) {
+        for change in params.changes {
+            let path = if let Ok(x) = change.uri.to_file_path() { x } else { continue };
+            let path = path.to_string_lossy().to_string();
+            self.include_sources.invalidate(&path);
+            if !self.memories.contains_key(&change.uri.to_string()) {
+                self.reindex_from_disk(&path);
+            }
+            if get_include_path(&change.uri).is_some() {
+                self.refresh_dependents(&path);
+            }
+        }
+    }
+
+    /// Asks the client to tell us when shaders, or the project settings that
+    /// declare global uniforms, change on disk.
+    pub fn register_file_watchers(&self) {
+        if !self.watch_files {
+            return;
+        }
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![
+                FileSystemWatcher {
+                    glob_pattern: GlobPattern::String("**/*.{gdshader,gdshaderinc}".to_string()),
+                    kind: None
+                },
+                FileSystemWatcher {
+                    glob_pattern: GlobPattern::String(format!("**/{}", PROJECT_FILE)),
+                    kind: None
+                }
+            ]
+        };
+        let params = RegistrationParams {
+            registrations: vec![Registration {
+                id: DID_CHANGE_WATCHED_FILES.to_string(),
+                method: DID_CHANGE_WATCHED_FILES.to_string(),
+                register_options: serde_json::to_value(options).ok()
+            }]
+        };
+        let request = Request::new(
+            REGISTER_CAPABILITY.to_string().into(),
+            REGISTER_CAPABILITY.to_string(),
+            params
+        );
+        _ = self.connection.sender.send(Message::Request(request));
+    }
+
+    /// How long until the next scheduled diagnostics are due, if any are.
+    pub fn time_until_diagnostics(&self) -> Option<Duration> {
+        self.pending_diagnostics
+            .values()
+            .min()
+            .map(|x| x.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn publish_pending_diagnostics(&mut self) {
+        let now = Instant::now();
+        let due: Vec<_> = self.pending_diagnostics
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        for uri in due {
+            self.pending_diagnostics.remove(&uri);
+            // The newer edit schedules the document again, so this version
+            // is neither analysed nor published once it has been replaced.
+            if self.has_newer_change(&uri) {
+                continue;
+            }
+            let diagnostics = match self.memories.get_mut(&uri) {
+                Some(memory) => memory.evaluate_new(None).clone(),
+                None => continue
+            };
+            if self.has_newer_change(&uri) {
+                continue;
+            }
+            if let (Some(memory), Ok(url)) = (self.memories.get(&uri), Url::parse(&uri)) {
+                send_errors(&self.connection, &url, diagnostics, memory.version);
+            }
+        }
+    }
+
+    /// Whether an edit of `uri` is waiting to be handled. The messages read to
+    /// find out are queued, so that they are still handled in order.
+    fn has_newer_change(&mut self, uri: &str) -> bool {
+        while let Ok(message) = self.connection.receiver.try_recv() {
+            self.queued_messages.push_back(message);
+        }
+        self.queued_messages.iter().any(|x| match x {
+            Message::Notification(notif) if notif.method == DID_CHANGE => notif.params
+                .pointer("/textDocument/uri")
+                .and_then(|x| x.as_str())
+                .and_then(|x| Url::parse(x).ok())
+                .is_some_and(|x| x.as_str() == uri),
+            _ => false
+        })
+    }
+
+    /// The next message to handle, waiting for it until `timeout` if one is
+    /// given. `Err(true)` means the wait timed out.
+    fn next_message(&mut self, timeout: Option<Duration>) -> Result<Message, bool> {
+        if let Some(message) = self.queued_messages.pop_front() {
+            return Ok(message);
+        }
+        match timeout {
+            Some(timeout) => self.connection.receiver.recv_timeout(timeout).map_err(|x| x.is_timeout()),
+            None => self.connection.receiver.recv().map_err(|_| false)
+        }
+    }
+
+    /// Builds a fresh memory for a document and publishes its diagnostics.
+    fn evaluate_document(&self, uri: &Url, code: &str, version: Option<i32>) -> Memory {
+        let mut memory = Memory::new(code, self.root_dir.clone(), self.position_encoding);
+        memory.predefined_macros = self.predefined_macros.clone();
+        memory.include_sources = self.include_sources.clone();
+        memory.version = version;
+        memory.uri = Some(uri.clone());
+        let diagnostics = memory.evaluate_new(None).clone();
+        send_errors(&self.connection, uri, diagnostics, version);
+        memory
+    }
+
+    /// Re-analyses an open document from its current text, reusing what the
+    /// last analysis found where it still holds.
+    fn rediagnose(&mut self, uri: &str) {
+        let (memory, url) = match (self.memories.get_mut(uri), Url::parse(uri)) {
+            (Some(memory), Ok(url)) => (memory, url),
+            _ => return
+        };
+        let diagnostics = memory.evaluate_new(None).clone();
+        send_errors(&self.connection, &url, diagnostics, memory.version);
+    }
+
+    /// Switches to the macros predefined by new settings, and re-diagnoses
+    /// every open document with them. Settings without `defines` change
+    /// nothing, since they may only concern another extension.
+    pub fn change_configuration(&mut self, params: DidChangeConfigurationParams) {
+        let defines = params.settings
+            .pointer("/defines")
+            .or_else(|| params.settings.pointer("/gdshader-lsp/defines"));
+        let defines = if let Some(defines) = defines { defines } else { return };
+        self.predefined_macros = get_predefined_macros(defines);
+        let uris: Vec<String> = self.memories.keys().cloned().collect();
+        for uri in uris {
+            if let Some(memory) = self.memories.get_mut(&uri) {
+                memory.predefined_macros = self.predefined_macros.clone();
+            }
+            self.pending_diagnostics.remove(&uri);
+            self.rediagnose(&uri);
+        }
+    }
+
+    /// The open documents that include `path`.
+    fn find_dependents(&self, path: &str) -> Vec<String> {
+        let path = normalize_path(path);
+        self.memories
+            .iter()
+            .filter(|(_, memory)| memory.include_paths.iter().any(|x| normalize_path(x) == path))
+            .map(|(uri, _)| uri.clone())
+            .collect()
+    }
+
+    /// Re-diagnoses every open document that includes `path`.
+    fn refresh_dependents(&mut self, path: &str) {
+        for uri in self.find_dependents(path) {
+            self.pending_diagnostics.remove(&uri);
+            self.rediagnose(&uri);
+        }
+    }
+
+    fn reindex_from_disk(&mut self, path: &str) {
+        let is_shader = path.ends_with(".gdshader") || path.ends_with(".gdshaderinc");
+        let is_project_file = Path::new(path).file_name().is_some_and(|x| x == PROJECT_FILE);
+        match fs::read_to_string(path) {
+            Ok(code) if is_shader => self.workspace.index_file(path, &code),
+            Ok(code) if is_project_file => self.workspace.index_project_settings(path, &code),
+            _ => self.workspace.remove_file(path)
+        }
+    }
+
+    pub fn index_document(&mut self, uri: &Url, code: &str) {
+        if let Ok(path) = uri.to_file_path() {
+            self.workspace.index_file(&path.to_string_lossy(), code);
         }
     }
 
@@ -57,21 +323,123 @@ impl<'a> Server<'a> {
             .to_string();
         self.memories.get_mut(&uri)
     }
+
+    /// The documents that can see `declaration`, found in `uri`, each with
+    /// the declaration as that document refers to it. A declaration made in
+    /// an include, or in an open include itself, is seen by every open
+    /// document; any other only by its own. When the include is not open,
+    /// its text is evaluated on its own and returned as the last document.
+    /// Open documents other than `uri` are analysed again so that their
+    /// references are current.
+    fn documents_seeing(&mut self, uri: &Url, declaration: &SymbolLocation) -> Vec<SeeingDocument> {
+        let declaration_file = declaration.include_path.clone().or_else(|| get_include_path(uri));
+
+        let mut documents = vec![];
+        let mut declaration_file_is_open = false;
+        for (key, memory) in self.memories.iter_mut() {
+            let document = if let Ok(x) = Url::parse(key) { x } else { continue };
+            let target = if let Some(file) = &declaration_file {
+                let is_declaration_file = document.to_file_path()
+                    .is_ok_and(|x| x.to_string_lossy() == *file);
+                declaration_file_is_open |= is_declaration_file;
+                SymbolLocation {
+                    include_path: if is_declaration_file { None } else { Some(file.clone()) },
+                    range: declaration.range
+                }
+            } else if document == *uri {
+                declaration.clone()
+            } else {
+                continue;
+            };
+            if document != *uri {
+                memory.evaluate_at_cursor(Position::default());
+            }
+            documents.push(SeeingDocument { uri: document, target, memory: SeenBy::Open(key.clone()) });
+        }
+
+        if let Some(file) = declaration_file.filter(|_| !declaration_file_is_open) {
+            if let (Some(text), Ok(include_uri)) = (self.include_sources.read(&file), Url::from_file_path(&file)) {
+                let include_memory = evaluate_include_source(&text, self.position_encoding, HashMap::new());
+                let target = SymbolLocation { include_path: None, range: declaration.range };
+                documents.push(SeeingDocument { uri: include_uri, target, memory: SeenBy::Closed(include_memory) });
+            }
+        }
+        documents
+    }
+
+    /// Finds the uses of `declaration` in every document that can see it.
+    pub fn find_references(
+        &mut self,
+        uri: &Url,
+        declaration: &SymbolLocation,
+        include_declaration: bool
+    ) -> Vec<Location> {
+        let mut locations = vec![];
+        for document in self.documents_seeing(uri, declaration) {
+            let memory = match &document.memory {
+                SeenBy::Closed(x) => x,
+                SeenBy::Open(key) => if let Some(x) = self.memories.get(key) { x } else { continue }
+            };
+            locations.extend(
+                get_references(memory, &document.target, include_declaration)
+                    .map(|x| Location::new(document.uri.clone(), x.range))
+            );
+        }
+        locations
+    }
+
+    /// Renames `declaration` in every document that can see it, including
+    /// the include file it is declared in, read from disk when it is not open.
+    pub fn rename_symbol(
+        &mut self,
+        uri: &Url,
+        declaration: &SymbolLocation,
+        new_name: &str
+    ) -> WorkspaceEdit {
+        let mut changes = HashMap::new();
+        for document in self.documents_seeing(uri, declaration) {
+            let memory = match &document.memory {
+                SeenBy::Closed(x) => x,
+                SeenBy::Open(key) => if let Some(x) = self.memories.get(key) { x } else { continue }
+            };
+            let edits = get_rename_edits(memory, &document.target, new_name);
+            if !edits.is_empty() {
+                changes.insert(document.uri, edits);
+            }
+        }
+
+        WorkspaceEdit { changes: Some(changes), ..Default::default() }
+    }
 }
 
 fn main() {
     if TEST { test(); return; }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|x| x == "check") {
+        std::process::exit(run_check(&args[1..]));
+    }
 
     let (connection, io_threads) = Connection::stdio();
 
+    let include_sources = IncludeSources::new();
     let mut server = Server {
         memories: HashMap::new(),
         root_dir: Some("donkey".to_string()),
+        predefined_macros: HashMap::new(),
+        include_sources: include_sources.clone(),
+        workspace: WorkspaceIndex::new(include_sources),
+        position_encoding: PositionEncoding::default(),
+        watch_files: false,
+        pending_diagnostics: HashMap::new(),
+        queued_messages: VecDeque::new(),
         connection: &connection
     };
 
     loop {
-        match connection.receiver.recv() {
+        // Waits for the next message, or until diagnostics are due.
+        let timeout = server.time_until_diagnostics();
+        let received = server.next_message(timeout);
+        match received {
             Ok(msg) => match msg {
                 Message::Notification(notif) => {
                     if notif.method == DID_OPEN {
@@ -94,6 +462,30 @@ fn main() {
                         if let Ok(param) = did_save_params {
                             server.save_document(param)
                         }
+
+                    } else if notif.method == DID_CLOSE {
+                        let did_close_params: Result<DidCloseTextDocumentParams, _> =
+                            notif.extract(DID_CLOSE);
+                        if let Ok(params) = did_close_params {
+                            server.close_document(params);
+                        }
+
+                    } else if notif.method == DID_CHANGE_WATCHED_FILES {
+                        let did_change_watched_files_params: Result<DidChangeWatchedFilesParams, _> =
+                            notif.extract(DID_CHANGE_WATCHED_FILES);
+                        if let Ok(params) = did_change_watched_files_params {
+                            server.change_watched_files(params);
+                        }
+
+                    } else if notif.method == DID_CHANGE_CONFIGURATION {
+                        let did_change_configuration_params: Result<DidChangeConfigurationParams, _> =
+                            notif.extract(DID_CHANGE_CONFIGURATION);
+                        if let Ok(params) = did_change_configuration_params {
+                            server.change_configuration(params);
+                        }
+
+                    } else if notif.method == INITIALIZED {
+                        server.register_file_watchers();
                     }
                 },
                 Message::Request(req) => {
@@ -113,7 +505,8 @@ fn main() {
                 },
                 Message::Response(x) => { }
             }
-            Err(_) => {
+            Err(true) => server.publish_pending_diagnostics(),
+            Err(false) => {
                 io_threads.join().unwrap();
                 return
             }
@@ -138,12 +531,29 @@ pub fn handle_request(
                      .trim_matches(|x| x == '"')
                      .to_string()
                 );
+            server.predefined_macros = req.params.get("initializationOptions")
+                .and_then(|x| x.get("defines"))
+                .map_or(HashMap::new(), get_predefined_macros);
+            server.watch_files = req.params.pointer("/capabilities/workspace/didChangeWatchedFiles/dynamicRegistration")
+                .and_then(|x| x.as_bool())
+                .unwrap_or(false);
+            let offered_encodings: Vec<PositionEncodingKind> = req.params
+                .pointer("/capabilities/general/positionEncodings")
+                .and_then(|x| serde_json::from_value(x.clone()).ok())
+                .unwrap_or_default();
+            server.position_encoding = PositionEncoding::negotiate(&offered_encodings);
+            if let Some(root_dir) = &server.root_dir {
+                server.workspace.index_root(root_dir, server.position_encoding);
+            }
             for x in server.memories.iter_mut() {
                 x.1.root_dir = server.root_dir.clone();
+                x.1.predefined_macros = server.predefined_macros.clone();
+                x.1.include_sources = server.include_sources.clone();
             }
             Ok(Response::new_ok(req.id, serde_json::to_value(
                 InitializeResult {
                     capabilities: ServerCapabilities {
+                        position_encoding: Some(server.position_encoding.kind()),
                         text_document_sync: Some(
                             TextDocumentSyncCapability::Kind(
                                 TextDocumentSyncKind::INCREMENTAL
@@ -165,6 +575,31 @@ pub fn handle_request(
                         hover_provider: Some(
                             lsp_types::HoverProviderCapability::Simple(true)
                         ),
+                        signature_help_provider: Some(SignatureHelpOptions {
+                            trigger_characters: Some(vec!["(".to_string(), ",".to_string()]),
+                            ..Default::default()
+                        }),
+                        definition_provider: Some(OneOf::Left(true)),
+                        references_provider: Some(OneOf::Left(true)),
+                        document_highlight_provider: Some(OneOf::Left(true)),
+                        rename_provider: Some(OneOf::Right(RenameOptions {
+                            prepare_provider: Some(true),
+                            work_done_progress_options: Default::default()
+                        })),
+                        document_symbol_provider: Some(OneOf::Left(true)),
+                        workspace_symbol_provider: Some(OneOf::Left(true)),
+                        semantic_tokens_provider: Some(
+                            SemanticTokensServerCapabilities::SemanticTokensOptions(
+                                SemanticTokensOptions {
+                                    legend: get_semantic_token_legend(),
+                                    full: Some(SemanticTokensFullOptions::Bool(true)),
+                                    range: Some(true),
+                                    ..Default::default()
+                                }
+                            )
+                        ),
+                        document_formatting_provider: Some(OneOf::Left(true)),
+                        document_range_formatting_provider: Some(OneOf::Left(true)),
                         ..Default::default()
                     },
                 ..Default::default()
@@ -175,9 +610,7 @@ pub fn handle_request(
             let maybe_memory = server.get_memory_from_uri(&req);
 
             if let Some(memory) = maybe_memory {
-                let mut stream = TokenStream::new(&memory.get_source().get_code(), Some(cursor));
-                let tree = parse_tokens(&mut stream);
-                memory.evaluate(tree);
+                let stream = memory.evaluate_at_cursor(cursor);
                 let names = get_completion_items(memory, cursor, &stream.cursor_element);
 
                 Ok(Response::new_ok(
@@ -193,12 +626,10 @@ pub fn handle_request(
             let maybe_memory = server.get_memory_from_uri(&req);
 
             if let Some(memory) = maybe_memory {
-                let text = {
-                    let stream = TokenStream::new(&memory.get_source().get_code(), Some(cursor));
-                    let text = stream.find_cursor_text().map_or("".to_string(), |x| x);
-                    text
-                };
-                if let Some(contents) = get_hover_description(memory, cursor, &text){
+                let stream = memory.evaluate_at_cursor(cursor);
+                let text = stream.find_cursor_text().map_or("".to_string(), |x| x);
+                let expansion = stream.find_cursor_expansion();
+                if let Some(contents) = get_hover_description(memory, cursor, &text, expansion) {
                     Ok(Response::new_ok(
                         req.id,
                         serde_json::to_value(lsp_types::Hover{
@@ -209,7 +640,172 @@ pub fn handle_request(
                 } else { Err(ResponseError::DoNothing) }
             } else {  Err(ResponseError::DoNothing) }
         },
-        DEFINITION => Err(ResponseError::DoNothing),
+        SIGNATURE_HELP => {
+            let cursor = get_cursor(&req.params);
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            if let Some(memory) = maybe_memory {
+                let stream = memory.evaluate_at_cursor(cursor);
+                if let Some(help) = get_signature_help(memory, &stream, cursor) {
+                    Ok(Response::new_ok(req.id, serde_json::to_value(help).unwrap()))
+                } else { Err(ResponseError::DoNothing) }
+            } else { Err(ResponseError::DoNothing) }
+        },
+        DEFINITION => {
+            let cursor = get_cursor(&req.params);
+            let uri = get_uri(&req.params);
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            if let (Some(memory), Some(uri)) = (maybe_memory, uri) {
+                let stream = memory.evaluate_at_cursor(cursor);
+                let text = stream.find_cursor_text().map_or("".to_string(), |x| x);
+                let location = get_definition(memory, cursor, &stream.cursor_element, &text)
+                    .and_then(|definition| {
+                        let uri = match definition.include_path {
+                            Some(path) => Url::from_file_path(path).ok()?,
+                            None => uri
+                        };
+                        Some(Location::new(uri, definition.range))
+                    });
+                if let Some(location) = location {
+                    Ok(Response::new_ok(
+                        req.id,
+                        serde_json::to_value(GotoDefinitionResponse::Scalar(location)).unwrap()
+                    ))
+                } else { Err(ResponseError::DoNothing) }
+            } else { Err(ResponseError::DoNothing) }
+        },
+        REFERENCES => {
+            let cursor = get_cursor(&req.params);
+            let include_declaration = req.params.get("context")
+                .and_then(|x| x.get("includeDeclaration"))
+                .and_then(|x| x.as_bool())
+                .unwrap_or(true);
+            let uri = get_uri(&req.params);
+            let declaration = server.get_memory_from_uri(&req).and_then(|memory| {
+                memory.evaluate_at_cursor(cursor);
+                find_declaration_at(memory, cursor)
+            });
+
+            if let (Some(declaration), Some(uri)) = (declaration, uri) {
+                let locations = server.find_references(&uri, &declaration, include_declaration);
+                Ok(Response::new_ok(req.id, serde_json::to_value(locations).unwrap()))
+            } else { Err(ResponseError::DoNothing) }
+        },
+        DOCUMENT_HIGHLIGHT => {
+            let cursor = get_cursor(&req.params);
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            if let Some(memory) = maybe_memory {
+                memory.evaluate_at_cursor(cursor);
+                let highlights = get_document_highlights(memory, cursor);
+                Ok(Response::new_ok(req.id, serde_json::to_value(highlights).unwrap()))
+            } else { Err(ResponseError::DoNothing) }
+        },
+        PREPARE_RENAME => {
+            let cursor = get_cursor(&req.params);
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            if let Some(memory) = maybe_memory {
+                let stream = memory.evaluate_at_cursor(cursor);
+                let text = stream.find_cursor_text().map_or("".to_string(), |x| x);
+                match prepare_rename(memory, cursor, &text) {
+                    Ok(range) => Ok(Response::new_ok(
+                        req.id,
+                        serde_json::to_value(range.map(PrepareRenameResponse::Range)).unwrap()
+                    )),
+                    Err(message) =>
+                        Ok(Response::new_err(req.id, ErrorCode::RequestFailed as i32, message))
+                }
+            } else { Err(ResponseError::DoNothing) }
+        },
+        RENAME => {
+            let cursor = get_cursor(&req.params);
+            let uri = get_uri(&req.params);
+            let new_name = req.params.get("newName")
+                .and_then(|x| x.as_str())
+                .map_or("".to_string(), |x| x.to_string());
+            let declaration = server.get_memory_from_uri(&req).map(|memory| {
+                let stream = memory.evaluate_at_cursor(cursor);
+                let text = stream.find_cursor_text().map_or("".to_string(), |x| x);
+                prepare_rename(memory, cursor, &text)?;
+                let declaration = find_declaration_at(memory, cursor)
+                    .ok_or("There is nothing to rename here.".to_string())?;
+                validate_new_name(memory, &declaration, &new_name)?;
+                Ok(declaration)
+            });
+
+            match (declaration, uri) {
+                (Some(Ok(declaration)), Some(uri)) => {
+                    let edit = server.rename_symbol(&uri, &declaration, &new_name);
+                    Ok(Response::new_ok(req.id, serde_json::to_value(edit).unwrap()))
+                }
+                (Some(Err(message)), _) =>
+                    Ok(Response::new_err(req.id, ErrorCode::RequestFailed as i32, message)),
+                _ => Err(ResponseError::DoNothing)
+            }
+        },
+        SEMANTIC_TOKENS_FULL => {
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            if let Some(memory) = maybe_memory {
+                let stream = memory.analyze(None);
+                let tokens = SemanticTokensResult::Tokens(SemanticTokens {
+                    result_id: None,
+                    data: get_semantic_tokens(memory, &stream.inactive_ranges, None)
+                });
+                Ok(Response::new_ok(req.id, serde_json::to_value(tokens).unwrap()))
+            } else { Err(ResponseError::DoNothing) }
+        },
+        SEMANTIC_TOKENS_RANGE => {
+            let range = req.params.get("range").and_then(|x| serde_json::from_value(x.clone()).ok());
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            match (maybe_memory, range) {
+                (Some(memory), Some(range)) => {
+                    let stream = memory.analyze(None);
+                    let tokens = SemanticTokensRangeResult::Tokens(SemanticTokens {
+                        result_id: None,
+                        data: get_semantic_tokens(memory, &stream.inactive_ranges, Some(range))
+                    });
+                    Ok(Response::new_ok(req.id, serde_json::to_value(tokens).unwrap()))
+                }
+                _ => Err(ResponseError::DoNothing)
+            }
+        },
+        FORMATTING | RANGE_FORMATTING => {
+            let options: Option<FormattingOptions> = req.params
+                .get("options")
+                .and_then(|x| serde_json::from_value(x.clone()).ok());
+            let range = req.params.get("range").and_then(|x| serde_json::from_value(x.clone()).ok());
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            match (maybe_memory, options) {
+                (Some(memory), Some(options)) => {
+                    match get_formatting_edits(memory.get_source().get_code(), &options, range, memory.get_source().encoding()) {
+                        Ok(edits) => Ok(Response::new_ok(req.id, serde_json::to_value(edits).unwrap())),
+                        Err(message) =>
+                            Ok(Response::new_err(req.id, ErrorCode::RequestFailed as i32, message))
+                    }
+                }
+                _ => Err(ResponseError::DoNothing)
+            }
+        },
+        DOCUMENT_SYMBOL => {
+            let maybe_memory = server.get_memory_from_uri(&req);
+
+            if let Some(memory) = maybe_memory {
+                let symbols = DocumentSymbolResponse::Nested(get_document_symbols(memory));
+                Ok(Response::new_ok(req.id, serde_json::to_value(symbols).unwrap()))
+            } else { Err(ResponseError::DoNothing) }
+        },
+        WORKSPACE_SYMBOL => {
+            let query = req.params.get("query")
+                .and_then(|x| x.as_str())
+                .map_or("".to_string(), |x| x.to_string());
+            let symbols = server.workspace.search(&query);
+            Ok(Response::new_ok(req.id, serde_json::to_value(symbols).unwrap()))
+        },
         EXIT => Err(ResponseError::Shutdown),
         SHUTDOWN => Err(ResponseError::Shutdown),
         _ =>Err(ResponseError::DoNothing) 
@@ -231,6 +827,135 @@ pub fn get_cursor(val: &Value) -> Position {
     Position{character: char, line}
 }
 
+/// The path of the document if it is a shader include.
+pub fn get_include_path(uri: &Url) -> Option<String> {
+    uri.to_file_path()
+        .ok()
+        .filter(|x| x.extension().is_some_and(|x| x == "gdshaderinc"))
+        .map(|x| x.to_string_lossy().to_string())
+}
+
+pub fn get_uri(val: &Value) -> Option<Url> {
+    let uri_json = val.get("textDocument")?.get("uri")?;
+    serde_json::from_value(uri_json.clone()).ok()
+}
+
+/// Reads the `defines` initialization option, given either as an object of
+/// names to values or as a list of `NAME` or `NAME=VALUE` strings.
+pub fn get_predefined_macros(val: &Value) -> HashMap<String, MacroInfo> {
+    let mut macros = HashMap::new();
+    if let Some(defines) = val.as_object() {
+        for (name, value) in defines {
+            let value = match value {
+                Value::String(x) => x.clone(),
+                Value::Null => "".to_string(),
+                x => x.to_string()
+            };
+            macros.insert(name.clone(), MacroInfo::predefined(&value));
+        }
+    } else if let Some(defines) = val.as_array() {
+        for define in defines.iter().filter_map(|x| x.as_str()) {
+            let (name, value) = define.split_once('=').unwrap_or((define, ""));
+            macros.insert(name.trim().to_string(), MacroInfo::predefined(value));
+        }
+    }
+    macros
+}
+
+const CHECK_USAGE: &str = "\
+usage: gdshader-lsp check [PROJECT_DIR] [--format text|json|sarif] [--define NAME[=VALUE]]...
+
+Reports the diagnostics of every shader under PROJECT_DIR, which defaults to
+the current directory, and exits with status 1 if any of them is an error.";
+
+/// Runs `gdshader-lsp check`, and returns the exit status: 0 when no shader
+/// has errors, 1 when some do, and 2 when the arguments are wrong.
+fn run_check(args: &[String]) -> i32 {
+    let mut root_dir = None;
+    let mut format = ReportFormat::Text;
+    let mut defines = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None)
+        };
+        match flag {
+            "--format" | "--define" => {
+                let value = match inline_value.or_else(|| args.next().cloned()) {
+                    Some(x) => x,
+                    None => { eprintln!("{} needs a value.\n\n{}", flag, CHECK_USAGE); return 2 }
+                };
+                if flag == "--define" {
+                    defines.push(Value::String(value));
+                } else if let Some(x) = ReportFormat::parse(&value) {
+                    format = x;
+                } else {
+                    eprintln!("Unknown format '{}'.\n\n{}", value, CHECK_USAGE);
+                    return 2;
+                }
+            }
+            "-h" | "--help" => { println!("{}", CHECK_USAGE); return 0 }
+            _ if !arg.starts_with('-') && root_dir.is_none() => root_dir = Some(arg.clone()),
+            _ => { eprintln!("Unexpected argument '{}'.\n\n{}", arg, CHECK_USAGE); return 2 }
+        }
+    }
+
+    let root_dir = root_dir.unwrap_or_else(|| ".".to_string());
+    if !fs::metadata(&root_dir).is_ok_and(|x| x.is_dir()) {
+        eprintln!("'{}' is not a directory.", root_dir);
+        return 2;
+    }
+    // The flags take the same `NAME=VALUE` strings as the `defines` option.
+    let predefined_macros = get_predefined_macros(&Value::Array(defines));
+    let reports = check_project(&root_dir, &predefined_macros);
+    let mut output = format_reports(&reports, format);
+    if !output.ends_with('\n') {
+        output.push('\n');
+    }
+    // A closed pipe, as when piping into `head`, is not worth a panic.
+    let _ = std::io::stdout().write_all(output.as_bytes());
+    if has_errors(&reports) { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn check_exits_with_the_status_of_the_project() {
+        let root = std::env::temp_dir().join(format!("gdshader-lsp-exit-{}", std::process::id()));
+        _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let shader = root.join("a.gdshader");
+        let root = root.to_string_lossy().to_string();
+
+        fs::write(&shader, "shader_type spatial;\n").unwrap();
+        assert_eq!(run_check(&args(&[&root])), 0);
+        fs::write(&shader, "shader_type spatial;\nvoid vertex() { x = 1; }\n").unwrap();
+        assert_eq!(run_check(&args(&[&root, "--format", "json"])), 1);
+        // A macro can switch off the code with the error.
+        fs::write(&shader, "shader_type spatial;\n#ifndef OFF\nvoid vertex() { x = 1; }\n#endif\n").unwrap();
+        assert_eq!(run_check(&args(&[&root, "--define=OFF"])), 0);
+        _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn check_exits_with_2_on_wrong_arguments() {
+        assert_eq!(run_check(&args(&["--format", "xml"])), 2);
+        assert_eq!(run_check(&args(&["--format"])), 2);
+        assert_eq!(run_check(&args(&["--verbose"])), 2);
+        assert_eq!(run_check(&args(&["a", "b"])), 2);
+        assert_eq!(run_check(&args(&["/nonexistent/gdshader-lsp"])), 2);
+        assert_eq!(run_check(&args(&["--help"])), 0);
+    }
+}
+
 fn test() {
 }
 
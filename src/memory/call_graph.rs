@@ -0,0 +1,206 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use lsp_types::{Position, Range};
+
+use crate::{lexer::{ExtraRange, Token}, nodes::{ExpressionNode, FunctionNode}};
+
+use super::{range_key, stages::collect_expressions, Memory, ReferenceKind};
+
+/// A function declared in the document.
+pub struct GraphFunction {
+    pub name: String,
+    pub identifier: Range,
+    pub body: Range,
+}
+
+/// A call from one function of the document to another.
+pub struct Call {
+    pub caller: usize,
+    pub callee: usize,
+    pub range: Range,
+}
+
+/// Which functions of the document call which. It is found from the
+/// references that evaluating the calls recorded, so it needs no state of its
+/// own to survive incremental analysis. Calls to functions declared further
+/// down record no reference, so those are found from the syntax tree.
+pub struct CallGraph {
+    pub functions: Vec<GraphFunction>,
+    pub calls: Vec<Call>,
+}
+
+impl CallGraph {
+    pub fn new(memory: &Memory, nodes: &[&FunctionNode]) -> Self {
+        let functions: Vec<GraphFunction> = nodes
+            .iter()
+            .map(|x| GraphFunction {
+                name: memory.get_token_text(x.identifier),
+                identifier: x.identifier.range,
+                body: x.block.range
+            })
+            .collect();
+        let by_identifier: HashMap<[u32; 4], usize> = functions
+            .iter()
+            .enumerate()
+            .map(|(idx, x)| (range_key(x.identifier), idx))
+            .collect();
+
+        let mut graph = Self { functions, calls: vec![] };
+        for reference in &memory.references {
+            if reference.kind != ReferenceKind::Read || reference.declaration.include_path.is_some() {
+                continue;
+            }
+            let callee = if let Some(&x) = by_identifier.get(&range_key(reference.declaration.range)) {
+                x
+            } else {
+                continue
+            };
+            if let Some(caller) = graph.function_at(reference.range.start) {
+                graph.calls.push(Call { caller, callee, range: reference.range });
+            }
+        }
+
+        let resolved: HashSet<[u32; 4]> = graph.calls.iter().map(|x| range_key(x.range)).collect();
+        for (caller, node) in nodes.iter().enumerate() {
+            let mut expressions = vec![];
+            for statement in &node.block.statements {
+                collect_expressions(statement, &mut expressions);
+            }
+            let mut identifiers = vec![];
+            for expression in expressions {
+                collect_calls(expression, &mut identifiers);
+            }
+            for identifier in identifiers.into_iter().filter(|x| !resolved.contains(&range_key(x.range))) {
+                if let Some(callee) = graph.find(&memory.get_token_text(identifier)) {
+                    graph.calls.push(Call { caller, callee, range: identifier.range });
+                }
+            }
+        }
+        graph
+    }
+
+    /// The function whose body holds `position`.
+    pub fn function_at(&self, position: Position) -> Option<usize> {
+        self.functions.iter().position(|x| x.body.contains_position(position))
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.functions.iter().position(|x| x.name == name)
+    }
+
+    /// The functions among `stages` that reach `function` through any number
+    /// of calls. A stage reaches itself.
+    pub fn reaching_stages(&self, function: usize, stages: &[&str]) -> Vec<usize> {
+        let mut reached = vec![false; self.functions.len()];
+        let mut pending = vec![function];
+        reached[function] = true;
+        while let Some(current) = pending.pop() {
+            for call in self.calls.iter().filter(|x| x.callee == current) {
+                if !reached[call.caller] {
+                    reached[call.caller] = true;
+                    pending.push(call.caller);
+                }
+            }
+        }
+        (0..self.functions.len())
+            .filter(|&x| reached[x] && stages.contains(&self.functions[x].name.as_str()))
+            .collect()
+    }
+
+    /// The shortest chain of functions through which `from` calls `to`,
+    /// starting with `from` and ending with `to`.
+    pub fn call_chain(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut previous: Vec<Option<usize>> = vec![None; self.functions.len()];
+        let mut visited = vec![false; self.functions.len()];
+        let mut pending = VecDeque::from([from]);
+        visited[from] = true;
+        while let Some(current) = pending.pop_front() {
+            if current == to {
+                let mut chain = vec![to];
+                while let Some(x) = previous[*chain.last().unwrap()] {
+                    chain.push(x);
+                }
+                chain.reverse();
+                return Some(chain);
+            }
+            for call in self.calls.iter().filter(|x| x.caller == current) {
+                if !visited[call.callee] {
+                    visited[call.callee] = true;
+                    previous[call.callee] = Some(current);
+                    pending.push_back(call.callee);
+                }
+            }
+        }
+        None
+    }
+
+    /// The first call that `caller` makes to `callee`.
+    pub fn call_between(&self, caller: usize, callee: usize) -> Option<&Call> {
+        self.calls.iter().find(|x| x.caller == caller && x.callee == callee)
+    }
+
+    /// The cycles of calls, each as a chain that starts and ends with the
+    /// same function. Every function on a cycle is reported through one
+    /// cycle only, the shortest through the first such function declared.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut covered = vec![false; self.functions.len()];
+        let mut cycles = vec![];
+        for function in 0..self.functions.len() {
+            if covered[function] {
+                continue;
+            }
+            let cycle = self.calls
+                .iter()
+                .filter(|x| x.caller == function)
+                .filter_map(|x| self.call_chain(x.callee, function))
+                .min_by_key(|x| x.len());
+            if let Some(chain) = cycle {
+                let mut cycle = vec![function];
+                cycle.extend(chain);
+                for &x in &cycle {
+                    covered[x] = true;
+                }
+                cycles.push(cycle);
+            }
+        }
+        cycles
+    }
+
+    /// Names the functions of a chain, as in `light -> shade -> helper`.
+    pub fn describe_chain(&self, chain: &[usize]) -> String {
+        chain.iter().map(|&x| self.functions[x].name.as_str()).collect::<Vec<_>>().join(" -> ")
+    }
+}
+
+/// The identifiers of every call in `expression`, including nested ones.
+pub(super) fn collect_calls(expression: &ExpressionNode, identifiers: &mut Vec<Token>) {
+    match expression {
+        ExpressionNode::Call(x) => {
+            identifiers.push(x.identifier);
+            x.args.iter().for_each(|x| collect_calls(&x.expression, identifiers));
+        }
+        ExpressionNode::Assignment(x) => {
+            collect_calls(&x.left, identifiers);
+            collect_calls(&x.right, identifiers);
+        }
+        ExpressionNode::Binary(x) => {
+            collect_calls(&x.left, identifiers);
+            collect_calls(&x.right, identifiers);
+        }
+        ExpressionNode::Conditional(x) => {
+            collect_calls(&x.condition, identifiers);
+            collect_calls(&x.action, identifiers);
+            collect_calls(&x.alternate, identifiers);
+        }
+        ExpressionNode::ArrayAccess(x) => {
+            collect_calls(&x.argument, identifiers);
+            collect_calls(&x.index, identifiers);
+        }
+        ExpressionNode::Increment(x) => collect_calls(&x.arg, identifiers),
+        ExpressionNode::Unary(x) => collect_calls(&x.arg, identifiers),
+        ExpressionNode::Paren(x) => collect_calls(x, identifiers),
+        ExpressionNode::MemberAccess(x) => collect_calls(&x.argument, identifiers),
+        ExpressionNode::ArrayLiteral(x) => x.iter().for_each(|x| collect_calls(x, identifiers)),
+        ExpressionNode::Primitive(_) | ExpressionNode::Identifier(_) => {}
+    }
+}
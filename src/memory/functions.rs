@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use lsp_types::Range;
 use crate::lexer::{Token, TokenKind};
 
-use super::TypeInfo;
+use super::{ShaderType, TypeInfo};
 
 
 #[derive(Clone, Debug, PartialEq)]
@@ -35,57 +35,103 @@ impl FunctionParamQualifier {
 pub struct FunctionParam {
     pub name: String,
     pub ty: TypeInfo,
-    pub qualifier: Option<FunctionParamQualifier>
+    pub qualifier: Option<FunctionParamQualifier>,
+    pub is_const: bool
+}
+impl FunctionParam {
+    /// The parameter as it is written in a declaration, like `inout vec3 x`.
+    pub fn label(&self) -> String {
+        let constness = if self.is_const { "const " } else { "" };
+        let qualifier = match self.qualifier {
+            Some(FunctionParamQualifier::In) => "in ",
+            Some(FunctionParamQualifier::Out) => "out ",
+            Some(FunctionParamQualifier::InOut) => "inout ",
+            None => ""
+        };
+        format!("{}{}{} {}", constness, qualifier, self.ty.to_string(), self.name)
+    }
 }
 
+/// The shader types and stages a builtin overload may be called in.
+pub type StageTags = &'static [(ShaderType, &'static str)];
+
+/// Derivatives, and what relies on them, need the neighbouring fragments.
+const FRAGMENT_STAGES: StageTags = &[
+    (ShaderType::Spatial, "fragment"),
+    (ShaderType::Spatial, "light"),
+    (ShaderType::CanvasItem, "fragment"),
+    (ShaderType::CanvasItem, "light"),
+    (ShaderType::Sky, "sky"),
+];
+const SDF_STAGES: StageTags = &[(ShaderType::CanvasItem, "fragment"), (ShaderType::CanvasItem, "light")];
+const PARTICLE_STAGES: StageTags = &[(ShaderType::Particles, "start"), (ShaderType::Particles, "process")];
+
 #[derive(Clone, Debug)]
 pub struct FunctionSignature {
     pub return_type: TypeInfo,
     pub params: Vec<FunctionParam>,
+    /// `None` if the overload may be called anywhere.
+    pub stages: Option<StageTags>,
+}
+impl FunctionSignature {
+    /// Whether the overload may be called in `stage` of `shader_type`. Outside
+    /// of a stage, it may be if any stage of the shader type allows it.
+    pub fn is_available(&self, shader_type: ShaderType, stage: Option<&str>) -> bool {
+        self.stages.is_none_or(|tags| {
+            tags.iter().any(|(ty, x)| *ty == shader_type && stage.is_none_or(|stage| *x == stage))
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FunctionInfo {
     pub signatures: Vec<FunctionSignature>,
     pub range: Option<Range>,
+    pub include_path: Option<String>,
     pub description: Option<String>,
     pub is_const: bool
 }
 
 macro_rules! builtin_function {
-    ($name:ident [$( ($( $($arg_id:ident)* ,)*) -> $r_type:ident,)*] $desc:literal) => {
-        builtin_function!(@main false $name [$( ($( $($arg_id)*,) *) -> $r_type,)*] $desc)
+    ($name:ident [$( ($( $($arg_id:ident)* ,)*) -> $r_type:ident $(in $tags:ident)?,)*] $desc:literal) => {
+        builtin_function!(@main false $name [$( ($( $($arg_id)*,) *) -> $r_type $(in $tags)?,)*] $desc)
     };
-    ($name:ident [$( ($( $($arg_id:ident)* ,)*) -> $r_type:ident,)*] $desc:literal const) => {
-        builtin_function!(@main true $name [$( ($( $($arg_id)*,)*) -> $r_type,)*] $desc)
+    ($name:ident [$( ($( $($arg_id:ident)* ,)*) -> $r_type:ident $(in $tags:ident)?,)*] $desc:literal const) => {
+        builtin_function!(@main true $name [$( ($( $($arg_id)*,)*) -> $r_type $(in $tags)?,)*] $desc)
     };
-    (@main $const:literal $name:ident [$( ($( $($arg_id:ident)*, )*) -> $r_type:ident,)*] $desc:literal) => {
+    (@main $const:literal $name:ident [$( ($( $($arg_id:ident)*, )*) -> $r_type:ident $(in $tags:ident)?,)*] $desc:literal) => {
         (stringify!($name).to_string(), FunctionInfo {
             signatures: vec![
                $(
                 FunctionSignature {
                     return_type: TypeInfo::from_str(stringify!($r_type)),
-                    params: vec![ $( builtin_function!(@param $($arg_id)*), )* ]
+                    params: vec![ $( builtin_function!(@param $($arg_id)*), )* ],
+                    stages: builtin_function!(@stages $($tags)?)
                 },
                 )*
             ],
             range: None,
+            include_path: None,
             description: Some($desc.to_string()),
             is_const: $const 
         }) 
     };
+    (@stages) => { None };
+    (@stages $tags:ident) => { Some($tags) };
     (@param $param_type:ident $param_name:ident) => {
         FunctionParam {
             name: stringify!($param_name).to_string(),
             ty: TypeInfo::from_str(stringify!($param_type)),
-            qualifier: None
+            qualifier: None,
+            is_const: false
         }
     };
     (@param $qualifier:ident $param_type:ident $param_name:ident) => {
         FunctionParam {
             name: stringify!($param_name).to_string(),
             ty: TypeInfo::from_str(stringify!($param_type)),
-            qualifier: Some(FunctionParamQualifier::$qualifier)
+            qualifier: Some(FunctionParamQualifier::$qualifier),
+            is_const: false
         }
     };
 }
@@ -105,7 +151,7 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
             "Cast to an unsigned integer." const
         ),
         builtin_function!(
-            bool [(number x,) -> bool, (boolean x,) -> uint,]
+            bool [(number x,) -> bool, (boolean x,) -> bool,]
             "Cast to an boolean." const
         ),
         builtin_function!(
@@ -149,7 +195,7 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
             "Cast to a vec4." const
         ),
         builtin_function!(
-            uvec2 [(uint x,) -> vec2, (uint x, uint y,) -> uvec2,]
+            uvec2 [(uint x,) -> uvec2, (uint x, uint y,) -> uvec2,]
             "Cast to a uvec2." const
         ),
         builtin_function!(
@@ -189,15 +235,30 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
             "Cast to a vec4." const
         ),
         builtin_function!(
-            mat2 [(vec2 x, vec2 y,) -> mat2,]
+            mat2 [
+                (float x,) -> mat2,
+                (vec2 x, vec2 y,) -> mat2,
+                (mat3 m,) -> mat2,
+                (mat4 m,) -> mat2,
+            ]
             "Cast to a mat2." const
         ),
         builtin_function!(
-            mat3 [(vec3 x, vec3 y, vec3 z,) -> mat3,]
+            mat3 [
+                (float x,) -> mat3,
+                (vec3 x, vec3 y, vec3 z,) -> mat3,
+                (mat2 m,) -> mat3,
+                (mat4 m,) -> mat3,
+            ]
             "Cast to a mat3." const
         ),
         builtin_function!(
-            mat4 [(vec4 x, vec4 y, vec4 z, vec4 w,) -> mat4,]
+            mat4 [
+                (float x,) -> mat4,
+                (vec4 x, vec4 y, vec4 z, vec4 w,) -> mat4,
+                (mat2 m,) -> mat4,
+                (mat3 m,) -> mat4,
+            ]
             "Cast to a mat4." const
         ),
         builtin_function!(
@@ -321,24 +382,45 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
             "Modulo (division remainder)"
         ),
         builtin_function!(
-            modf [(vec_type x, Out float y,) -> vec_type,]
+            modf [(vec_type x, Out vec_type i,) -> vec_type,]
             "Fractional of x, with i as integer part."
         ),
         builtin_function!(
-            min [(vec_type a, vec_type b,) -> vec_type,]
+            min [
+                (vec_type a, vec_type b,) -> vec_type,
+                (vec_type a, float b,) -> vec_type,
+                (ivec_type a, ivec_type b,) -> ivec_type,
+                (ivec_type a, int b,) -> ivec_type,
+                (uvec_type a, uvec_type b,) -> uvec_type,
+                (uvec_type a, uint b,) -> uvec_type,
+            ]
             "Lowest value between a and b."
         ),
         builtin_function!(
-            max [(vec_type a, vec_type b,) -> vec_type,]
+            max [
+                (vec_type a, vec_type b,) -> vec_type,
+                (vec_type a, float b,) -> vec_type,
+                (ivec_type a, ivec_type b,) -> ivec_type,
+                (ivec_type a, int b,) -> ivec_type,
+                (uvec_type a, uvec_type b,) -> uvec_type,
+                (uvec_type a, uint b,) -> uvec_type,
+            ]
             "Highest value between a and b."
         ),
         builtin_function!(
-            clamp [(vec_type x, vec_type min, vec_type max,) -> vec_type,]
+            clamp [
+                (vec_type x, vec_type min, vec_type max,) -> vec_type,
+                (vec_type x, float min, float max,) -> vec_type,
+                (ivec_type x, ivec_type min, ivec_type max,) -> ivec_type,
+                (ivec_type x, int min, int max,) -> ivec_type,
+                (uvec_type x, uvec_type min, uvec_type max,) -> uvec_type,
+                (uvec_type x, uint min, uint max,) -> uvec_type,
+            ]
             "Clamp x between min and max (inclusive)."
         ),
         builtin_function!(
             mix [
-                (float a, float b, float c,) -> float,
+                (vec_type a, vec_type b, vec_type c,) -> vec_type,
                 (vec_type a, vec_type b, float c,) -> vec_type,
                 (vec_type a, vec_type b, bvec_type c,) -> vec_type,
             ]
@@ -349,8 +431,18 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
             "Performs a fused multiply-add operation: (a * b + c) (faster than doing
             it manually)."
         ),
-        // STEP
-        // SMOOTH_STEP
+        builtin_function!(
+            step [(vec_type a, vec_type b,) -> vec_type, (float a, vec_type b,) -> vec_type,]
+            "b < a ? 0.0 : 1.0."
+        ),
+        builtin_function!(
+            smoothstep [
+                (vec_type a, vec_type b, vec_type c,) -> vec_type,
+                (float a, float b, vec_type c,) -> vec_type,
+            ]
+"Hermite interpolate between a and b by c. Returns 0.0 if c is below a,
+1.0 if c is above b, and a smooth curve in between."
+        ),
         builtin_function!(
             isnan [(vec_type x,) -> bvec_type,]
             "Returns true if scalar or vector component is NaN."
@@ -428,27 +520,51 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
             "Inverse matrix."
         ),
         builtin_function!(
-            lessThan [(vec_type x, vec_type y,) -> bvec_type,]
+            lessThan [
+                (vec_type x, vec_type y,) -> bvec_type,
+                (ivec_type x, ivec_type y,) -> bvec_type,
+                (uvec_type x, uvec_type y,) -> bvec_type,
+            ]
             "Bool vector comparison on < int/uint/float vectors."
         ),
         builtin_function!(
-            greaterThan [(vec_type x, vec_type y,) -> bvec_type,]
+            greaterThan [
+                (vec_type x, vec_type y,) -> bvec_type,
+                (ivec_type x, ivec_type y,) -> bvec_type,
+                (uvec_type x, uvec_type y,) -> bvec_type,
+            ]
             "Bool vector comparison on > int/uint/float vectors."
         ),
         builtin_function!(
-            lessThanEqual [(vec_type x, vec_type y,) -> bvec_type,]
+            lessThanEqual [
+                (vec_type x, vec_type y,) -> bvec_type,
+                (ivec_type x, ivec_type y,) -> bvec_type,
+                (uvec_type x, uvec_type y,) -> bvec_type,
+            ]
             "Bool vector comparison on <= int/uint/float vectors."
         ),
         builtin_function!(
-            greaterThanEqual [(vec_type x, vec_type y,) -> bvec_type,]
+            greaterThanEqual [
+                (vec_type x, vec_type y,) -> bvec_type,
+                (ivec_type x, ivec_type y,) -> bvec_type,
+                (uvec_type x, uvec_type y,) -> bvec_type,
+            ]
             "Bool vector comparison on >= int/uint/float vectors."
         ),
         builtin_function!(
-            equal [(vec_type x, vec_type y,) -> bvec_type,]
+            equal [
+                (vec_type x, vec_type y,) -> bvec_type,
+                (ivec_type x, ivec_type y,) -> bvec_type,
+                (uvec_type x, uvec_type y,) -> bvec_type,
+            ]
             "Bool vector comparison on == int/uint/float vectors."
         ),
         builtin_function!(
-            notEqual [(vec_type x, vec_type y,) -> bvec_type,]
+            notEqual [
+                (vec_type x, vec_type y,) -> bvec_type,
+                (ivec_type x, ivec_type y,) -> bvec_type,
+                (uvec_type x, uvec_type y,) -> bvec_type,
+            ]
             "Bool vector comparison on != int/uint/float vectors."
         ),
         builtin_function!(
@@ -468,18 +584,20 @@ pub fn make_builtin_functions() -> HashMap<String, FunctionInfo> {
                 (gsampler2D s, int lod,) -> ivec2,
                 (gsampler2DArray s, int lod,) -> ivec2,
                 (gsampler3D s, int lod,) -> ivec3,
-                (samplerCube s, int lod,) -> ivec2,
-                (samplerCubeArray s, int lod,) -> ivec2,
+                (gsamplerCube s, int lod,) -> ivec2,
+                (gsamplerCubeArray s, int lod,) -> ivec3,
+                (gsamplerExternalOES s, int lod,) -> ivec2,
             ]
 "Get the size of a texture. The LOD defines which mipmap level is used.
 An LOD value of 0 will use the full resolution texture."
         ),
         builtin_function!(
             textureQueryLod [
-                (gsampler2D s, vec2 p,) -> vec2,
-                (gsampler2DArray s, vec2 p,) -> vec3,
-                (gsampler3D s, vec3 p,) -> vec2,
-                (samplerCube s, vec3 p,) -> vec2,
+                (gsampler2D s, vec2 p,) -> vec2 in FRAGMENT_STAGES,
+                (gsampler2DArray s, vec2 p,) -> vec3 in FRAGMENT_STAGES,
+                (gsampler3D s, vec3 p,) -> vec2 in FRAGMENT_STAGES,
+                (gsamplerCube s, vec3 p,) -> vec2 in FRAGMENT_STAGES,
+                (gsamplerCubeArray s, vec3 p,) -> vec2 in FRAGMENT_STAGES,
             ]
 "Compute the level-of-detail that would be used to sample from a texture.
 The x component of the resulted value is the mipmap array that would be accessed.
@@ -491,7 +609,8 @@ The y component is computed level-of-detail relative to the base level
                 (gsampler2D s,) -> int,
                 (gsampler2DArray s,) -> int,
                 (gsampler3D s,) -> int,
-                (samplerCube s,) -> int,
+                (gsamplerCube s,) -> int,
+                (gsamplerCubeArray s,) -> int,
             ]
 "Get the number of accessible mipmap levels of a texture.
 If the texture is unassigned to a sampler, 1 is returned
@@ -502,16 +621,27 @@ If the texture is unassigned to a sampler, 1 is returned
                 (gsampler2D s, vec2 p,) -> gvec4_type,
                 (gsampler2DArray s, vec3 p,) -> gvec4_type,
                 (gsampler3D s, vec3 p,) -> gvec4_type,
-                (samplerCube s, vec3 p,) -> vec4_type,
-                (samplerCubeArray s, vec4 p,) -> vec4_type,
+                (gsamplerCube s, vec3 p,) -> gvec4_type,
+                (gsamplerCubeArray s, vec4 p,) -> gvec4_type,
+                (gsamplerExternalOES s, vec2 p,) -> gvec4_type,
+                (gsampler2D s, vec2 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
+                (gsampler2DArray s, vec3 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
+                (gsampler3D s, vec3 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
+                (gsamplerCube s, vec3 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
+                (gsamplerCubeArray s, vec4 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
             ]
-            "Perform a texture read."
+            "Perform a texture read. The bias is only available where derivatives are."
         ),
         builtin_function!(
             textureProj [
                 (gsampler2D s, vec3 p,) -> gvec4_type,
                 (gsampler2D s, vec4 p,) -> gvec4_type,
                 (gsampler3D s, vec4 p,) -> gvec4_type,
+                (gsamplerExternalOES s, vec3 p,) -> gvec4_type,
+                (gsamplerExternalOES s, vec4 p,) -> gvec4_type,
+                (gsampler2D s, vec3 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
+                (gsampler2D s, vec4 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
+                (gsampler3D s, vec4 p, float bias,) -> gvec4_type in FRAGMENT_STAGES,
             ]
             "Perform a texture read with projection."
         ),
@@ -520,8 +650,8 @@ If the texture is unassigned to a sampler, 1 is returned
                 (gsampler2D s, vec2 p, float lod,) -> gvec4_type,
                 (gsampler2DArray s, vec3 p, float lod,) -> gvec4_type,
                 (gsampler3D s, vec3 p, float lod,) -> gvec4_type,
-                (samplerCube s, vec3 p, float lod,) -> vec4_type,
-                (samplerCubeArray s, vec4 p, float lod,) -> vec4_type,
+                (gsamplerCube s, vec3 p, float lod,) -> gvec4_type,
+                (gsamplerCubeArray s, vec4 p, float lod,) -> gvec4_type,
             ]
             "Perform a texture read at custom mipmap."
         ),
@@ -538,8 +668,8 @@ If the texture is unassigned to a sampler, 1 is returned
                 (gsampler2D s, vec2 p, vec2 dPdx, vec2 dPdy,) -> gvec4_type,
                 (gsampler2DArray s, vec3 p, vec2 dPdx, vec2 dPdy,) -> gvec4_type,
                 (gsampler3D s, vec3 p, vec2 dPdx, vec2 dPdy,) -> gvec4_type,
-                (samplerCube s, vec3 p, vec3 dPdx, vec3 dPdy,) -> vec4_type,
-                (samplerCubeArray s, vec3 p, vec3 dPdx, vec3 dPdy,) -> vec4_type,
+                (gsamplerCube s, vec3 p, vec3 dPdx, vec3 dPdy,) -> gvec4_type,
+                (gsamplerCubeArray s, vec4 p, vec3 dPdx, vec3 dPdy,) -> gvec4_type,
             ]
             "Performs a texture read with explicit gradients."
         ),
@@ -563,63 +693,68 @@ If the texture is unassigned to a sampler, 1 is returned
             textureGather [
                 (gsampler2D s, vec2 p,) -> gvec4_type,
                 (gsampler2DArray s, vec3 p,) -> gvec4_type,
-                (samplerCube s, vec3 p,) -> vec4_type,
+                (gsamplerCube s, vec3 p,) -> gvec4_type,
+                (gsampler2D s, vec2 p, int comps,) -> gvec4_type,
+                (gsampler2DArray s, vec3 p, int comps,) -> gvec4_type,
+                (gsamplerCube s, vec3 p, int comps,) -> gvec4_type,
             ]
-            "Gathers four texels from a texture."
+"Gathers four texels from a texture. Use comps within range of 0..3 to
+define which component (x, y, z, w) is returned. If comps is not provided:
+0 (or x-component) is used."
         ),
         builtin_function!(
-            dFdx [(vec_type p,) -> vec_type,]
+            dFdx [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Derivative in x using local differencing. Internally, can use either
 dFdxCoarse or dFdxFine, but the decision for which to use is made by
 the GPU driver."
         ),
         builtin_function!(
-            dFdxCoarse [(vec_type p,) -> vec_type,]
+            dFdxCoarse [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Calculates derivative with respect to x window coordinate using local
 differencing based on the value of p for the current fragment neighbour(s),
 and will possibly, but not necessarily, include the value for the current
 fragment. This function is not available on gl_compatibility profile."
         ),
         builtin_function!(
-            dFdxFine [(vec_type p,) -> vec_type,]
+            dFdxFine [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Calculates derivative with respect to x window coordinate using local
 differencing based on the value of p for the current fragment and its
 immediate neighbour(s). This function is not available on gl_compatibility
 profile."
         ),
         builtin_function!(
-            dFdy [(vec_type p,) -> vec_type,]
+            dFdy [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Derivative in y using local differencing. Internally, can use either
 dFdyCoarse or dFdyFine, but the decision for which to use is made by
 the GPU driver."
         ),
         builtin_function!(
-            dFdyCoarse [(vec_type p,) -> vec_type,]
+            dFdyCoarse [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Calculates derivative with respect to y window coordinate using local
 differencing based on the value of p for the current fragment neighbour(s),
 and will possibly, but not necessarily, include the value for the current
 fragment. This function is not available on gl_compatibility profile."
         ),
         builtin_function!(
-            dFdyFine [(vec_type p,) -> vec_type,]
+            dFdyFine [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Calculates derivative with respect to y window coordinate using local
 differencing based on the value of p for the current fragment and its
 immediate neighbour(s). This function is not available on gl_compatibility
 profile."
         ),
         builtin_function!(
-            fwidth [(vec_type p,) -> vec_type,]
+            fwidth [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Sum of absolute derivative in x and y. This is the equivalent of using
 abs(dFdx(p)) + abs(dFdy(p))."
         ),
         builtin_function!(
-            fwidthCoarse [(vec_type p,) -> vec_type,]
+            fwidthCoarse [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Sum of absolute derivative in x and y. This is the equivalent of using
 abs(dFdxCoarse(p)) + abs(dFdyCoarse(p)). This function is not available
 on gl_compatibility profile."
         ),
         builtin_function!(
-            fwidthFine [(vec_type p,) -> vec_type,]
+            fwidthFine [(vec_type p,) -> vec_type in FRAGMENT_STAGES,]
 "Sum of absolute derivative in x and y. This is the equivalent of using
 abs(dFdxFine(p)) + abs(dFdyFine(p)). This function is not available on
 gl_compatibility profile."
@@ -693,15 +828,15 @@ into 8-bit and pack them into a 32-bit unsigned integer and vice-versa."
             "Reverse the order of bits in an integer."
         ),
         builtin_function!(
-            bitCount [(ivec_type value,) -> ivec_type, (uvec_type value,) -> uvec_type,]
+            bitCount [(ivec_type value,) -> ivec_type, (uvec_type value,) -> ivec_type,]
             "Counts the number of 1 bits in an integer."
         ),
         builtin_function!(
-            findLSB [(ivec_type value,) -> ivec_type, (uvec_type value,) -> uvec_type,]
+            findLSB [(ivec_type value,) -> ivec_type, (uvec_type value,) -> ivec_type,]
             "Find the index of the least significant bit set to 1 in an integer."
         ),
         builtin_function!(
-            findMSB [(ivec_type value,) -> ivec_type, (uvec_type value,) -> uvec_type,]
+            findMSB [(ivec_type value,) -> ivec_type, (uvec_type value,) -> ivec_type,]
             "Find the index of the most significant bit set to 1 in an integer."
         ),
         builtin_function!(
@@ -723,7 +858,7 @@ msb - will contain the most significant bits.
 lsb - will contain the least significant bits."
         ),
         builtin_function!(
-            uadCarry [(uvec_type x, uvec_type y, Out uvec_type carry,) -> uvec_type,]
+            uaddCarry [(uvec_type x, uvec_type y, Out uvec_type carry,) -> uvec_type,]
             "Adds two unsigned integers and generates carry."
         ),
         builtin_function!(
@@ -731,7 +866,7 @@ lsb - will contain the least significant bits."
             "Subtracts two unsigned integers and generates borrow."
         ),
         builtin_function!(
-            ldexp [(vec_type x, Out ivec_type exp,) -> vec_type,]
+            ldexp [(vec_type x, ivec_type exp,) -> vec_type,]
 "Assemble a floating-point number from a value and exponent. If this
 product is too large to be represented in the floating-point type the
 result is undefined."
@@ -741,7 +876,103 @@ result is undefined."
 "Splits a floating-point number(x) into significand (in the range of [0.5, 1.0])
 and an integral exponent. For x equals zero the significand and exponent are
 both zero. For x of infinity or NaN, the results are undefined."
+        ),
+        builtin_function!(
+            emit_subparticle [
+                (mat4 xform, vec3 velocity, vec4 color, vec4 custom, uint flags,) -> bool in PARTICLE_STAGES,
+            ]
+"Emits a particle from a sub-emitter. Use the FLAG_EMIT_* constants to
+choose which of the arguments are applied. Returns false if the particle
+could not be emitted."
+        ),
+        builtin_function!(
+            texture_sdf [(vec2 sdf_pos,) -> float in SDF_STAGES,]
+            "Performs an SDF texture lookup."
+        ),
+        builtin_function!(
+            texture_sdf_normal [(vec2 sdf_pos,) -> vec2 in SDF_STAGES,]
+            "Calculates a normal from the SDF texture."
+        ),
+        builtin_function!(
+            sdf_to_screen_uv [(vec2 sdf_pos,) -> vec2 in SDF_STAGES,]
+            "Converts an SDF to screen UV."
+        ),
+        builtin_function!(
+            screen_uv_to_sdf [(vec2 uv,) -> vec2 in SDF_STAGES,]
+            "Converts screen UV to an SDF."
         )
     ])
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{line_index::PositionEncoding, memory::Memory};
+
+    fn errors(code: &str) -> Vec<String> {
+        let mut memory = Memory::new(code, None, PositionEncoding::default());
+        memory.evaluate_new(None)
+            .iter()
+            .filter(|x| !x.message.ends_with("is unused."))
+            .map(|x| x.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn cube_and_external_samplers_read_as_float() {
+        let code = "shader_type spatial;
+uniform samplerCube cube;
+uniform samplerCubeArray cubes;
+uniform samplerExternalOES external;
+void fragment() {
+    vec4 a = texture(cube, vec3(0.0));
+    vec4 b = textureLod(cubes, vec4(0.0), 1.0);
+    vec4 c = textureProj(external, vec3(0.0));
+    ivec3 d = textureSize(cubes, 0);
+    vec4 e = textureGather(cube, vec3(0.0), 1);
+    ALBEDO = (a + b + c + e).rgb * float(d.x);
+}
+";
+        assert_eq!(errors(code), Vec::<String>::new());
+        let code = "shader_type spatial;
+uniform samplerCube cube;
+void fragment() {
+    ivec4 a = texture(cube, vec3(0.0));
+    vec4 b = texture(cube, vec2(0.0));
+}
+";
+        assert_eq!(
+            errors(code),
+            vec!["Type mismatch: 'ivec4' and 'vec4'.", "Invalid arguments for function 'texture'"]
+        );
+    }
+
+    #[test]
+    fn overloads_are_available_in_their_stages() {
+        let functions = make_builtin_functions();
+        let signature = |name: &str, params: usize| {
+            functions[name].signatures.iter().find(|x| x.params.len() == params).unwrap().clone()
+        };
+
+        let sin = signature("sin", 1);
+        assert!(sin.is_available(ShaderType::Fog, Some("fog")));
+        assert!(sin.is_available(ShaderType::Spatial, None));
+
+        let dfdx = signature("dFdx", 1);
+        assert!(dfdx.is_available(ShaderType::Spatial, Some("fragment")));
+        assert!(!dfdx.is_available(ShaderType::Spatial, Some("vertex")));
+        assert!(dfdx.is_available(ShaderType::Sky, Some("sky")));
+        assert!(!dfdx.is_available(ShaderType::Particles, None));
+        // Outside of a stage, a helper may still be called from one that allows it.
+        assert!(dfdx.is_available(ShaderType::CanvasItem, None));
+
+        let emit = signature("emit_subparticle", 5);
+        assert!(emit.is_available(ShaderType::Particles, Some("process")));
+        assert!(!emit.is_available(ShaderType::Spatial, Some("fragment")));
+
+        let sdf = signature("texture_sdf", 1);
+        assert!(sdf.is_available(ShaderType::CanvasItem, Some("light")));
+        assert!(!sdf.is_available(ShaderType::CanvasItem, Some("vertex")));
+    }
+}
@@ -37,15 +37,15 @@ pub fn make_builtin_hints() -> HashMap<String, HintInfo> {
             "Used as normalmap."
         ),
         build_hint!(
-            (sampler2D) hint_default_white
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) hint_default_white
             "As value or albedo color, default to opaque white."
         ),
         build_hint!(
-            (sampler2D) hint_default_black
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) hint_default_black
             "As value or albedo color, default to opaque black."
         ),
         build_hint!(
-            (sampler2D) hint_default_transparent
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) hint_default_transparent
             "As value or albedo color, default to transparent black."
         ),
         build_hint!(
@@ -89,35 +89,35 @@ _normal is a normal map that guides the roughness limiter, with roughness
 increasing in areas that have high-frequency detail."
         ),
         build_hint!(
-            (sampler2D) filter_nearest
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) filter_nearest
             "Enabled specified texture filtering."
         ),
         build_hint!(
-            (sampler2D) filter_linear
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) filter_linear
             "Enabled specified texture filtering."
         ),
         build_hint!(
-            (sampler2D) filter_nearest_mipmap
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) filter_nearest_mipmap
             "Enabled specified texture filtering."
         ),
         build_hint!(
-            (sampler2D) filter_linear_mipmap
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) filter_linear_mipmap
             "Enabled specified texture filtering."
         ),
         build_hint!(
-            (sampler2D) filter_nearest_mipmap_anisotropic
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) filter_nearest_mipmap_anisotropic
             "Enabled specified texture filtering."
         ),
         build_hint!(
-            (sampler2D) filter_linear_mipmap_anisotropic
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) filter_linear_mipmap_anisotropic
             "Enabled specified texture filtering."
         ),
         build_hint!(
-            (sampler2D) repeat_enable
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) repeat_enable
             "Enabled texture repeating."
         ),
         build_hint!(
-            (sampler2D) repeat_disable
+            (sampler2D sampler2DArray sampler3D samplerCube samplerCubeArray) repeat_disable
             "Enabled texture repeating."
         ),
         build_hint!(
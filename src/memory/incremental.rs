@@ -0,0 +1,617 @@
+use std::collections::{HashMap, HashSet};
+use lsp_types::*;
+
+use crate::{
+    interpreter::evaluate_top_level_node,
+    lexer::{lex_tokens, relex_tokens, Token, TokenKind, TokenStream},
+    line_index::PositionEncoding,
+    calculate_new_end_position,
+    nodes::{FunctionNode, TopLevelNode},
+    parser::parse_top_level
+};
+
+use super::{make_builtin_functions, CallGraph, variable_builtins, BuiltinUse, FunctionInfo, Memory, Scope, ScopeList, StructInfo, SymbolReference, ValueInfo};
+
+/// What the last analysis of a document found, kept so that later analyses
+/// only redo the work that an edit invalidated.
+///
+/// The preprocessed tokens are reused while the text and its includes are
+/// unchanged, and only the edited part of the text is lexed again. Top-level
+/// declarations are reused while their tokens are, and their evaluation is
+/// replayed unless one of the names they mention has been declared
+/// differently since. Declarations after an edit are moved along with their
+/// text.
+///
+/// A call to a function declared further down does not see it when the
+/// call is evaluated, so editing that function leaves its callers' effects
+/// as they were. What does depend on it, like the stage and recursion
+/// checks, comes from the call graph, which is built again every time.
+#[derive(Default)]
+pub struct AnalysisCache {
+    stream: Option<CachedStream>,
+    declarations: Vec<CachedDeclaration>,
+    /// The exports of declarations that edits have invalidated, so that the
+    /// declarations using them can be found.
+    stale_exports: HashMap<String, String>,
+    /// Set when nothing that was evaluated can be trusted, such as after the
+    /// shader type changes.
+    evaluate_all: bool,
+}
+
+struct CachedStream {
+    code: String,
+    predefined_macros: String,
+    /// The tokens of `code` before preprocessing.
+    raw_tokens: Vec<Token>,
+    include_texts: Vec<Option<String>>,
+    stream: TokenStream,
+}
+
+struct CachedDeclaration {
+    tokens: Vec<Token>,
+    node: TopLevelNode,
+    parse_diagnostics: Vec<Diagnostic>,
+    /// Every name mentioned in the declaration.
+    identifiers: HashSet<String>,
+    effects: Option<Effects>,
+    /// Set when an edit before it moved the declaration. Its node still has the
+    /// old positions, so it is parsed again, but its effects are moved along.
+    moved: bool,
+}
+
+/// Everything that evaluating a declaration added to the memory.
+struct Effects {
+    exports: Vec<Export>,
+    /// The scopes it entered, and where the first of them was in the scope list.
+    scopes: Vec<Scope>,
+    scope_base: usize,
+    references: Vec<SymbolReference>,
+    builtin_uses: Vec<BuiltinUse>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Debug)]
+enum Export {
+    Value(String, ValueInfo),
+    Function(String, FunctionInfo),
+    Struct(String, StructInfo),
+}
+
+impl Export {
+    fn name(&self) -> &String {
+        match self {
+            Export::Value(name, _) | Export::Function(name, _) | Export::Struct(name, _) => name
+        }
+    }
+
+    /// A description of the export, for telling whether it has changed.
+    fn signature(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl AnalysisCache {
+    /// Drops the declarations that `change` touches, and moves those after it
+    /// to where their text ends up. Must be called before the change is
+    /// applied, while its range still refers to the text the declarations
+    /// came from.
+    pub fn apply_change(&mut self, change: &TextDocumentContentChangeEvent, encoding: PositionEncoding) {
+        let range = if let Some(range) = change.range { range } else {
+            self.declarations.clear();
+            self.evaluate_all = true;
+            return;
+        };
+        let shift = PositionShift { end: range.end, new_end: calculate_new_end_position(range.start, &change.text, encoding) };
+
+        let declarations = std::mem::take(&mut self.declarations);
+        for mut declaration in declarations {
+            let (start, end) = declaration.span();
+            if end < range.start {
+                self.declarations.push(declaration);
+                continue;
+            }
+            if start >= range.end {
+                if shift.end != shift.new_end {
+                    declaration.shift(&shift);
+                }
+                self.declarations.push(declaration);
+                continue;
+            }
+            if let TopLevelNode::ShaderType(_) = declaration.node {
+                self.evaluate_all = true;
+            }
+            for export in declaration.effects.iter().flat_map(|x| x.exports.iter()) {
+                self.stale_exports.insert(export.name().clone(), export.signature());
+            }
+        }
+    }
+}
+
+/// Moves the ranges after an edit to where their text is once it is applied.
+struct PositionShift {
+    /// Where the edit ended before and after it was applied.
+    end: Position,
+    new_end: Position,
+}
+
+impl PositionShift {
+    fn position(&self, position: &mut Position) {
+        if position.line == self.end.line {
+            position.character = position.character - self.end.character + self.new_end.character;
+        }
+        position.line = position.line - self.end.line + self.new_end.line;
+    }
+
+    fn range(&self, range: &mut Range) {
+        if range.start >= self.end {
+            self.position(&mut range.start);
+            self.position(&mut range.end);
+        }
+    }
+
+    /// Moves a range in the document, leaving those in includes alone.
+    fn local_range(&self, range: &mut Option<Range>, include_path: &Option<String>) {
+        if let (Some(range), None) = (range, include_path) {
+            self.range(range);
+        }
+    }
+
+    fn value(&self, value: &mut ValueInfo) {
+        self.local_range(&mut value.range, &value.include_path);
+    }
+}
+
+impl CachedDeclaration {
+    fn shift(&mut self, shift: &PositionShift) {
+        for token in &mut self.tokens {
+            shift.range(&mut token.range);
+        }
+        self.moved = true;
+        let effects = if let Some(effects) = &mut self.effects { effects } else { return };
+        for export in &mut effects.exports {
+            match export {
+                Export::Value(_, x) => shift.value(x),
+                Export::Function(_, x) => shift.local_range(&mut x.range, &x.include_path),
+                Export::Struct(_, x) => if x.include_path.is_none() {
+                    shift.range(&mut x.range);
+                    for field in &mut x.fields {
+                        shift.range(&mut field.range);
+                    }
+                }
+            }
+        }
+        for scope in &mut effects.scopes {
+            shift.range(&mut scope.range);
+            scope.values.values_mut().for_each(|x| shift.value(x));
+        }
+        for reference in &mut effects.references {
+            shift.range(&mut reference.range);
+            if reference.declaration.include_path.is_none() {
+                shift.range(&mut reference.declaration.range);
+            }
+        }
+        for builtin_use in &mut effects.builtin_uses {
+            shift.range(&mut builtin_use.range);
+        }
+        for diagnostic in &mut effects.diagnostics {
+            shift.range(&mut diagnostic.range);
+        }
+    }
+
+    fn span(&self) -> (Position, Position) {
+        let start = self.tokens.first().map_or(Position::default(), |x| x.range.start);
+        let end = self.tokens.last().map_or(Position::default(), |x| x.range.end);
+        (start, end)
+    }
+
+    /// The name the declaration gives to what it declares, if it has one.
+    fn declared_name(&self, memory: &Memory) -> Option<String> {
+        let identifier = match &self.node {
+            TopLevelNode::Uniform(x) => x.value.identifier,
+            TopLevelNode::Varying(x) => x.value.identifier,
+            TopLevelNode::Const(x) => x.value.identifier,
+            TopLevelNode::Struct(x) => x.identifier,
+            TopLevelNode::Function(x) => x.identifier,
+            _ => return None
+        };
+        Some(memory.get_token_text(identifier))
+    }
+}
+
+impl Memory {
+    /// Preprocesses, parses and evaluates the document, reusing what the
+    /// last analysis found where the document has not changed. Returns the
+    /// token stream so the caller can inspect what sits under the cursor.
+    pub fn analyze(&mut self, cursor: Option<Position>) -> TokenStream {
+        let mut stream = self.cached_token_stream();
+        stream.set_cursor(cursor);
+        self.load_preprocessor_state(&stream);
+
+        let preprocessor_diagnostics = stream.get_source().get_diagnostics().clone();
+        let mut declarations = self.parse_declarations(&mut stream, cursor);
+        self.source.force_change_diagnostics(preprocessor_diagnostics);
+        for declaration in &declarations {
+            self.source.add_diagnostics(declaration.parse_diagnostics.clone());
+        }
+        for range in &stream.inactive_ranges {
+            self.source.push_inactive_region(*range);
+        }
+
+        self.evaluate_declarations(&mut declarations);
+        let functions: Vec<&FunctionNode> = declarations
+            .iter()
+            .filter_map(|x| match &x.node {
+                TopLevelNode::Function(x) => Some(x),
+                _ => None
+            })
+            .collect();
+        let graph = CallGraph::new(self, &functions);
+        self.check_recursion(&graph);
+        self.check_stages(&graph, &functions);
+        self.cache.declarations = declarations;
+        for (name, range) in self.find_unused_declarations(&graph) {
+            self.source.push_unused_hint(range, &name);
+        }
+        stream
+    }
+
+    fn cached_token_stream(&mut self) -> TokenStream {
+        let code = self.source.get_code();
+        let predefined_macros = sorted_debug(&self.predefined_macros);
+        let is_current = self.cache.stream.as_ref().is_some_and(|cached| {
+            cached.code == *code
+                && cached.predefined_macros == predefined_macros
+                && cached.stream.include_paths
+                    .iter()
+                    .map(|x| self.include_sources.read(x))
+                    .eq(cached.include_texts.iter().cloned())
+        });
+        if !is_current {
+            let encoding = self.source.encoding();
+            let raw_tokens = match &self.cache.stream {
+                Some(cached) if cached.code == *code => cached.raw_tokens.clone(),
+                Some(cached) => relex_tokens(&cached.code, &cached.raw_tokens, code, encoding),
+                None => lex_tokens(code, encoding)
+            };
+            let stream = TokenStream::from_raw_tokens(
+                code,
+                raw_tokens.clone(),
+                None,
+                encoding,
+                self.root_dir.as_deref(),
+                &self.include_sources,
+                self.predefined_macros.clone()
+            );
+            let expansions_changed = self.cache.stream.as_ref().is_none_or(|cached| {
+                cached.stream.get_source().get_expansions() != stream.get_source().get_expansions()
+            });
+            if expansions_changed {
+                // Tokens expanded from a macro keep their range, so a changed
+                // macro is not noticed by comparing tokens.
+                self.cache.declarations.clear();
+                self.cache.evaluate_all = true;
+            }
+            self.cache.stream = Some(CachedStream {
+                code: code.clone(),
+                predefined_macros,
+                raw_tokens,
+                include_texts: stream.include_paths.iter().map(|x| self.include_sources.read(x)).collect(),
+                stream
+            });
+        }
+        self.cache.stream.as_ref().unwrap().stream.clone()
+    }
+
+    /// Parses the top-level declarations, reusing the cached ones whose tokens
+    /// are unchanged. The declaration under the cursor is always parsed, since
+    /// parsing is what finds the element being completed.
+    fn parse_declarations(
+        &mut self,
+        stream: &mut TokenStream,
+        cursor: Option<Position>
+    ) -> Vec<CachedDeclaration> {
+        let mut cached: HashMap<(u32, u32), CachedDeclaration> = std::mem::take(&mut self.cache.declarations)
+            .into_iter()
+            .map(|x| (key(x.span().0), x))
+            .collect();
+        let mut declarations = vec![];
+        let mut cursor_passed = cursor.is_none();
+
+        while let Ok(token) = stream.current() {
+            let idx = stream.current_idx();
+            let reusable = cached.remove(&key(token.range.start)).filter(|x| {
+                stream.tokens
+                    .get(idx..idx + x.tokens.len())
+                    .is_some_and(|tokens| same_tokens(tokens, &x.tokens))
+            });
+            let holds_cursor = |end: Position| !cursor_passed && cursor.is_some_and(|x| x <= end);
+
+            if let Some(declaration) = reusable {
+                if !declaration.moved && !holds_cursor(declaration.span().1) {
+                    stream.force_change_position(idx + declaration.tokens.len());
+                    declarations.push(declaration);
+                    continue;
+                }
+                cached.insert(key(token.range.start), declaration);
+            }
+
+            let diagnostic_count = stream.get_source().get_diagnostics().len();
+            let node = match parse_top_level(stream) {
+                Ok(node) => node,
+                Err(_) => break
+            };
+            let tokens = stream.tokens[idx..stream.current_idx()].to_vec();
+            let end = tokens.last().map_or(token.range.end, |x| x.range.end);
+            cursor_passed |= holds_cursor(end);
+            let node = if let Some(node) = node { node } else { continue };
+
+            // Parsing a reused declaration again gives the same node, so what
+            // its evaluation did still holds.
+            let effects = cached
+                .remove(&key(token.range.start))
+                .filter(|x| same_tokens(&x.tokens, &tokens))
+                .and_then(|x| x.effects);
+            let identifiers = tokens
+                .iter()
+                .filter(|x| x.kind == TokenKind::Identifier)
+                .map(|&x| stream.get_source().token_text(x))
+                .collect();
+            declarations.push(CachedDeclaration {
+                parse_diagnostics: stream.get_source().get_diagnostics()[diagnostic_count..].to_vec(),
+                tokens,
+                node,
+                identifiers,
+                effects,
+                moved: false
+            });
+        }
+
+        for declaration in cached.into_values() {
+            for export in declaration.effects.iter().flat_map(|x| x.exports.iter()) {
+                self.cache.stale_exports.insert(export.name().clone(), export.signature());
+            }
+        }
+        declarations
+    }
+
+    /// Evaluates the declarations in order. A declaration is replayed from
+    /// its recorded effects when none of the names it mentions have changed
+    /// meaning; includes, render modes and the shader type are always
+    /// evaluated again, since they depend on more than their own text.
+    fn evaluate_declarations(&mut self, declarations: &mut [CachedDeclaration]) {
+        let mut scopes = ScopeList::new();
+        scopes.extend(variable_builtins());
+        self.scopes = scopes;
+        self.functions = make_builtin_functions();
+        self.structs = HashMap::new();
+        self.references = vec![];
+        self.builtin_uses = vec![];
+
+        let evaluate_all = std::mem::take(&mut self.cache.evaluate_all)
+            || declarations.iter().any(|x| {
+                x.effects.is_none() && matches!(x.node, TopLevelNode::ShaderType(_))
+            });
+        let stale_exports = std::mem::take(&mut self.cache.stale_exports);
+        let redeclared: HashSet<String> = declarations
+            .iter()
+            .filter(|x| x.effects.is_none())
+            .filter_map(|x| x.declared_name(self))
+            .collect();
+        // Names whose meaning may differ from when the declarations mentioning
+        // them were last evaluated.
+        let mut changed: HashSet<String> = stale_exports
+            .keys()
+            .filter(|x| !redeclared.contains(*x))
+            .cloned()
+            .collect();
+
+        for declaration in declarations.iter_mut() {
+            let always_evaluated = matches!(
+                declaration.node,
+                TopLevelNode::Include(_) | TopLevelNode::RenderMode(_) | TopLevelNode::ShaderType(_)
+            );
+            if let Some(effects) = &declaration.effects {
+                if !evaluate_all && !always_evaluated && declaration.identifiers.is_disjoint(&changed) {
+                    self.replay_effects(effects);
+                    continue;
+                }
+            }
+
+            let previous_exports: HashMap<String, String> = match &declaration.effects {
+                Some(effects) => effects.exports.iter().map(|x| (x.name().clone(), x.signature())).collect(),
+                None => stale_exports.clone()
+            };
+            let effects = self.evaluate_declaration(declaration);
+            for export in &effects.exports {
+                if previous_exports.get(export.name()) != Some(&export.signature()) {
+                    changed.insert(export.name().clone());
+                }
+            }
+            if let Some(old_effects) = &declaration.effects {
+                for export in &old_effects.exports {
+                    if !effects.exports.iter().any(|x| x.name() == export.name()) {
+                        changed.insert(export.name().clone());
+                    }
+                }
+            }
+            declaration.effects = Some(effects);
+        }
+    }
+
+    fn evaluate_declaration(&mut self, declaration: &CachedDeclaration) -> Effects {
+        let scope_base = self.scopes.scopes.len();
+        let reference_count = self.references.len();
+        let builtin_use_count = self.builtin_uses.len();
+        let diagnostic_count = self.source.get_diagnostics().len();
+
+        _ = evaluate_top_level_node(declaration.node.clone(), self);
+        self.scopes.force_scope(0);
+
+        let exports = match &declaration.node {
+            TopLevelNode::Include(_) => self.collect_include_exports(),
+            TopLevelNode::Function(_) => declaration.declared_name(self)
+                .and_then(|name| self.functions.get(&name).map(|x| Export::Function(name, x.clone())))
+                .into_iter()
+                .collect(),
+            TopLevelNode::Struct(_) => declaration.declared_name(self)
+                .and_then(|name| self.structs.get(&name).map(|x| Export::Struct(name, x.clone())))
+                .into_iter()
+                .collect(),
+            _ => declaration.declared_name(self)
+                .and_then(|name| self.scopes.scopes[0].values.get(&name).map(|x| Export::Value(name, x.clone())))
+                .into_iter()
+                .collect()
+        };
+        Effects {
+            exports,
+            scopes: self.scopes.scopes[scope_base..].to_vec(),
+            scope_base,
+            references: self.references[reference_count..].to_vec(),
+            builtin_uses: self.builtin_uses[builtin_use_count..].to_vec(),
+            diagnostics: self.source.get_diagnostics()[diagnostic_count..].to_vec()
+        }
+    }
+
+    /// Everything declared in an include, which are the only globals with an
+    /// include path.
+    fn collect_include_exports(&self) -> Vec<Export> {
+        let values = self.scopes.scopes[0].values
+            .iter()
+            .filter(|(_, x)| x.include_path.is_some())
+            .map(|(name, x)| Export::Value(name.clone(), x.clone()));
+        let functions = self.functions
+            .iter()
+            .filter(|(_, x)| x.include_path.is_some())
+            .map(|(name, x)| Export::Function(name.clone(), x.clone()));
+        let structs = self.structs
+            .iter()
+            .filter(|(_, x)| x.include_path.is_some())
+            .map(|(name, x)| Export::Struct(name.clone(), x.clone()));
+        values.chain(functions).chain(structs).collect()
+    }
+
+    fn replay_effects(&mut self, effects: &Effects) {
+        for export in &effects.exports {
+            match export {
+                Export::Value(name, x) => { self.scopes.scopes[0].values.insert(name.clone(), x.clone()); }
+                Export::Function(name, x) => { self.functions.insert(name.clone(), x.clone()); }
+                Export::Struct(name, x) => { self.structs.insert(name.clone(), x.clone()); }
+            }
+        }
+        let scope_base = self.scopes.scopes.len();
+        for scope in &effects.scopes {
+            let mut scope = scope.clone();
+            if scope.parent >= effects.scope_base {
+                scope.parent = scope.parent - effects.scope_base + scope_base;
+            }
+            self.scopes.scopes.push(scope);
+        }
+        self.references.extend(effects.references.iter().cloned());
+        self.builtin_uses.extend(effects.builtin_uses.iter().cloned());
+        self.source.add_diagnostics(effects.diagnostics.clone());
+    }
+}
+
+fn same_tokens(a: &[Token], b: &[Token]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| {
+        x.kind == y.kind && x.range == y.range && x.expansion == y.expansion
+    })
+}
+
+fn key(position: Position) -> (u32, u32) {
+    (position.line, position.character)
+}
+
+fn sorted_debug<T: std::fmt::Debug>(map: &HashMap<String, T>) -> String {
+    let mut entries: Vec<_> = map.iter().map(|x| format!("{:?}", x)).collect();
+    entries.sort();
+    entries.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = "shader_type spatial;\nuniform float a = 1.0;\n\nvoid vertex() {\n\tVERTEX.y += a;\n}\n";
+
+    fn insert_line(memory: &mut Memory, line: u32, text: &str) {
+        let position = Position::new(line, 0);
+        memory.apply_change(TextDocumentContentChangeEvent {
+            range: Some(Range::new(position, position)),
+            range_length: None,
+            text: text.to_string()
+        });
+    }
+
+    #[test]
+    fn declarations_below_an_inserted_line_are_reused() {
+        let mut memory = Memory::new(CODE, None, PositionEncoding::default());
+        memory.analyze(None);
+        insert_line(&mut memory, 2, "const int b = 2;\n");
+
+        // What the function's evaluation recorded is replayed rather than
+        // evaluated again, so a marker added to it shows up.
+        let function = memory.cache.declarations
+            .iter_mut()
+            .find(|x| matches!(x.node, TopLevelNode::Function(_)))
+            .unwrap();
+        assert!(function.moved);
+        assert_eq!(function.span().0.line, 4);
+        let marker = Diagnostic { message: "marker".to_string(), ..Default::default() };
+        function.effects.as_mut().unwrap().diagnostics.push(marker);
+
+        memory.analyze(None);
+        assert!(memory.source.get_diagnostics().iter().any(|x| x.message == "marker"));
+        let uses: Vec<u32> = memory.references
+            .iter()
+            .filter(|x| x.declaration.range.start.line == 1)
+            .map(|x| x.range.start.line)
+            .collect();
+        assert_eq!(uses, vec![1, 5]);
+        assert!(memory.scopes.scopes.iter().any(|x| x.range.start.line == 4));
+    }
+
+    #[test]
+    fn declarations_below_a_removed_line_are_reused() {
+        let mut memory = Memory::new(CODE, None, PositionEncoding::default());
+        memory.analyze(None);
+        memory.apply_change(TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(2, 0), Position::new(3, 0))),
+            range_length: None,
+            text: String::new()
+        });
+        assert!(memory.cache.declarations.iter().any(|x| x.moved && x.span().0.line == 2));
+        memory.analyze(None);
+
+        let mut expected = Memory::new(memory.source.get_code(), None, PositionEncoding::default());
+        expected.analyze(None);
+        let ranges = |memory: &Memory| memory.references.iter().map(|x| (x.range, x.declaration.range)).collect::<Vec<_>>();
+        assert_eq!(ranges(&memory), ranges(&expected));
+        assert_eq!(memory.source.get_diagnostics(), expected.source.get_diagnostics());
+    }
+
+    #[test]
+    fn callers_of_an_edited_function_below_them_match_a_fresh_analysis() {
+        let code = "shader_type spatial;\nvoid vertex() { VERTEX.y = f(1.0); }\nfloat f(float a) { return dFdx(a); }\n";
+        let edits = [
+            (Range::new(Position::new(2, 0), Position::new(2, 5)), "int"),
+            (Range::new(Position::new(2, 26), Position::new(2, 33)), "a"),
+            (Range::new(Position::new(2, 6), Position::new(2, 7)), "g"),
+            (Range::new(Position::new(2, 0), Position::new(3, 0)), ""),
+        ];
+        for (range, text) in edits {
+            let mut memory = Memory::new(code, None, PositionEncoding::default());
+            memory.analyze(None);
+            memory.apply_change(TextDocumentContentChangeEvent {
+                range: Some(range),
+                range_length: None,
+                text: text.to_string()
+            });
+            memory.analyze(None);
+
+            let mut expected = Memory::new(memory.source.get_code(), None, PositionEncoding::default());
+            expected.analyze(None);
+            assert_eq!(memory.source.get_diagnostics(), expected.source.get_diagnostics());
+        }
+    }
+}
@@ -7,14 +7,24 @@ mod scope;
 mod hint;
 mod variables;
 mod render_modes;
+mod references;
+mod incremental;
+mod unused;
+mod call_graph;
+mod stages;
+mod recursion;
 pub use variables::*;
 pub use types::*;
 pub use functions::*;
 pub use scope::*;
 pub use hint::*;
 pub use render_modes::*;
+pub use references::*;
+pub use incremental::*;
+pub use call_graph::*;
+pub use stages::*;
 
-use crate::{get_byte_offset_from_position, interpreter::{evaluate_top_level_node, EvaluateError}, lexer::{Token, TokenStream}, nodes::TopLevelNode, parse_tokens, source_code::SourceDocument};
+use crate::{get_byte_offset_from_position, includes::IncludeSources, interpreter::{evaluate_expression, evaluate_top_level_node, EvaluateError, ExpressionEvaluation}, lexer::{ExtraRange, MacroInfo, Token, TokenStream}, line_index::PositionEncoding, nodes::{ExpressionNode, TopLevelNode}, project_files, source_code::SourceDocument};
 
 pub struct Memory {
     pub root_dir: Option<String>,
@@ -25,44 +35,68 @@ pub struct Memory {
     pub hints: HashMap<String, HintInfo>,
     pub structs: HashMap<String, StructInfo>,
     pub scopes: ScopeList,
+    pub references: Vec<SymbolReference>,
+    /// Stage builtins used by helper functions, which are checked once the
+    /// stages calling each helper are known.
+    pub builtin_uses: Vec<BuiltinUse>,
+    /// Macros supplied by the client, defined before the document is preprocessed.
+    pub predefined_macros: HashMap<String, MacroInfo>,
+    /// Macros still defined at the end of the document.
+    pub macros: HashMap<String, MacroInfo>,
+    /// Where the text of includes is read from.
+    pub include_sources: IncludeSources,
+    /// Paths of every include the document depends on.
+    pub include_paths: Vec<String>,
+    /// The macros defined where each include is, by the range of its path,
+    /// which the include is evaluated with.
+    pub macros_at_includes: Vec<(Range, HashMap<String, MacroInfo>)>,
+    /// The version of the document given by the client.
+    pub version: Option<i32>,
+    /// Where the document lives, for diagnostics that link to other places in it.
+    pub uri: Option<Url>,
+    /// The function whose body is being evaluated.
+    pub current_function: Option<String>,
 
-    source: SourceDocument
+    source: SourceDocument,
+    cache: AnalysisCache
 }
 impl Memory {
-    pub fn new(source_str: &str, root_dir: Option<String>) -> Self {
-        let source = SourceDocument::new(source_str);
+    pub fn new(source_str: &str, root_dir: Option<String>, encoding: PositionEncoding) -> Self {
+        let source = SourceDocument::new(source_str, encoding);
         let mut scopes = ScopeList::new();
-        if root_dir.is_some() {
-            scopes.extend(variable_builtins());
-        }
-        let (functions, hints) = if root_dir.is_none() {
-            (HashMap::new(), HashMap::new())
-        } else {
-            (make_builtin_functions(), make_builtin_hints())
-        };
+        scopes.extend(variable_builtins());
         
         Memory {
             root_dir,
             shader_type: ShaderType::Spatial,
             valid_render_modes: HashMap::new(),
             builtin_types: make_builtin_types(),
-            functions,
-            hints,
+            functions: make_builtin_functions(),
+            hints: make_builtin_hints(),
             structs: HashMap::new(),
             scopes,
-            source
+            references: vec![],
+            builtin_uses: vec![],
+            predefined_macros: HashMap::new(),
+            macros: HashMap::new(),
+            include_sources: IncludeSources::new(),
+            include_paths: vec![],
+            macros_at_includes: vec![],
+            version: None,
+            uri: None,
+            current_function: None,
+            source,
+            cache: AnalysisCache::default()
         }
     }
 
     pub fn evaluate(&mut self, top_levels: Vec<TopLevelNode>) -> &Vec<Diagnostic> {
         let mut scopes = ScopeList::new();
-        if self.root_dir.is_some() {
-            scopes.extend(variable_builtins());
-        }
-        let functions = if self.root_dir.is_none() { HashMap::new() }
-        else { make_builtin_functions() };
+        scopes.extend(variable_builtins());
         self.scopes = scopes;
-        self.functions = functions;
+        self.functions = make_builtin_functions();
+        self.references = vec![];
+        self.builtin_uses = vec![];
 
         for top_level in top_levels {
             _ = evaluate_top_level_node(top_level, self);
@@ -71,15 +105,66 @@ impl Memory {
         self.source.get_diagnostics()
     }
 
+    /// Re-analyses the document, returning the token stream so the caller can
+    /// inspect what sits under the cursor.
+    pub fn evaluate_at_cursor(&mut self, cursor: Position) -> TokenStream {
+        self.analyze(Some(cursor))
+    }
+
     pub fn evaluate_new(&mut self, cursor: Option<Position>) -> &Vec<Diagnostic> {
-        let mut stream = TokenStream::new(self.source.get_code(), cursor);
-        let tree = parse_tokens(&mut stream);
+        self.analyze(cursor);
+        self.source.get_diagnostics()
+    }
+
 
-        let mut diagnostics = stream.get_source().get_diagnostics().clone();
-        self.source.add_diagnostics(diagnostics);
-        self.evaluate(tree)
+    /// Lexes and preprocesses the document.
+    pub fn token_stream(&self, cursor: Option<Position>) -> TokenStream {
+        let root_dir = self.root_dir.as_deref();
+        let macros = self.predefined_macros.clone();
+        TokenStream::preprocessed(self.source.get_code(), cursor, self.source.encoding(), root_dir, &self.include_sources, macros)
     }
 
+    pub fn load_preprocessor_state(&mut self, stream: &TokenStream) {
+        self.source.set_expansions(stream.get_source().get_expansions().clone());
+        self.macros = stream.macros.clone();
+        self.include_paths = stream.include_paths.clone();
+        self.macros_at_includes = stream.macros_at_includes.clone();
+    }
+
+    pub fn add_reference(
+        &mut self,
+        declaration: SymbolLocation,
+        range: Range,
+        kind: ReferenceKind
+    ) {
+        self.references.push(SymbolReference { declaration, range, kind });
+    }
+
+    /// Evaluates an expression that is not part of the analysed document, such
+    /// as an argument still being typed, without keeping the diagnostics,
+    /// references and builtin uses it records.
+    pub fn evaluate_detached(&mut self, expression: ExpressionNode) -> Option<ExpressionEvaluation> {
+        let diagnostics = self.source.get_diagnostics().clone();
+        let references = self.references.clone();
+        let builtin_uses = self.builtin_uses.clone();
+        let result = evaluate_expression(self, expression).ok();
+        self.source.force_change_diagnostics(diagnostics);
+        self.references = references;
+        self.builtin_uses = builtin_uses;
+        result
+    }
+
+    /// Marks the reference or builtin use recorded at `range` as a write.
+    pub fn mark_written(&mut self, range: Range) {
+        if let Some(reference) = self.references.iter_mut().rev().find(|x| x.range == range) {
+            if reference.kind == ReferenceKind::Read {
+                reference.kind = ReferenceKind::Write;
+            }
+        }
+        if let Some(builtin_use) = self.builtin_uses.iter_mut().rev().find(|x| x.range == range) {
+            builtin_use.written = true;
+        }
+    }
 
     pub fn get_builtin_types(&self, scope: usize) -> Vec<CompletionItem> {
         self.builtin_types
@@ -118,6 +203,24 @@ impl Memory {
 
     }
 
+    pub fn get_macros(&self) -> Vec<CompletionItem> {
+        self.macros.iter().map(|(name, info)| {
+            let (kind, insert_text) = if info.params.is_some() {
+                (CompletionItemKind::FUNCTION, format!("{}($0)", name))
+            } else {
+                (CompletionItemKind::CONSTANT, name.clone())
+            };
+            CompletionItem {
+                label: name.clone(),
+                kind: Some(kind),
+                detail: Some(info.definition(name)),
+                insert_text_format: Some(InsertTextFormat::SNIPPET),
+                insert_text: Some(insert_text),
+                ..Default::default()
+            }
+        }).collect()
+    }
+
     pub fn get_structs(&self) -> Vec<CompletionItem> {
         self.structs.keys().map(|x| CompletionItem {
             label: x.to_string(),
@@ -126,9 +229,13 @@ impl Memory {
         }).collect()
     }
     
+    /// The functions that may be called at `cursor`, leaving out the builtins
+    /// with no overload available there.
     pub fn get_functions(&self, cursor: Position, is_const: bool) -> Vec<CompletionItem> {
+        let stage = self.stage_at(cursor);
         self.functions
             .iter()
+            .filter(|(_, info)| info.signatures.iter().any(|x| x.is_available(self.shader_type, stage)))
             .filter_map(|(name, info)| {
                 if !is_const || info.is_const {
                     Some(CompletionItem {
@@ -143,6 +250,21 @@ impl Memory {
             .collect()
     }
 
+    /// The stage function whose body holds `position`, if any. It is the
+    /// function of the document declared last before that body.
+    fn stage_at(&self, position: Position) -> Option<&'static str> {
+        let body = self.scopes.scopes
+            .iter()
+            .find(|x| matches!(x.scope_type, ScopeType::Function(_)) && x.range.contains_position(position))?;
+        let (name, _) = self.functions
+            .iter()
+            .filter(|(_, x)| x.include_path.is_none())
+            .filter_map(|(name, x)| Some((name, x.range?)))
+            .filter(|(_, range)| range.start <= body.range.start)
+            .max_by_key(|(_, range)| range.start)?;
+        shader_stages(self.shader_type).iter().copied().find(|x| x == name)
+    }
+
     pub fn get_variables(&self, scope: usize, is_const: bool) -> Vec<CompletionItem>{
         self.scopes.collect_scopes_from(scope)
             .iter()
@@ -172,7 +294,7 @@ impl Memory {
     }
 
     pub fn get_token_text(&self, token: Token) -> String {
-        token.text(self.source.get_code())
+        self.source.token_text(token)
     }
 
     pub fn get_source(&self) -> &SourceDocument {
@@ -185,23 +307,15 @@ impl Memory {
     }
 
     pub fn fetch_gdshaderinc_files(&self, root_path: &str) -> Vec<String> {
-        let mut result = Vec::new();
-
-        let walker = walkdir::WalkDir::new(root_path).into_iter();
-        for entry in walker.filter_map(|e| e.ok()) {
-            let path = entry.path();
-            if path.is_file() && path.extension().unwrap_or_default() == "gdshaderinc" {
-                if let Some(path) = path.to_str() {
-                    let better_path = path.replace(root_path, "res://");
-                    result.push(better_path);
-                }
-            }
-        }
-    
-        result
+        project_files(root_path, &["gdshaderinc"])
+            .iter()
+            .filter_map(|x| x.to_str())
+            .map(|x| x.replace(root_path, "res://"))
+            .collect()
     }
 
     pub fn apply_change(&mut self, change: TextDocumentContentChangeEvent) {
+        self.cache.apply_change(&change, self.source.encoding());
         self.source.apply_change(change);
     }
 }
@@ -212,23 +326,26 @@ pub struct ValueInfo {
     pub editable: bool,
     pub is_const: bool,
     pub range: Option<Range>,
+    /// The include file this was declared in, or `None` for the current document.
+    pub include_path: Option<String>,
     pub description: Option<String>
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct StructInfo {
     pub fields: Vec<StructField>,
-    pub range: Range 
+    pub range: Range,
+    pub include_path: Option<String>
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct StructField {
     pub name: String,
     pub ty: TypeInfo,
     pub range: Range, 
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ShaderType {
     Spatial,
     CanvasItem,
@@ -236,4 +353,16 @@ pub enum ShaderType {
     Fog,
     Sky
 }
+impl ShaderType {
+    /// The name the shader type is declared with.
+    pub fn name(self) -> &'static str {
+        match self {
+            ShaderType::Spatial => "spatial",
+            ShaderType::CanvasItem => "canvas_item",
+            ShaderType::Particles => "particles",
+            ShaderType::Fog => "fog",
+            ShaderType::Sky => "sky",
+        }
+    }
+}
 
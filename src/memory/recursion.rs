@@ -0,0 +1,38 @@
+use lsp_types::{DiagnosticRelatedInformation, Location};
+
+use super::{CallGraph, Memory};
+
+impl Memory {
+    /// Shaders cannot recurse, whether a function calls itself or goes
+    /// through others. Each cycle is reported at its first call, and links to
+    /// every call along it.
+    pub fn check_recursion(&mut self, graph: &CallGraph) {
+        for cycle in graph.cycles() {
+            let calls: Vec<_> = cycle
+                .windows(2)
+                .filter_map(|x| graph.call_between(x[0], x[1]))
+                .collect();
+            let first = if let Some(x) = calls.first() { x } else { continue };
+
+            let message = if cycle.len() == 2 {
+                format!("Recursion is not allowed: '{}' calls itself.", graph.functions[cycle[0]].name)
+            } else {
+                format!("Recursion is not allowed: {}.", graph.describe_chain(&cycle))
+            };
+            let related_information = self.uri.as_ref().map(|uri| {
+                calls
+                    .iter()
+                    .map(|x| DiagnosticRelatedInformation {
+                        location: Location { uri: uri.clone(), range: x.range },
+                        message: format!(
+                            "'{}' calls '{}' here.",
+                            graph.functions[x.caller].name,
+                            graph.functions[x.callee].name
+                        )
+                    })
+                    .collect()
+            });
+            self.source.push_related_error(&message, first.range, related_information);
+        }
+    }
+}
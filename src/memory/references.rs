@@ -0,0 +1,29 @@
+use lsp_types::Range;
+
+/// Where a symbol was declared. An `include_path` of `None` means the
+/// symbol lives in the document that was evaluated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolLocation {
+    pub include_path: Option<String>,
+    pub range: Range,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceKind {
+    Declaration,
+    Read,
+    Write,
+}
+
+/// A single resolved use of a user-declared symbol in the current document.
+#[derive(Clone, Debug)]
+pub struct SymbolReference {
+    pub declaration: SymbolLocation,
+    pub range: Range,
+    pub kind: ReferenceKind,
+}
+
+/// A range as a key for maps, since ranges cannot be hashed.
+pub fn range_key(range: Range) -> [u32; 4] {
+    [range.start.line, range.start.character, range.end.line, range.end.character]
+}
@@ -19,6 +19,7 @@ pub enum ScopeType {
     Function(Box<FunctionReturn>)
 }
 
+#[derive(Clone)]
 pub struct Scope {
     pub scope_type: ScopeType,
     pub parent: usize,
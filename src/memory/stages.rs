@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+
+use lsp_types::Range;
+
+use crate::{lexer::{Token, TokenKind}, nodes::*};
+
+use super::{call_graph::collect_calls, range_key, shader_stages, stage_variables, CallGraph, Memory, ReferenceKind, ShaderType, StageTags, ValueInfo};
+
+/// The stages that may write varyings. The fragment stage writes them for
+/// the light stage to read.
+const VARYING_WRITERS: &[&str] = &["vertex", "fragment"];
+
+/// A builtin of some stage that a helper function reads or writes, or a
+/// builtin function it calls that only some stages provide. It is only valid
+/// if every stage that calls the helper provides it.
+#[derive(Clone, Debug)]
+pub struct BuiltinUse {
+    pub name: String,
+    pub range: Range,
+    pub written: bool,
+    /// For a call, the stages the overload called is available in.
+    pub call_stages: Option<StageTags>,
+}
+
+impl Memory {
+    /// Inside a helper function, finds a builtin of any stage of the shader
+    /// type. Whether the stages calling the helper provide it is checked
+    /// after the whole document is evaluated.
+    pub fn find_stage_builtin(&self, name: &str) -> Option<ValueInfo> {
+        let stages = shader_stages(self.shader_type);
+        if self.current_function.as_ref().is_none_or(|x| stages.contains(&x.as_str())) {
+            return None;
+        }
+        stages
+            .iter()
+            .flat_map(|x| stage_variables(self.shader_type, x))
+            .find_map(|(x, info)| (x == name).then_some(info))
+    }
+
+    /// Checks a call to a builtin overload that only some stages provide. A
+    /// call in a helper function is checked once the stages calling it are
+    /// known, unless no stage of the shader type provides the overload.
+    pub fn check_call_stages(&mut self, name: &str, tags: StageTags, range: Range) {
+        let function = if let Some(function) = self.current_function.clone() { function } else { return };
+        let shader_type = self.shader_type;
+        let is_available = |stage: &str| tags.contains(&(shader_type, stage));
+        let stages = shader_stages(shader_type);
+        let message = if !stages.iter().any(|x| is_available(x)) {
+            format!("'{}' {} in '{}' shaders.", name, self.unavailable_call(name), shader_type.name())
+        } else if !stages.contains(&function.as_str()) {
+            self.builtin_uses.push(BuiltinUse { name: name.to_string(), range, written: false, call_stages: Some(tags) });
+            return;
+        } else if !is_available(&function) {
+            format!("'{}' {} in the '{}' function.", name, self.unavailable_call(name), function)
+        } else {
+            return;
+        };
+        _ = self.alert_error(&message, range);
+    }
+
+    /// Other overloads of the function may be available anywhere, in which
+    /// case only the one called is at fault.
+    fn unavailable_call(&self, name: &str) -> &'static str {
+        let overloaded = self.functions.get(name).is_some_and(|x| x.signatures.iter().any(|x| x.stages.is_none()));
+        if overloaded { "with these arguments is not available" } else { "is not available" }
+    }
+
+    /// Checks what is only valid in some stages: the builtins helper functions
+    /// use, and the writes to varyings and `DEPTH`. What a helper does is
+    /// checked against every stage that calls it, naming the calls between.
+    pub fn check_stages(&mut self, graph: &CallGraph, functions: &[&FunctionNode]) {
+        self.check_builtin_uses(graph);
+        self.check_discards(graph, functions);
+        if !matches!(self.shader_type, ShaderType::Spatial | ShaderType::CanvasItem) {
+            return;
+        }
+        self.check_varying_writes(graph);
+        if self.shader_type == ShaderType::Spatial {
+            self.check_depth_writes(graph, functions);
+        }
+    }
+
+    fn check_builtin_uses(&mut self, graph: &CallGraph) {
+        let stages = shader_stages(self.shader_type);
+        let stage_builtins: HashMap<&str, Vec<(String, ValueInfo)>> = stages
+            .iter()
+            .map(|x| (*x, stage_variables(self.shader_type, x)))
+            .collect();
+
+        let mut errors = vec![];
+        for builtin_use in &self.builtin_uses {
+            let function = if let Some(x) = graph.function_at(builtin_use.range.start) { x } else { continue };
+            for stage in graph.reaching_stages(function, stages) {
+                let stage_name = graph.functions[stage].name.as_str();
+                let problem = if let Some(tags) = builtin_use.call_stages {
+                    if tags.contains(&(self.shader_type, stage_name)) {
+                        continue;
+                    }
+                    self.unavailable_call(&builtin_use.name)
+                } else {
+                    let builtin = stage_builtins[stage_name].iter().find(|x| x.0 == builtin_use.name);
+                    match builtin {
+                        None => "is not available",
+                        Some((_, info)) if builtin_use.written && !info.editable => "is read-only",
+                        _ => continue
+                    }
+                };
+                let message = format!(
+                    "'{}' {} in the '{}' function, which calls '{}' ({}).",
+                    builtin_use.name,
+                    problem,
+                    stage_name,
+                    graph.functions[function].name,
+                    graph.call_chain(stage, function).map_or(String::new(), |x| graph.describe_chain(&x))
+                );
+                errors.push((message, builtin_use.range));
+                break;
+            }
+        }
+        for (message, range) in errors {
+            _ = self.alert_error(&message, range);
+        }
+    }
+
+    /// `discard` only makes sense for a fragment, so it may be used in the
+    /// fragment stage and the helpers that no other stage calls.
+    fn check_discards(&mut self, graph: &CallGraph, functions: &[&FunctionNode]) {
+        let mut errors = vec![];
+        for (function, node) in functions.iter().enumerate() {
+            let mut discards = vec![];
+            for statement in &node.block.statements {
+                collect_discards(statement, &mut discards);
+            }
+            if discards.is_empty() {
+                continue;
+            }
+            let stage = graph
+                .reaching_stages(function, shader_stages(self.shader_type))
+                .into_iter()
+                .find(|&x| graph.functions[x].name != "fragment");
+            let stage = if let Some(stage) = stage { stage } else { continue };
+
+            let message = if stage == function {
+                "'discard' can only be used in the 'fragment' function.".to_string()
+            } else {
+                format!(
+                    "'discard' cannot be used in the '{}' function, which calls '{}' ({}).",
+                    graph.functions[stage].name,
+                    graph.functions[function].name,
+                    graph.call_chain(stage, function).map_or(String::new(), |x| graph.describe_chain(&x))
+                )
+            };
+            errors.extend(discards.into_iter().map(|x| (message.clone(), x.range)));
+        }
+        for (message, range) in errors {
+            _ = self.alert_error(&message, range);
+        }
+    }
+
+    fn check_varying_writes(&mut self, graph: &CallGraph) {
+        let varyings: HashMap<(Option<String>, [u32; 4]), String> = self.scopes.scopes
+            .first()
+            .into_iter()
+            .flat_map(|x| &x.values)
+            .filter(|(_, x)| !x.is_const && x.editable)
+            .filter_map(|(name, x)| Some(((x.include_path.clone(), range_key(x.range?)), name.clone())))
+            .collect();
+
+        let mut errors = vec![];
+        for reference in self.references.iter().filter(|x| x.kind == ReferenceKind::Write) {
+            let key = (reference.declaration.include_path.clone(), range_key(reference.declaration.range));
+            let (name, function) = match (varyings.get(&key), graph.function_at(reference.range.start)) {
+                (Some(name), Some(function)) => (name, function),
+                _ => continue
+            };
+            let stage = graph
+                .reaching_stages(function, shader_stages(self.shader_type))
+                .into_iter()
+                .find(|&x| !VARYING_WRITERS.contains(&graph.functions[x].name.as_str()));
+            let stage = if let Some(stage) = stage { stage } else { continue };
+
+            let message = if stage == function {
+                format!("Varying '{}' cannot be written in the '{}' function.", name, graph.functions[stage].name)
+            } else {
+                format!(
+                    "Varying '{}' cannot be written in the '{}' function, which calls '{}' ({}).",
+                    name,
+                    graph.functions[stage].name,
+                    graph.functions[function].name,
+                    graph.call_chain(stage, function).map_or(String::new(), |x| graph.describe_chain(&x))
+                )
+            };
+            errors.push((message, reference.range));
+        }
+        for (message, range) in errors {
+            _ = self.alert_error(&message, range);
+        }
+    }
+
+    /// The depth of a fragment is undefined on any path that does not write
+    /// `DEPTH`, once some path does. The writes may be made by the helpers
+    /// the fragment stage calls, and a call to a helper that writes it on
+    /// every path is a write.
+    fn check_depth_writes(&mut self, graph: &CallGraph, functions: &[&FunctionNode]) {
+        let fragment = if let Some(x) = graph.find("fragment") { x } else { return };
+        let reached = std::iter::once(fragment).chain(
+            (0..functions.len()).filter(|&x| x != fragment && !graph.reaching_stages(x, &["fragment"]).is_empty())
+        );
+        let first_write = reached
+            .flat_map(|x| {
+                let mut expressions = vec![];
+                for statement in &functions[x].block.statements {
+                    collect_expressions(statement, &mut expressions);
+                }
+                expressions
+            })
+            .find_map(|x| self.find_write(x, "DEPTH"));
+        let range = if let Some(range) = first_write { range } else { return };
+
+        let mut flow = WriteFlow {
+            memory: self,
+            functions,
+            name: "DEPTH",
+            callees: graph.calls.iter().map(|x| (range_key(x.range), x.callee)).collect(),
+            always: vec![None; functions.len()]
+        };
+        if !flow.function_always_writes(fragment) {
+            let message = "'DEPTH' is only written on some paths, and is undefined on the others.";
+            self.source.push_warning(message, range);
+        }
+    }
+
+    /// The first assignment or increment of the variable `name` in `expression`.
+    fn find_write(&self, expression: &ExpressionNode, name: &str) -> Option<Range> {
+        let is_target = |target: &ExpressionNode| {
+            base_identifier(target).is_some_and(|x| self.get_token_text(x) == name)
+        };
+        match expression {
+            ExpressionNode::Assignment(x) => {
+                if is_target(&x.left) {
+                    return Some(x.left.range());
+                }
+                self.find_write(&x.left, name).or_else(|| self.find_write(&x.right, name))
+            }
+            ExpressionNode::Increment(x) => {
+                if is_target(&x.arg) { Some(x.arg.range()) } else { self.find_write(&x.arg, name) }
+            }
+            ExpressionNode::Unary(x) => self.find_write(&x.arg, name),
+            ExpressionNode::Binary(x) => self.find_write(&x.left, name).or_else(|| self.find_write(&x.right, name)),
+            ExpressionNode::Paren(x) => self.find_write(x, name),
+            ExpressionNode::Conditional(x) => self.find_write(&x.condition, name)
+                .or_else(|| self.find_write(&x.action, name))
+                .or_else(|| self.find_write(&x.alternate, name)),
+            ExpressionNode::Call(x) => x.args.iter().find_map(|x| self.find_write(&x.expression, name)),
+            ExpressionNode::ArrayAccess(x) => self.find_write(&x.argument, name)
+                .or_else(|| self.find_write(&x.index, name)),
+            ExpressionNode::MemberAccess(x) => self.find_write(&x.argument, name),
+            ExpressionNode::ArrayLiteral(x) => x.iter().find_map(|x| self.find_write(x, name)),
+            ExpressionNode::Primitive(_) | ExpressionNode::Identifier(_) => None,
+        }
+    }
+}
+
+/// How the paths through some statements leave them, with regard to writing
+/// a variable.
+#[derive(Clone, Copy, Default)]
+struct Flow {
+    /// Every path that leaves the statements has written the variable, or
+    /// discarded the fragment.
+    writes: bool,
+    /// Some path returns without having written it.
+    returns: bool,
+    /// Some path breaks out or continues without having written it.
+    breaks: bool,
+}
+
+/// Finds whether every path through functions writes a variable, counting
+/// calls to the functions that always write it.
+struct WriteFlow<'a> {
+    memory: &'a Memory,
+    functions: &'a [&'a FunctionNode],
+    name: &'a str,
+    callees: HashMap<[u32; 4], usize>,
+    /// Whether each function always writes the variable, once known.
+    always: Vec<Option<bool>>,
+}
+
+impl WriteFlow<'_> {
+    fn function_always_writes(&mut self, function: usize) -> bool {
+        if let Some(always) = self.always[function] {
+            return always;
+        }
+        // A recursive call does not count while the function is worked out.
+        self.always[function] = Some(false);
+        let always = self.statements(&self.functions[function].block.statements).writes;
+        self.always[function] = Some(always);
+        always
+    }
+
+    fn writes(&mut self, expression: &ExpressionNode) -> bool {
+        if self.memory.find_write(expression, self.name).is_some() {
+            return true;
+        }
+        let mut calls = vec![];
+        collect_calls(expression, &mut calls);
+        calls.into_iter().any(|x| {
+            let callee = self.callees.get(&range_key(x.range)).copied();
+            callee.is_some_and(|x| self.function_always_writes(x))
+        })
+    }
+
+    /// The statements run in order, so they write the variable once one of
+    /// them does, unless an earlier one may leave them first.
+    fn statements<'b>(&mut self, statements: impl IntoIterator<Item = &'b StatementNode>) -> Flow {
+        let mut flow = Flow::default();
+        for statement in statements {
+            let statement = self.statement(statement);
+            flow.returns |= statement.returns;
+            flow.breaks |= statement.breaks;
+            if statement.writes {
+                flow.writes = !flow.returns && !flow.breaks;
+                return flow;
+            }
+        }
+        flow
+    }
+
+    fn statement(&mut self, statement: &StatementNode) -> Flow {
+        let written = Flow { writes: true, ..Default::default() };
+        match statement {
+            StatementNode::Expression(x) => Flow { writes: self.writes(x), ..Default::default() },
+            StatementNode::VarDeclaration(x) => {
+                Flow { writes: x.expression.as_ref().is_some_and(|x| self.writes(x)), ..Default::default() }
+            }
+            StatementNode::Block(x) => self.statements(&x.statements),
+            StatementNode::If(x) => {
+                if self.writes(&x.condition) {
+                    return written;
+                }
+                let action = self.statement(&x.action);
+                let alternate = match &x.alternate {
+                    Some(x) => self.statement(&x.action),
+                    None => Flow::default()
+                };
+                Flow {
+                    writes: action.writes && alternate.writes,
+                    returns: action.returns || alternate.returns,
+                    breaks: action.breaks || alternate.breaks
+                }
+            }
+            // The body of a loop may not run at all, and breaking out of it
+            // only leaves the loop.
+            StatementNode::While(x) => {
+                if self.writes(&x.condition) {
+                    return written;
+                }
+                Flow { returns: self.statement(&x.action).returns, ..Default::default() }
+            }
+            StatementNode::For(x) => {
+                if self.statement(&x.initializer).writes || self.writes(&x.condition) {
+                    return written;
+                }
+                Flow { returns: self.statement(&x.action).returns, ..Default::default() }
+            }
+            StatementNode::DoWhile(x) => {
+                let action = self.statement(&x.action);
+                let leaves = action.returns || action.breaks;
+                let writes = action.writes || (!leaves && self.writes(&x.condition));
+                Flow { writes, returns: action.returns && !writes, breaks: false }
+            }
+            // Each case runs on into the next one until it breaks.
+            StatementNode::Switch(x) => {
+                if self.writes(&x.condition) {
+                    return written;
+                }
+                let has_default = x.cases.iter().any(|x| x.keyword.kind == TokenKind::Default);
+                let runs: Vec<Flow> = (0..x.cases.len())
+                    .map(|idx| self.statements(x.cases[idx..].iter().flat_map(|x| &x.statements)))
+                    .collect();
+                Flow {
+                    writes: has_default && runs.iter().all(|x| x.writes),
+                    returns: runs.iter().any(|x| x.returns),
+                    breaks: false
+                }
+            }
+            // A discarded fragment needs nothing written.
+            StatementNode::Discard(_) => written,
+            StatementNode::Return(x) => {
+                let writes = x.expression.as_ref().is_some_and(|x| self.writes(x));
+                Flow { writes, returns: !writes, breaks: false }
+            }
+            StatementNode::Break(_) | StatementNode::Continue(_) => Flow { breaks: true, ..Default::default() },
+        }
+    }
+}
+
+/// Every expression in a statement, including those of nested statements.
+pub(super) fn collect_expressions<'a>(statement: &'a StatementNode, expressions: &mut Vec<&'a ExpressionNode>) {
+    match statement {
+        StatementNode::Expression(x) => expressions.push(x),
+        StatementNode::VarDeclaration(x) => expressions.extend(x.expression.as_deref()),
+        StatementNode::Block(x) => x.statements.iter().for_each(|x| collect_expressions(x, expressions)),
+        StatementNode::If(x) => {
+            expressions.push(&x.condition);
+            collect_expressions(&x.action, expressions);
+            if let Some(alternate) = &x.alternate {
+                collect_expressions(&alternate.action, expressions);
+            }
+        }
+        StatementNode::While(x) => {
+            expressions.push(&x.condition);
+            collect_expressions(&x.action, expressions);
+        }
+        StatementNode::DoWhile(x) => {
+            collect_expressions(&x.action, expressions);
+            expressions.push(&x.condition);
+        }
+        StatementNode::For(x) => {
+            collect_expressions(&x.initializer, expressions);
+            expressions.push(&x.condition);
+            expressions.push(&x.update);
+            collect_expressions(&x.action, expressions);
+        }
+        StatementNode::Switch(x) => {
+            expressions.push(&x.condition);
+            for statement in x.cases.iter().flat_map(|x| &x.statements) {
+                collect_expressions(statement, expressions);
+            }
+        }
+        StatementNode::Return(x) => expressions.extend(x.expression.as_deref()),
+        StatementNode::Break(_) | StatementNode::Continue(_) | StatementNode::Discard(_) => {}
+    }
+}
+
+/// Every `discard` in a statement, including those of nested statements.
+fn collect_discards(statement: &StatementNode, discards: &mut Vec<Token>) {
+    match statement {
+        StatementNode::Discard(x) => discards.push(*x),
+        StatementNode::Block(x) => x.statements.iter().for_each(|x| collect_discards(x, discards)),
+        StatementNode::If(x) => {
+            collect_discards(&x.action, discards);
+            if let Some(alternate) = &x.alternate {
+                collect_discards(&alternate.action, discards);
+            }
+        }
+        StatementNode::While(x) => collect_discards(&x.action, discards),
+        StatementNode::DoWhile(x) => collect_discards(&x.action, discards),
+        StatementNode::For(x) => collect_discards(&x.action, discards),
+        StatementNode::Switch(x) => {
+            for statement in x.cases.iter().flat_map(|x| &x.statements) {
+                collect_discards(statement, discards);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The variable that an assignment to `target` writes, as in `x.y[2] = ...`.
+fn base_identifier(target: &ExpressionNode) -> Option<Token> {
+    match target {
+        ExpressionNode::Identifier(x) => Some(*x),
+        ExpressionNode::Paren(x) => base_identifier(x),
+        ExpressionNode::ArrayAccess(x) => base_identifier(&x.argument),
+        ExpressionNode::MemberAccess(x) => base_identifier(&x.argument),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{line_index::PositionEncoding, memory::Memory};
+
+    fn stage_errors(code: &str) -> Vec<String> {
+        let mut memory = Memory::new(code, None, PositionEncoding::default());
+        memory.evaluate_new(None)
+            .iter()
+            .filter(|x| x.message.contains("not available"))
+            .map(|x| x.message.clone())
+            .collect()
+    }
+
+    fn depth_warnings(code: &str) -> usize {
+        let code = format!("shader_type spatial;\n{}\n", code);
+        let mut memory = Memory::new(&code, None, PositionEncoding::default());
+        memory.evaluate_new(None)
+            .iter()
+            .filter(|x| x.message.contains("'DEPTH' is only written on some paths"))
+            .count()
+    }
+
+    #[test]
+    fn depth_written_on_every_path_is_not_reported() {
+        assert_eq!(depth_warnings("void fragment() { DEPTH = 1.0; }"), 0);
+        assert_eq!(depth_warnings("void fragment() { if (FRAGCOORD.x > 0.0) { DEPTH = 1.0; } else { DEPTH = 0.0; } }"), 0);
+        assert_eq!(depth_warnings("void fragment() { if (FRAGCOORD.x > 0.0) DEPTH = 1.0; else discard; }"), 0);
+        assert_eq!(depth_warnings("void fragment() { if (FRAGCOORD.x > 0.0) { discard; } DEPTH = 1.0; }"), 0);
+    }
+
+    #[test]
+    fn depth_skipped_by_a_path_is_reported() {
+        assert_eq!(depth_warnings("void fragment() { if (FRAGCOORD.x > 0.0) { DEPTH = 1.0; } }"), 1);
+        assert_eq!(depth_warnings("void fragment() { if (FRAGCOORD.x > 0.0) return; DEPTH = 1.0; }"), 1);
+        assert_eq!(
+            depth_warnings("void fragment() { for (int i = 0; i < 2; i++) { if (i == 1) { return; } } DEPTH = 1.0; }"),
+            1
+        );
+        assert_eq!(depth_warnings("void fragment() { switch (int(FRAGCOORD.x)) { case 0: DEPTH = 1.0; break; } }"), 1);
+    }
+
+    #[test]
+    fn depth_written_by_a_helper_is_checked() {
+        assert_eq!(depth_warnings("void write() { DEPTH = 1.0; }\nvoid fragment() { write(); }"), 0);
+        assert_eq!(
+            depth_warnings("void write() { DEPTH = 1.0; }\nvoid fragment() { if (FRAGCOORD.x > 0.0) { write(); } }"),
+            1
+        );
+        assert_eq!(
+            depth_warnings("void write() { if (FRAGCOORD.x > 0.0) { DEPTH = 1.0; } }\nvoid fragment() { write(); }"),
+            1
+        );
+    }
+
+    #[test]
+    fn builtins_are_checked_against_the_calling_stage() {
+        let code = "shader_type spatial;
+uniform sampler2D t;
+void vertex() { float a = dFdx(VERTEX.x); }
+void fragment() { float a = dFdx(FRAGCOORD.x); }
+";
+        assert_eq!(stage_errors(code), vec!["'dFdx' is not available in the 'vertex' function."]);
+
+        // Only the overload with a bias needs the neighbouring fragments.
+        let code = "shader_type spatial;
+uniform sampler2D t;
+void vertex() { vec4 a = texture(t, UV); vec4 b = texture(t, UV, 1.0); }
+";
+        assert_eq!(
+            stage_errors(code),
+            vec!["'texture' with these arguments is not available in the 'vertex' function."]
+        );
+
+        let code = "shader_type spatial;
+void fragment() { emit_subparticle(mat4(1.0), vec3(0.0), vec4(0.0), vec4(0.0), 0u); }
+";
+        assert_eq!(stage_errors(code), vec!["'emit_subparticle' is not available in 'spatial' shaders."]);
+    }
+
+    #[test]
+    fn builtins_in_helpers_are_checked_against_the_stages_calling_them() {
+        let code = "shader_type canvas_item;
+float helper() { return texture_sdf(vec2(0.0)); }
+void vertex() { VERTEX.x = helper(); }
+void fragment() { COLOR.a = helper(); }
+";
+        assert_eq!(
+            stage_errors(code),
+            vec!["'texture_sdf' is not available in the 'vertex' function, which calls 'helper' (vertex -> helper)."]
+        );
+    }
+}
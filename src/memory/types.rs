@@ -19,6 +19,9 @@ pub enum GenericSize {
     GSampler2D,
     GSampler2DArray,
     GSampler3D,
+    GSamplerCube,
+    GSamplerCubeArray,
+    GSamplerExternal,
 }
 impl GenericSize {
     pub fn as_size(&self) -> Option<u32> {
@@ -30,6 +33,9 @@ impl GenericSize {
             GenericSize::GSampler2D => None,
             GenericSize::GSampler2DArray => None,
             GenericSize::GSampler3D => None,
+            GenericSize::GSamplerCube => None,
+            GenericSize::GSamplerCubeArray => None,
+            GenericSize::GSamplerExternal => None,
         } 
     }
 }
@@ -92,6 +98,10 @@ impl TypeInfo {
             (Uint, GSampler2D) => Self { base: "usampler2D".to_string(), size: 0 },
             (Uint, GSampler2DArray) => Self { base: "usampler2DArray".to_string(), size: 0 },
             (Uint, GSampler3D) => Self { base: "usampler3D".to_string(), size: 0 },
+            // Cubemaps and external textures are only ever read as float.
+            (Float, GSamplerCube) => Self { base: "samplerCube".to_string(), size: 0 },
+            (Float, GSamplerCubeArray) => Self { base: "samplerCubeArray".to_string(), size: 0 },
+            (Float, GSamplerExternal) => Self { base: "samplerExternalOES".to_string(), size: 0 },
             _ => panic!()
         }
     }
@@ -122,6 +132,7 @@ impl TypeInfo {
             "sampler3D" => Some(Primitive::Float),
             "samplerCube" => Some(Primitive::Float),
             "samplerCubeArray" => Some(Primitive::Float),
+            "samplerExternalOES" => Some(Primitive::Float),
             "isampler2D" => Some(Primitive::Int),
             "isampler2DArray" => Some(Primitive::Int),
             "isampler3D" => Some(Primitive::Int),
@@ -148,7 +159,7 @@ impl TypeInfo {
             "vec4" => Some(GenericSize::GVec4Type),
             "ivec4" => Some(GenericSize::GVec4Type),
             "uvec4" => Some(GenericSize::GVec4Type),
-            "gvec4_type" => Some(GenericSize::GVec3Type),
+            "gvec4_type" => Some(GenericSize::GVec4Type),
             "sampler2D" => Some(GenericSize::GSampler2D),
             "isampler2D" => Some(GenericSize::GSampler2D),
             "usampler2D" => Some(GenericSize::GSampler2D),
@@ -161,6 +172,12 @@ impl TypeInfo {
             "isampler3D" => Some(GenericSize::GSampler3D),
             "usampler3D" => Some(GenericSize::GSampler3D),
             "gsampler3D" => Some(GenericSize::GSampler3D),
+            "samplerCube" => Some(GenericSize::GSamplerCube),
+            "gsamplerCube" => Some(GenericSize::GSamplerCube),
+            "samplerCubeArray" => Some(GenericSize::GSamplerCubeArray),
+            "gsamplerCubeArray" => Some(GenericSize::GSamplerCubeArray),
+            "samplerExternalOES" => Some(GenericSize::GSamplerExternal),
+            "gsamplerExternalOES" => Some(GenericSize::GSamplerExternal),
             _ => None 
         }
     }
@@ -174,6 +191,9 @@ impl TypeInfo {
             "gsampler2D" => Some(GenericSize::GSampler2D),
             "gsampler2DArray" => Some(GenericSize::GSampler2DArray),
             "gsampler3D" => Some(GenericSize::GSampler3D),
+            "gsamplerCube" => Some(GenericSize::GSamplerCube),
+            "gsamplerCubeArray" => Some(GenericSize::GSamplerCubeArray),
+            "gsamplerExternalOES" => Some(GenericSize::GSamplerExternal),
             _ => None,
         }
     }
@@ -236,6 +256,8 @@ impl PartialEq for TypeInfo {
             "gsampler2DArray" => if ["sampler2DArray", "isampler2DArray", "usampler2DArray"].contains(&other_base) {
                 return true;
             }
+            // Cubemaps and external textures only come as float samplers.
+            "gsamplerCube" | "gsamplerCubeArray" | "gsamplerExternalOES" if base[1..] == *other_base => return true,
             _ => {}
         }
         match other_base {
@@ -260,10 +282,10 @@ impl PartialEq for TypeInfo {
             "gvec2_type" => if ["vec2", "ivec2", "uvec2"].contains(&base) {
                 return true;
             }
-            "gvec3_type" => if ["vec2", "ivec2", "uvec2"].contains(&base) {
+            "gvec3_type" => if ["vec3", "ivec3", "uvec3"].contains(&base) {
                 return true;
             }
-            "gvec4_type" => if ["vec2", "ivec2", "uvec2"].contains(&base) {
+            "gvec4_type" => if ["vec4", "ivec4", "uvec4"].contains(&base) {
                 return true;
             }
             "gsampler2D" => if ["sampler2D", "isampler2D", "usampler2D"].contains(&base) {
@@ -275,6 +297,8 @@ impl PartialEq for TypeInfo {
             "gsampler2DArray" => if ["sampler2DArray", "isampler2DArray", "usampler2DArray"].contains(&base) {
                 return true;
             }
+            // Cubemaps and external textures only come as float samplers.
+            "gsamplerCube" | "gsamplerCubeArray" | "gsamplerExternalOES" if other_base[1..] == *base => return true,
             _ => {}
         }
         false
@@ -340,6 +364,8 @@ pub fn make_builtin_types() -> HashMap<String, BuiltinTypeInfo> {
 "Sampler type for binding Cubemaps, which are read as float."),
         builtin_type!(samplerCubeArray false
 "Sampler type for binding Cubemap arrays, which are read as float."),
+        builtin_type!(samplerExternalOES false
+"External sampler type. Only supported in Compatibility/Android platform."),
     ])
 }
 
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+
+use lsp_types::Range;
+
+use crate::lexer::ExtraRange;
+
+use super::{range_key, shader_stages, CallGraph, Memory, ReferenceKind, ScopeType};
+
+impl Memory {
+    /// Finds the variables, parameters, varyings, uniforms, constants, structs
+    /// and functions declared in the document that are never read, along with
+    /// the range of their identifier. Writes only count for parameters, since
+    /// an `out` parameter is written for the caller to read. Calls to functions
+    /// declared further down are taken from `graph`. The stages Godot calls for
+    /// the shader type, and names starting with `_`, are never reported, nor
+    /// are the top-level declarations of an include, which the shaders
+    /// including it use.
+    pub fn find_unused_declarations(&self, graph: &CallGraph) -> Vec<(String, Range)> {
+        let is_include = self.uri.as_ref().is_some_and(|x| x.path().ends_with(".gdshaderinc"));
+        let mut names: HashMap<[u32; 4], (String, bool)> = HashMap::new();
+        for (idx, scope) in self.scopes.scopes.iter().enumerate() {
+            if is_include && idx == 0 {
+                continue;
+            }
+            for (name, value) in &scope.values {
+                let range = match value.range {
+                    Some(range) if value.include_path.is_none() => range,
+                    _ => continue
+                };
+                let is_parameter = matches!(scope.scope_type, ScopeType::Function(_))
+                    && !scope.range.contains_position(range.start);
+                names.insert(range_key(range), (name.clone(), is_parameter));
+            }
+        }
+        if !is_include {
+            let entry_points = shader_stages(self.shader_type);
+            for (name, function) in &self.functions {
+                if let Some(range) = function.range.filter(|_| function.include_path.is_none()) {
+                    if !entry_points.contains(&name.as_str()) {
+                        names.insert(range_key(range), (name.clone(), false));
+                    }
+                }
+            }
+            for (name, info) in self.structs.iter().filter(|x| x.1.include_path.is_none()) {
+                names.insert(range_key(info.range), (name.clone(), false));
+            }
+        }
+
+        let mut used: HashSet<[u32; 4]> = self.references
+            .iter()
+            .filter(|x| x.declaration.include_path.is_none())
+            .filter(|x| match x.kind {
+                ReferenceKind::Declaration => false,
+                ReferenceKind::Read => true,
+                ReferenceKind::Write => names.get(&range_key(x.declaration.range)).is_some_and(|x| x.1)
+            })
+            .map(|x| range_key(x.declaration.range))
+            .collect();
+        used.extend(
+            graph.calls
+                .iter()
+                .filter(|x| x.caller != x.callee)
+                .map(|x| range_key(graph.functions[x.callee].identifier))
+        );
+
+        self.references
+            .iter()
+            .filter(|x| x.kind == ReferenceKind::Declaration && x.declaration.include_path.is_none())
+            .filter(|x| !used.contains(&range_key(x.declaration.range)))
+            .filter_map(|x| {
+                let (name, _) = names.get(&range_key(x.declaration.range))?;
+                (!name.starts_with('_')).then(|| (name.clone(), x.range))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{line_index::PositionEncoding, memory::Memory};
+
+    fn unused(code: &str) -> Vec<String> {
+        let mut memory = Memory::new(code, None, PositionEncoding::default());
+        memory.evaluate_new(None)
+            .iter()
+            .filter_map(|x| x.message.strip_suffix("' is unused.")?.strip_prefix('\'').map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn only_the_stages_of_the_shader_type_are_entry_points() {
+        let code = "shader_type spatial;
+void vertex() {}
+void fragment() {}
+void light() {}
+void start() {}
+void sky() {}
+";
+        assert_eq!(unused(code), vec!["start", "sky"]);
+        let code = "shader_type particles;
+void start() {}
+void process() {}
+void light() {}
+";
+        assert_eq!(unused(code), vec!["light"]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, hash::Hash};
 
-use super::{TypeInfo, ValueInfo};
+use super::{ShaderType, TypeInfo, ValueInfo};
 
 macro_rules! builtin_variable {
     ((const $type:ident $name:ident $description:literal)) => {
@@ -15,6 +15,7 @@ macro_rules! builtin_variable {
             editable: !$const,
             is_const: $const,
             range: None,
+            include_path: None,
             description: Some($description.to_string())
         })
     };
@@ -38,6 +39,33 @@ macro_rules! var_hashmap {
 //}
 
 
+/// The functions Godot calls for a shader type, one for each stage.
+pub fn shader_stages(shader_type: ShaderType) -> &'static [&'static str] {
+    match shader_type {
+        ShaderType::Spatial | ShaderType::CanvasItem => &["vertex", "fragment", "light"],
+        ShaderType::Particles => &["start", "process"],
+        ShaderType::Sky => &["sky"],
+        ShaderType::Fog => &["fog"],
+    }
+}
+
+/// The builtin variables a stage adds to the global ones.
+pub fn stage_variables(shader_type: ShaderType, stage: &str) -> Vec<(String, ValueInfo)> {
+    match (shader_type, stage) {
+        (ShaderType::Spatial, "vertex") => spatial_vertex_vars(),
+        (ShaderType::Spatial, "fragment") => spatial_fragment_vars(),
+        (ShaderType::Spatial, "light") => spatial_light_vars(),
+        (ShaderType::CanvasItem, "vertex") => canvas_item_vertex_vars(),
+        (ShaderType::CanvasItem, "fragment") => canvas_item_fragment_vars(),
+        (ShaderType::CanvasItem, "light") => canvas_item_light_vars(),
+        (ShaderType::Particles, "start") => [particle_start_process(), particle_start()].concat(),
+        (ShaderType::Particles, "process") => [particle_start_process(), particle_process()].concat(),
+        (ShaderType::Sky, "sky") => sky_stuff(),
+        (ShaderType::Fog, "fog") => fog_stuff(),
+        _ => vec![]
+    }
+}
+
 pub fn variable_builtins() -> Vec<(String, ValueInfo)> {
     var_hashmap!(
         (const float TIME
@@ -114,6 +114,8 @@ pub struct ArrayAccessNode {
 #[derive(Clone, Debug)]
 pub struct MemberAccessNode {
     pub argument: Box<ExpressionNode>,
-    pub member: Token 
+    pub member: Token,
+    /// Set when the member is called as a method, as in `arr.length()`.
+    pub args: Option<Vec<CallArgumentNode>>
 }
 
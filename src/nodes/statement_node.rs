@@ -10,11 +10,13 @@ pub enum StatementNode {
     VarDeclaration(VarDeclarationNode),
     If(IfNode),
     While(WhileNode),
+    DoWhile(DoWhileNode),
     For(ForNode),
     Switch(SwitchNode),
     Expression(Box<ExpressionNode>),
     Continue(Token),
     Break(Token),
+    Discard(Token),
     Return(ReturnNode),
     Block(BlockNode),
 }
@@ -54,6 +56,13 @@ pub struct WhileNode {
     pub action: Box<StatementNode>
 }
 
+#[derive(Clone, Debug)]
+pub struct DoWhileNode {
+    pub keyword: Token,
+    pub action: Box<StatementNode>,
+    pub condition: Box<ExpressionNode>
+}
+
 #[derive(Clone, Debug)]
 pub struct ForNode {
     pub keyword: Token,
@@ -67,7 +76,9 @@ pub struct ForNode {
 pub struct SwitchNode {
     pub keyword: Token,
     pub condition: Box<ExpressionNode>,
-    pub cases: Vec<SwitchCaseNode>
+    pub cases: Vec<SwitchCaseNode>,
+    /// From the opening brace of the cases to the closing one.
+    pub range: Range
 }
 
 #[derive(Clone, Debug)]
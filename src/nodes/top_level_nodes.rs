@@ -83,6 +83,7 @@ pub struct FunctionNode {
 }
 #[derive(Clone, Debug)]
 pub struct ParamNode {
+    pub const_keyword: Option<Token>,
     pub qualifier: Option<Token>,
     pub value_node: ValueNode,
 }
@@ -3,7 +3,7 @@ use super::*;
 pub fn parse_expression(
     stream: &mut TokenStream,
 ) -> ExpressionResult {
-    let mut original = parse_expression_priority(stream)?;
+    let mut original = parse_expression_priority(stream, false)?;
     loop {
         if let Some(op) = parse_binary_operation(stream) {
             original = parse_binary_expression(stream, op, original)?
@@ -15,14 +15,18 @@ pub fn parse_expression(
     }
 }
 
+/// Parses an operand with its postfix operators. `negated` is set when it
+/// follows a unary minus, which lets an int literal reach `-2147483648`.
 pub fn parse_expression_priority(
     stream: &mut TokenStream,
+    negated: bool,
 ) -> ExpressionResult {
     if !stream.parsing_statement {
         stream.queue_cursor_element(CompletionElement::Identifier(stream.parsing_const));
     }
 
     let mut original = if let Some(primitive) = parse_primitive(stream) {
+        check_int_range(stream, primitive, negated);
         ExpressionNode::Primitive(primitive)
     } else if let Some(op) = parse_unary_operation(stream){
         parse_unary_expression(stream, op)?
@@ -80,7 +84,7 @@ pub fn parse_call_expression(
 }
 
 pub fn parse_unary_expression(stream: &mut TokenStream, op: Token) -> ExpressionResult {
-    let arg = Box::new(parse_expression_priority(stream)?);
+    let arg = Box::new(parse_expression_priority(stream, op.kind == Dash)?);
     Ok(ExpressionNode::Unary(UnaryNode{ arg, op }))
 }
 
@@ -89,7 +93,7 @@ pub fn parse_binary_expression(
     op: Token,
     original: ExpressionNode
 ) -> ExpressionResult {
-    let right = parse_expression_priority(stream)?; 
+    let right = parse_expression_priority(stream, false)?; 
 
     Ok(ExpressionNode::Binary(BinaryNode{
         left: Box::new(original),
@@ -133,8 +137,6 @@ pub fn parse_parenthetical_expression(stream: &mut TokenStream) -> ExpressionRes
     stream.advance();
     let expr = Box::new(parse_expression(stream)?);
     parse_kind(stream, RightParen)?;
-    eprintln!("current: {:?}", stream.cursor_element);
-
     Ok(ExpressionNode::Paren(expr))
 }
 
@@ -175,10 +177,27 @@ pub fn parse_member_access_expression(
     stream.queue_cursor_element(CompletionElement::Member(Box::new(original.clone())));
     stream.advance();
     let member = parse_identifier(stream)?;
+    let args = match stream.current()?.kind {
+        LeftParen => {
+            stream.advance();
+            let args = parse_list(
+                stream,
+                Comma,
+                RightParen,
+                Trailing::Optional,
+                |s| parse_expression(s)
+                    .map(|e| CallArgumentNode{qualifier: None, expression: e})
+            )?;
+            stream.advance();
+            Some(args)
+        }
+        _ => None
+    };
 
     Ok(ExpressionNode::MemberAccess(MemberAccessNode{
         argument: Box::new(original),
-        member
+        member,
+        args
     }))
 }
 
@@ -192,6 +211,7 @@ pub fn parse_array_literal_expression(stream: &mut TokenStream) -> ExpressionRes
         Trailing::Optional,
         |s| parse_expression(s) 
     )?;
+    stream.advance();
 
     Ok(ExpressionNode::ArrayLiteral(vec))
 }
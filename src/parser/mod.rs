@@ -81,20 +81,14 @@ pub fn parse_number(stream: &mut TokenStream) -> Result<Token, TokenError> {
 }
 
 pub fn parse_positive_int(stream: &mut TokenStream) -> Option<u32> {
-    let lines = stream.get_source().get_code();
+    let source = stream.get_source();
     if stream.current().is_err() { return None }
 
     let current = stream.current().unwrap();
     match current.kind {
-        IntConstant => match current.text(lines).parse::<u32>() {
-            Ok(x) => stream.advance_with(Some(x)),
-            _ => None
-        },
-        UintConstant => {
-            let text = current.text(lines);
-            let without_u = &text[..text.len() - 1];
-            match without_u.parse::<u32>() {
-                Ok(x) => stream.advance_with(Some(x)),
+        IntConstant | UintConstant => {
+            match int_literal_value(&source.token_text(current)).and_then(|x| u32::try_from(x).ok()) {
+                Some(x) => stream.advance_with(Some(x)),
                 _ => None
             }
         }
@@ -102,16 +96,42 @@ pub fn parse_positive_int(stream: &mut TokenStream) -> Option<u32> {
     }
 }
 
+/// Reports an int or uint constant whose value does not fit in its type. A
+/// hexadecimal int may use all 32 bits, which give its two's complement.
+pub fn check_int_range(stream: &mut TokenStream, token: Token, negated: bool) {
+    let text = stream.get_source().token_text(token);
+    let value = int_literal_value(&text);
+    let is_hex = text.starts_with("0x") || text.starts_with("0X");
+    // Only a decimal int reaches one further below zero than above it.
+    let negated_int = negated && !is_hex && token.kind == IntConstant;
+    let (type_name, max) = match token.kind {
+        IntConstant if is_hex => ("int", u32::MAX as u64),
+        IntConstant if negated_int => ("int", i32::MAX as u64 + 1),
+        IntConstant => ("int", i32::MAX as u64),
+        UintConstant => ("uint", u32::MAX as u64),
+        _ => return
+    };
+    if value.is_none_or(|x| x > max) {
+        let message = if negated_int {
+            format!("'-{}' is out of range for '{}', whose smallest value is -{}.", text, type_name, max)
+        } else {
+            format!("'{}' is out of range for '{}', whose largest value is {}.", text, type_name, max)
+        };
+        _ = stream.alert_error(&message, token.range);
+    }
+}
+
 pub fn parse_size(stream: &mut TokenStream) -> Result<(u32, Option<Range>), TokenError> {
     let message = "Expected positive integer constant.";
-    if stream.consume_if(|x| x.kind == LeftBracket).is_some() {
+    if let Some(left) = stream.consume_if(|x| x.kind == LeftBracket) {
         let range = stream.current()?.range;
         let size = match parse_positive_int(stream) {
             None => Err(stream.alert_error(message, range)),
             Some(x) if x == 0 => Err(stream.alert_error(message, range)),
             Some(x) => Ok(x)
         }?;
-        Ok((size, Some(range)))
+        let right = parse_kind(stream, RightBracket)?;
+        Ok((size, Some(Range::new(left.range.start, right.range.end))))
     } else {
         Ok((0, None))
     }
@@ -125,7 +145,7 @@ pub fn parse_type(stream: &mut TokenStream) -> Result<TypeNode, TokenError> {
         id.range.start,
         size_range.map_or(id.range.end, |x| x.end)
     );
-    let base = id.text(stream.get_source().get_code());
+    let base = stream.get_source().token_text(id);
     Ok(TypeNode { info: TypeInfo {base, size}, range })
 
 }
@@ -208,3 +228,50 @@ where
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use crate::{line_index::PositionEncoding, memory::Memory};
+
+    fn range_errors(expression: &str) -> Vec<String> {
+        let code = format!("shader_type spatial;\nvoid fragment() {{ {}; }}\n", expression);
+        let mut memory = Memory::new(&code, None, PositionEncoding::default());
+        memory.evaluate_new(None)
+            .iter()
+            .filter(|x| x.message.contains("out of range"))
+            .map(|x| x.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn int_literals_are_checked_against_their_range() {
+        assert!(range_errors("int a = -2147483648").is_empty());
+        assert_eq!(
+            range_errors("int a = -2147483649"),
+            vec!["'-2147483649' is out of range for 'int', whose smallest value is -2147483648."]
+        );
+        assert_eq!(
+            range_errors("int a = 2147483648"),
+            vec!["'2147483648' is out of range for 'int', whose largest value is 2147483647."]
+        );
+        assert!(range_errors("int a = 0xFFFFFFFF").is_empty());
+        assert_eq!(
+            range_errors("int a = 0x100000000"),
+            vec!["'0x100000000' is out of range for 'int', whose largest value is 4294967295."]
+        );
+    }
+
+    #[test]
+    fn uint_literals_are_checked_against_their_largest_value() {
+        assert!(range_errors("uint a = 4294967295u").is_empty());
+        assert_eq!(
+            range_errors("uint a = 4294967296u"),
+            vec!["'4294967296u' is out of range for 'uint', whose largest value is 4294967295."]
+        );
+        assert!(range_errors("uint a = -1u").is_empty());
+        assert_eq!(
+            range_errors("uint a = -5000000000u"),
+            vec!["'5000000000u' is out of range for 'uint', whose largest value is 4294967295."]
+        );
+    }
+}
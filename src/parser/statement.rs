@@ -30,10 +30,24 @@ pub fn parse_statement(stream: &mut TokenStream) -> StatementResult {
         Const => parse_var_declaration_statement(stream),
         If => parse_if_statement(stream),
         While => parse_while_statement(stream),
+        Do => parse_do_while_statement(stream),
         For => parse_for_statement(stream),
         Switch => parse_switch_statement(stream),
-        Continue => Ok(StatementNode::Continue(stream.consume()?)),
-        Break => Ok(StatementNode::Break(stream.consume()?)),
+        Continue => {
+            let keyword = stream.consume()?;
+            _ = parse_semicolon(stream);
+            Ok(StatementNode::Continue(keyword))
+        }
+        Break => {
+            let keyword = stream.consume()?;
+            _ = parse_semicolon(stream);
+            Ok(StatementNode::Break(keyword))
+        }
+        Discard => {
+            let keyword = stream.consume()?;
+            _ = parse_semicolon(stream);
+            Ok(StatementNode::Discard(keyword))
+        }
         Return => parse_return_statement(stream), 
         Identifier => parse_identifier_statement(stream),
         LeftBrace => parse_block(stream),
@@ -126,6 +140,23 @@ pub fn parse_while_statement(stream: &mut TokenStream) -> StatementResult {
     }))
 }
 
+pub fn parse_do_while_statement(stream: &mut TokenStream) -> StatementResult {
+    let keyword = stream.consume()?;
+    let action = Box::new(parse_statement(stream)?);
+    parse_kind(stream, While)?;
+    parse_kind(stream, LeftParen)?;
+    stream.parsing_statement = false;
+    let condition = Box::new(parse_expression(stream)?);
+    parse_kind(stream, RightParen)?;
+    _ = parse_semicolon(stream);
+
+    Ok(StatementNode::DoWhile(DoWhileNode {
+        keyword,
+        action,
+        condition
+    }))
+}
+
 pub fn parse_for_statement(stream: &mut TokenStream) -> StatementResult {
     let keyword = stream.consume()?;
     parse_kind(stream, LeftParen)?;
@@ -157,17 +188,18 @@ pub fn parse_switch_statement(stream: &mut TokenStream) -> StatementResult {
     parse_kind(stream, LeftParen)?;
     let condition = Box::new(parse_expression(stream)?); 
     parse_kind(stream, RightParen)?;
-    parse_kind(stream, LeftBrace)?;
+    let left = parse_kind(stream, LeftBrace)?;
     let mut cases = vec![];
     while stream.current()?.kind != RightBrace {
         cases.push(parse_switch_case(stream)?);
     }
-    stream.advance();
+    let right = parse_kind(stream, RightBrace)?;
 
     Ok(StatementNode::Switch(SwitchNode{
         keyword,
         condition,
-        cases
+        cases,
+        range: Range::new(left.range.start, right.range.end)
     }))
 }
 
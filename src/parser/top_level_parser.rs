@@ -210,9 +210,11 @@ pub fn parse_struct(stream: &mut TokenStream) -> TopLevelResult {
 }
 
 pub fn parse_function_arg(stream: &mut TokenStream) -> Result<ParamNode, TokenError> {
+    let const_keyword = parse_conditional(stream, Const);
     let qualifier = parse_qualifier(stream);
     let value_node = parse_value_specifier(stream)?;
     Ok(ParamNode {
+        const_keyword,
         qualifier,
         value_node
     })
@@ -224,7 +226,7 @@ pub fn parse_function(stream: &mut TokenStream) -> TopLevelResult {
     let type_node = match parse_conditional(stream, Void) {
         Some(x) => {
             is_void = true;
-            let text = x.text(stream.get_source().get_code());
+            let text = stream.get_source().token_text(x);
             TypeNode { info: TypeInfo::from_str(&text), range: x.range}
         }
         None => parse_type(stream)?,
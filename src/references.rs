@@ -0,0 +1,39 @@
+use lsp_types::*;
+
+use crate::{lexer::ExtraRange, memory::*};
+
+/// Finds the declaration of the symbol whose declaration or use sits under
+/// the cursor.
+pub fn find_declaration_at(memory: &Memory, cursor: Position) -> Option<SymbolLocation> {
+    memory.references
+        .iter()
+        .find(|x| x.range.contains_position(cursor))
+        .map(|x| x.declaration.clone())
+}
+
+pub fn get_references<'a>(
+    memory: &'a Memory,
+    declaration: &'a SymbolLocation,
+    include_declaration: bool
+) -> impl Iterator<Item = &'a SymbolReference> {
+    memory.references
+        .iter()
+        .filter(move |x| x.declaration == *declaration)
+        .filter(move |x| include_declaration || x.kind != ReferenceKind::Declaration)
+}
+
+pub fn get_document_highlights(memory: &Memory, cursor: Position) -> Vec<DocumentHighlight> {
+    let declaration = if let Some(x) = find_declaration_at(memory, cursor) { x }
+    else { return vec![] };
+
+    get_references(memory, &declaration, true)
+        .map(|x| DocumentHighlight {
+            range: x.range,
+            kind: Some(match x.kind {
+                ReferenceKind::Declaration => DocumentHighlightKind::TEXT,
+                ReferenceKind::Read => DocumentHighlightKind::READ,
+                ReferenceKind::Write => DocumentHighlightKind::WRITE,
+            })
+        })
+        .collect()
+}
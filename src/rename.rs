@@ -0,0 +1,112 @@
+use logos::Logos;
+use lsp_types::*;
+
+use crate::{
+    lexer::{ExtraRange, TokenKind},
+    memory::*,
+    references::get_references
+};
+
+/// Checks that the symbol under the cursor can be renamed, returning the
+/// range of its name. `Ok(None)` means there is nothing to rename there.
+pub fn prepare_rename(
+    memory: &Memory,
+    cursor: Position,
+    text: &str
+) -> Result<Option<Range>, String> {
+    if let Some(reference) = memory.references.iter().find(|x| x.range.contains_position(cursor)) {
+        Ok(Some(reference.range))
+    } else if is_builtin(memory, text) {
+        Err(format!("Cannot rename builtin '{}'.", text))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Ensures `new_name` is an identifier that would not collide with anything
+/// visible from where the declaration lives.
+pub fn validate_new_name(
+    memory: &Memory,
+    declaration: &SymbolLocation,
+    new_name: &str
+) -> Result<(), String> {
+    let mut lexer = TokenKind::lexer(new_name);
+    let is_identifier = lexer.next() == Some(Ok(TokenKind::Identifier))
+        && lexer.span().len() == new_name.len();
+    if !is_identifier {
+        return Err(format!("'{}' is not a valid identifier.", new_name));
+    }
+
+    let in_use = if let Some(fields) = find_struct_fields(memory, declaration) {
+        fields.iter().any(|x| x.name == new_name)
+    } else if let Some(scope) = find_declaring_scope(memory, declaration) {
+        memory.builtin_types.contains_key(new_name)
+            || memory.functions.contains_key(new_name)
+            || memory.structs.contains_key(new_name)
+            || visible_scopes(memory, scope)
+                .any(|x| memory.scopes.scopes[x].values.contains_key(new_name))
+    } else {
+        memory.is_id_in_use(new_name)
+            || memory.scopes.scopes.iter().any(|x| x.values.contains_key(new_name))
+    };
+    if in_use {
+        Err(format!("'{}' is already in use.", new_name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Edits that rename every reference to `declaration` in the given memory.
+pub fn get_rename_edits(
+    memory: &Memory,
+    declaration: &SymbolLocation,
+    new_name: &str
+) -> Vec<TextEdit> {
+    let mut edits: Vec<TextEdit> = vec![];
+    for reference in get_references(memory, declaration, true) {
+        if !edits.iter().any(|x| x.range == reference.range) {
+            edits.push(TextEdit::new(reference.range, new_name.to_string()));
+        }
+    }
+    edits
+}
+
+fn is_builtin(memory: &Memory, text: &str) -> bool {
+    memory.builtin_types.contains_key(text)
+        || memory.functions.get(text).is_some_and(|x| x.range.is_none())
+        || memory.scopes.scopes
+            .iter()
+            .any(|x| x.values.get(text).is_some_and(|x| x.range.is_none()))
+}
+
+fn find_struct_fields<'a>(
+    memory: &'a Memory,
+    declaration: &SymbolLocation
+) -> Option<&'a Vec<StructField>> {
+    memory.structs
+        .values()
+        .filter(|x| x.include_path == declaration.include_path)
+        .find(|x| x.fields.iter().any(|x| x.range == declaration.range))
+        .map(|x| &x.fields)
+}
+
+fn find_declaring_scope(memory: &Memory, declaration: &SymbolLocation) -> Option<usize> {
+    memory.scopes.scopes
+        .iter()
+        .position(|scope| scope.values.values().any(|x| {
+            x.range == Some(declaration.range) && x.include_path == declaration.include_path
+        }))
+        .filter(|&x| x != 0)
+}
+
+/// Scopes that can see, or be seen by, a local declared in `scope`. Since
+/// shadowing is not allowed, both ancestors and descendants count.
+fn visible_scopes(memory: &Memory, scope: usize) -> impl Iterator<Item = usize> + '_ {
+    let is_ancestor = move |mut current: usize, target: usize| loop {
+        if current == target { break true }
+        if current == 0 { break false }
+        current = memory.scopes.scopes[current].parent;
+    };
+    (0..memory.scopes.scopes.len())
+        .filter(move |&x| is_ancestor(scope, x) || is_ancestor(x, scope))
+}
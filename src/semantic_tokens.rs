@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+use lsp_types::*;
+
+use crate::{
+    lexer::{lex_tokens, ExtraRange, Token, TokenKind},
+    memory::{range_key, Memory, ReferenceKind, ScopeType, SymbolLocation, ValueInfo}
+};
+
+const TOKEN_TYPES: &[SemanticTokenType] = &[
+    SemanticTokenType::COMMENT,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::STRUCT,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::TYPE,
+    SemanticTokenType::MACRO,
+    SemanticTokenType::DECORATOR,
+    SemanticTokenType::ENUM_MEMBER,
+];
+
+const COMMENT: u32 = 0;
+const VARIABLE: u32 = 1;
+const PARAMETER: u32 = 2;
+const FUNCTION: u32 = 3;
+const STRUCT: u32 = 4;
+const PROPERTY: u32 = 5;
+const TYPE: u32 = 6;
+const MACRO: u32 = 7;
+const DECORATOR: u32 = 8;
+const ENUM_MEMBER: u32 = 9;
+
+/// The modifiers after the standard ones are our own: `constant` for values
+/// known at compile time, `writable` for builtins the current stage may
+/// assign, and `uniform` and `varying` for those kinds of globals.
+fn token_modifiers() -> Vec<SemanticTokenModifier> {
+    vec![
+        SemanticTokenModifier::DECLARATION,
+        SemanticTokenModifier::READONLY,
+        SemanticTokenModifier::DEFAULT_LIBRARY,
+        SemanticTokenModifier::new("constant"),
+        SemanticTokenModifier::new("writable"),
+        SemanticTokenModifier::new("uniform"),
+        SemanticTokenModifier::new("varying"),
+    ]
+}
+
+const DECLARATION: u32 = 1 << 0;
+const READONLY: u32 = 1 << 1;
+const DEFAULT_LIBRARY: u32 = 1 << 2;
+const CONSTANT: u32 = 1 << 3;
+const WRITABLE: u32 = 1 << 4;
+const UNIFORM: u32 = 1 << 5;
+const VARYING: u32 = 1 << 6;
+
+pub fn get_semantic_token_legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.to_vec(),
+        token_modifiers: token_modifiers()
+    }
+}
+
+/// A token to colour, before it is encoded relative to the one before it.
+struct ClassifiedToken {
+    line: u32,
+    start: u32,
+    length: u32,
+    token_type: u32,
+    modifiers: u32,
+}
+
+/// Colours the identifiers of an evaluated document by what they name, and
+/// marks the lines skipped by the preprocessor as comments. Only tokens that
+/// start inside `range` are returned, if it is given.
+pub fn get_semantic_tokens(
+    memory: &Memory,
+    inactive_ranges: &[Range],
+    range: Option<Range>
+) -> Vec<SemanticToken> {
+    let source = memory.get_source();
+    let is_inactive = |line: u32| inactive_ranges.iter().any(|x| x.start.line <= line && line < x.end.line);
+    let in_range = |position: Position| range.is_none_or(|x| x.start <= position && position < x.end);
+    let mut tokens = vec![];
+
+    for inactive in inactive_ranges {
+        for line in inactive.start.line..inactive.end.line {
+            let text = if let Some(x) = source.line_text(line) { x } else { break };
+            let length = source.encoding().text_width(text);
+            if length > 0 && in_range(Position::new(line, 0)) {
+                tokens.push(ClassifiedToken { line, start: 0, length, token_type: COMMENT, modifiers: 0 });
+            }
+        }
+    }
+
+    let raw_tokens = lex_tokens(source.get_code(), source.encoding());
+    let declarations = classify_declarations(memory);
+    let references: HashMap<_, _> = memory.references
+        .iter()
+        .map(|x| (range_key(x.range), x))
+        .collect();
+    let macros = collect_macro_names(memory, &raw_tokens);
+
+    let mut context = None;
+    for (idx, &token) in raw_tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Uniform | TokenKind::RenderMode => context = Some(token.kind),
+            TokenKind::Semicolon | TokenKind::Equal => context = None,
+            _ => {}
+        }
+        if token.kind != TokenKind::Identifier || is_inactive(token.range.start.line) || !in_range(token.range.start) {
+            continue;
+        }
+        let follows_hash = idx > 0 && raw_tokens[idx - 1].kind == TokenKind::Hash;
+        if follows_hash {
+            continue;
+        }
+        let name = source.token_text(token);
+
+        let classification = references
+            .get(&range_key(token.range))
+            .and_then(|reference| {
+                let (token_type, modifiers) = *declarations.get(&location_key(&reference.declaration))?;
+                let declaration = if reference.kind == ReferenceKind::Declaration { DECLARATION } else { 0 };
+                Some((token_type, modifiers | declaration))
+            })
+            .or_else(|| {
+                if macros.contains(&name) {
+                    Some((MACRO, 0))
+                } else if context == Some(TokenKind::RenderMode) && memory.valid_render_modes.contains_key(&name) {
+                    Some((ENUM_MEMBER, DEFAULT_LIBRARY))
+                } else if context == Some(TokenKind::Uniform) && memory.hints.contains_key(&name) {
+                    Some((DECORATOR, DEFAULT_LIBRARY))
+                } else {
+                    classify_builtin(memory, &name, token)
+                }
+            });
+
+        if let Some((token_type, modifiers)) = classification {
+            tokens.push(ClassifiedToken {
+                line: token.range.start.line,
+                start: token.range.start.character,
+                length: token.range.end.character - token.range.start.character,
+                token_type,
+                modifiers
+            });
+        }
+    }
+
+    encode_tokens(tokens)
+}
+
+/// Finds how to colour every symbol declared by the document or its
+/// includes, keyed by its declaration.
+fn classify_declarations(memory: &Memory) -> HashMap<(Option<String>, [u32; 4]), (u32, u32)> {
+    let mut declarations = HashMap::new();
+    for (idx, scope) in memory.scopes.scopes.iter().enumerate() {
+        for value in scope.values.values() {
+            let range = if let Some(range) = value.range { range } else { continue };
+            let is_parameter = matches!(scope.scope_type, ScopeType::Function(_))
+                && !scope.range.contains_position(range.start);
+            let classification = if is_parameter {
+                (PARAMETER, 0)
+            } else if idx == 0 && !value.is_const {
+                if value.editable { (VARIABLE, VARYING) } else { (VARIABLE, UNIFORM | READONLY) }
+            } else {
+                (VARIABLE, value_modifiers(value))
+            };
+            declarations.insert((value.include_path.clone(), range_key(range)), classification);
+        }
+    }
+    for function in memory.functions.values() {
+        if let Some(range) = function.range {
+            declarations.insert((function.include_path.clone(), range_key(range)), (FUNCTION, 0));
+        }
+    }
+    for info in memory.structs.values() {
+        declarations.insert((info.include_path.clone(), range_key(info.range)), (STRUCT, 0));
+        for field in &info.fields {
+            declarations.insert((info.include_path.clone(), range_key(field.range)), (PROPERTY, 0));
+        }
+    }
+    declarations
+}
+
+/// Builtin variables are looked up in the scope of the token, since which of
+/// them exist and which may be written depends on the stage.
+fn classify_builtin(memory: &Memory, name: &str, token: Token) -> Option<(u32, u32)> {
+    let scope = memory.scopes.find_scope_from_position(token.range.start);
+    let variable = memory.scopes
+        .collect_scopes_from(scope)
+        .into_iter()
+        .rev()
+        .find_map(|x| x.get(name));
+    if let Some(value) = variable.filter(|x| x.range.is_none()) {
+        let writable = if value.editable { WRITABLE } else { 0 };
+        return Some((VARIABLE, DEFAULT_LIBRARY | value_modifiers(value) | writable));
+    }
+    // A stage builtin read or written by a helper function.
+    if memory.builtin_uses.iter().any(|x| x.range == token.range && x.call_stages.is_none()) {
+        return Some((VARIABLE, DEFAULT_LIBRARY));
+    }
+    // Types are also builtin functions, as constructors.
+    if memory.builtin_types.contains_key(name) {
+        return Some((TYPE, DEFAULT_LIBRARY));
+    }
+    if memory.functions.get(name).is_some_and(|x| x.range.is_none()) {
+        return Some((FUNCTION, DEFAULT_LIBRARY));
+    }
+    None
+}
+
+fn value_modifiers(value: &ValueInfo) -> u32 {
+    match (value.is_const, value.editable) {
+        (true, _) => CONSTANT | READONLY,
+        (false, false) => READONLY,
+        (false, true) => 0
+    }
+}
+
+/// Every macro the document can see: those given by the client, those
+/// defined anywhere in the document, and those left over from its includes.
+fn collect_macro_names(memory: &Memory, raw_tokens: &[Token]) -> HashSet<String> {
+    let source = memory.get_source();
+    let defined = raw_tokens.windows(3).filter_map(|x| match x {
+        [hash, directive, name]
+            if hash.kind == TokenKind::Hash
+                && name.kind == TokenKind::Identifier
+                && source.token_text(*directive) == "define" => Some(source.token_text(*name)),
+        _ => None
+    });
+    memory.predefined_macros.keys().chain(memory.macros.keys()).cloned().chain(defined).collect()
+}
+
+fn encode_tokens(mut tokens: Vec<ClassifiedToken>) -> Vec<SemanticToken> {
+    tokens.sort_by_key(|x| (x.line, x.start));
+    let mut previous = (0, 0);
+    tokens
+        .into_iter()
+        .map(|x| {
+            let delta_line = x.line - previous.0;
+            let delta_start = if delta_line == 0 { x.start - previous.1 } else { x.start };
+            previous = (x.line, x.start);
+            SemanticToken {
+                delta_line,
+                delta_start,
+                length: x.length,
+                token_type: x.token_type,
+                token_modifiers_bitset: x.modifiers
+            }
+        })
+        .collect()
+}
+
+fn location_key(location: &SymbolLocation) -> (Option<String>, [u32; 4]) {
+    (location.include_path.clone(), range_key(location.range))
+}
@@ -0,0 +1,127 @@
+use lsp_types::*;
+
+use crate::{
+    interpreter::match_signature_prefix,
+    lexer::{Token, TokenKind, TokenStream},
+    memory::{FunctionSignature, Memory, TypeInfo},
+    parser::parse_expression
+};
+
+/// Lists every overload of the function whose call surrounds the cursor.
+/// The active signature is the first overload that accepts the types of the
+/// arguments written so far and has room for the argument being typed.
+pub fn get_signature_help(
+    memory: &mut Memory,
+    stream: &TokenStream,
+    cursor: Position
+) -> Option<SignatureHelp> {
+    let (identifier, commas) = find_enclosing_call(&stream.tokens, cursor)?;
+    let name = memory.get_token_text(stream.tokens[identifier]);
+    if !memory.functions.contains_key(&name) {
+        return None;
+    }
+
+    let scope = memory.scopes.find_scope_from_position(cursor);
+    let mut start = identifier + 2;
+    let mut arg_types = vec![];
+    for &comma in &commas {
+        arg_types.push(evaluate_argument(memory, stream, &stream.tokens[start..=comma], scope));
+        start = comma + 1;
+    }
+
+    let active_parameter = commas.len();
+    let function = memory.functions.get(&name)?;
+    let active_signature = function.signatures
+        .iter()
+        .position(|x| active_parameter < x.params.len().max(1) && match_signature_prefix(x, &arg_types))
+        .or_else(|| function.signatures.iter().position(|x| match_signature_prefix(x, &arg_types)))
+        .unwrap_or(0);
+    let documentation = function.description.clone().map(|x| {
+        Documentation::MarkupContent(MarkupContent { kind: MarkupKind::Markdown, value: x })
+    });
+    let signatures = function.signatures
+        .iter()
+        .map(|x| create_signature_information(&name, x, documentation.clone()))
+        .collect();
+
+    Some(SignatureHelp {
+        signatures,
+        active_signature: Some(active_signature as u32),
+        active_parameter: Some(active_parameter as u32)
+    })
+}
+
+/// Scans back from the cursor for the opening parenthesis of the call it is
+/// in. Returns the index of the function name, and of the commas that
+/// separate the arguments before the cursor.
+fn find_enclosing_call(tokens: &[Token], cursor: Position) -> Option<(usize, Vec<usize>)> {
+    let end = tokens.iter().position(|x| x.range.start >= cursor).unwrap_or(tokens.len());
+    let mut depth = 0;
+    let mut commas = vec![];
+    for idx in (0..end).rev() {
+        match tokens[idx].kind {
+            TokenKind::RightParen | TokenKind::RightBracket => depth += 1,
+            TokenKind::LeftParen | TokenKind::LeftBracket if depth > 0 => depth -= 1,
+            TokenKind::LeftParen if idx > 0 && tokens[idx - 1].kind == TokenKind::Identifier => {
+                commas.reverse();
+                return Some((idx - 1, commas));
+            }
+            // The cursor is inside parentheses or brackets within an
+            // argument, so the commas seen so far are not the call's.
+            TokenKind::LeftParen | TokenKind::LeftBracket => commas.clear(),
+            TokenKind::Comma if depth == 0 => commas.push(idx),
+            TokenKind::Semicolon | TokenKind::LeftBrace | TokenKind::RightBrace if depth == 0 => {
+                return None;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Evaluates an argument in the scope of the cursor, leaving the analysis of
+/// the document untouched. `tokens` ends with the comma after the argument,
+/// and the type is unknown unless the argument parses up to that comma.
+fn evaluate_argument(
+    memory: &mut Memory,
+    stream: &TokenStream,
+    tokens: &[Token],
+    scope: usize
+) -> Option<TypeInfo> {
+    let mut argument_stream = stream.sub_stream(tokens);
+    let expression = parse_expression(&mut argument_stream).ok()?;
+    if argument_stream.current_idx() != tokens.len() - 1 {
+        return None;
+    }
+    memory.scopes.force_scope(scope);
+    memory.evaluate_detached(expression).map(|x| x.type_info)
+}
+
+fn create_signature_information(
+    name: &str,
+    signature: &FunctionSignature,
+    documentation: Option<Documentation>
+) -> SignatureInformation {
+    let mut label = format!("{} {}(", signature.return_type.to_string(), name);
+    let mut parameters = vec![];
+    for (idx, param) in signature.params.iter().enumerate() {
+        if idx > 0 {
+            label.push_str(", ");
+        }
+        let start = label.encode_utf16().count() as u32;
+        label.push_str(&param.label());
+        let end = label.encode_utf16().count() as u32;
+        parameters.push(ParameterInformation {
+            label: ParameterLabel::LabelOffsets([start, end]),
+            documentation: None
+        });
+    }
+    label.push(')');
+
+    SignatureInformation {
+        label,
+        documentation,
+        parameters: Some(parameters),
+        active_parameter: None
+    }
+}
@@ -1,16 +1,24 @@
 use lsp_server::{Connection, Message, Notification};
 use lsp_types::*;
 
-use crate::{get_byte_offset_from_position, lexer::TokenError};
+use crate::{lexer::{Token, TokenError}, line_index::{LineIndex, PositionEncoding}};
 
+#[derive(Clone)]
 pub struct SourceDocument {
     code: String,
-    diagnostics: Vec<Diagnostic>
+    line_index: LineIndex,
+    diagnostics: Vec<Diagnostic>,
+    expansions: Vec<String>
 }
 impl SourceDocument {
-    pub fn new(source: &str) -> Self {
+    pub fn new(source: &str, encoding: PositionEncoding) -> Self {
         let diagnostics = vec![];
-        Self {diagnostics, code: source.to_string() }
+        Self {
+            diagnostics,
+            code: source.to_string(),
+            line_index: LineIndex::new(source, encoding),
+            expansions: vec![]
+        }
     }
 
     pub fn push_error<T>(&mut self, msg: &str, range: Range, error: T) -> T{
@@ -23,6 +31,32 @@ impl SourceDocument {
         TokenError::EofError
     }
 
+    pub fn push_warning(&mut self, msg: &str, range: Range) {
+        let mut diagnostic = create_diagnostic(range, msg);
+        diagnostic.severity = Some(DiagnosticSeverity::WARNING);
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// An error pointing at other places that took part in it.
+    pub fn push_related_error(
+        &mut self,
+        msg: &str,
+        range: Range,
+        related_information: Option<Vec<DiagnosticRelatedInformation>>
+    ) {
+        let mut diagnostic = create_diagnostic(range, msg);
+        diagnostic.related_information = related_information;
+        self.diagnostics.push(diagnostic);
+    }
+
+    pub fn push_unused_hint(&mut self, range: Range, name: &str) {
+        self.diagnostics.push(create_unused_hint(range, name));
+    }
+
+    pub fn push_inactive_region(&mut self, range: Range) {
+        self.diagnostics.push(create_inactive_hint(range));
+    }
+
     pub fn force_change_diagnostics(&mut self, diagnostics: Vec<Diagnostic>) {
         self.diagnostics = diagnostics;
     }
@@ -35,30 +69,88 @@ impl SourceDocument {
         &self.code
     }
 
+    /// The text of a token, looking it up in the expansion table if it came
+    /// from a macro.
+    pub fn token_text(&self, token: Token) -> String {
+        match token.expansion.and_then(|x| self.expansions.get(x)) {
+            Some(text) => text.clone(),
+            None => self.line_text(token.range.start.line).map_or("".to_string(), |x| token.text_in_line(x, self.encoding()))
+        }
+    }
+
+    /// How the columns of positions in this document are counted.
+    pub fn encoding(&self) -> PositionEncoding {
+        self.line_index.encoding()
+    }
+
+    /// The text of a line, without its line ending.
+    pub fn line_text(&self, line: u32) -> Option<&str> {
+        self.line_index.line_text(&self.code, line)
+    }
+
+    pub fn offset_at(&self, position: Position) -> usize {
+        self.line_index.offset(&self.code, position)
+    }
+
+    pub fn position_at(&self, offset: usize) -> Position {
+        self.line_index.position(&self.code, offset)
+    }
+
+    pub fn push_expansion(&mut self, text: String) -> usize {
+        self.expansions.push(text);
+        self.expansions.len() - 1
+    }
+
+    pub fn get_expansions(&self) -> &Vec<String> {
+        &self.expansions
+    }
+
+    pub fn set_expansions(&mut self, expansions: Vec<String>) {
+        self.expansions = expansions;
+    }
+
     pub fn add_diagnostics(&mut self, diagnostics: Vec<Diagnostic>) {
         self.diagnostics.extend(diagnostics)
     }
 
+    /// Applies an edit from the client. A change without a range replaces
+    /// the whole document.
     pub fn apply_change(&mut self, change: TextDocumentContentChangeEvent) {
-        if let Some(range) = change.range {
-            let start_byte = get_byte_offset_from_position(&self.code, range.start);
-            let end_byte = get_byte_offset_from_position(&self.code, range.end);
-            let new_text = &change.text;
-            if start_byte <= self.code.len() && end_byte <= self.code.len() && start_byte <= end_byte {
-                self.code.replace_range(start_byte..end_byte, new_text);
+        match change.range {
+            Some(range) => {
+                let start_byte = self.offset_at(range.start);
+                let end_byte = self.offset_at(range.end).max(start_byte);
+                self.code.replace_range(start_byte..end_byte, &change.text);
             }
+            None => self.code = change.text
         }
+        self.line_index = LineIndex::new(&self.code, self.encoding());
     }
 }
 
-fn create_unused_warning(range: Range, id_name: &str) -> Diagnostic {
+fn create_unused_hint(range: Range, id_name: &str) -> Diagnostic {
+    let message = format!("'{}' is unused.", id_name);
+    create_unnecessary_diagnostic(range, &message, DiagnosticSeverity::HINT)
+}
+
+fn create_inactive_hint(range: Range) -> Diagnostic {
+    let message = "Code is inactive due to preprocessor conditions.";
+    create_unnecessary_diagnostic(range, message, DiagnosticSeverity::HINT)
+}
+
+/// Editors fade out code covered by a diagnostic with the `UNNECESSARY` tag.
+fn create_unnecessary_diagnostic(
+    range: Range,
+    message: &str,
+    severity: DiagnosticSeverity
+) -> Diagnostic {
     Diagnostic {
         range,
-        severity: Some(DiagnosticSeverity::WARNING),
+        severity: Some(severity),
         code: None,
         code_description: None,
         source: Some("GDShaderServer".to_string()),
-        message: format!("'{}' is unused.", id_name),
+        message: message.to_string(),
         related_information: None,
         tags: Some(vec![DiagnosticTag::UNNECESSARY]),
         data: None,
@@ -79,11 +171,16 @@ fn create_diagnostic(range: Range, message: &str) -> Diagnostic {
     }
 }
 
-pub fn send_errors(connection: &Connection, uri: &Url, diagnostics: Vec<Diagnostic>) {
+pub fn send_errors(
+    connection: &Connection,
+    uri: &Url,
+    diagnostics: Vec<Diagnostic>,
+    version: Option<i32>
+) {
     let params = PublishDiagnosticsParams {
         uri: uri.clone(),
         diagnostics: diagnostics.clone(),
-        version: None,
+        version,
     };
 
     connection.sender.send(Message::Notification(Notification {
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use lsp_types::*;
+
+use crate::{includes::IncludeSources, lexer::{Token, TokenStream}, line_index::PositionEncoding, nodes::*, parse_tokens, project_files};
+
+/// A top-level declaration found while indexing the workspace.
+#[derive(Clone, Debug)]
+pub struct IndexedSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub range: Range,
+    /// Set for global uniforms, which are shared by the whole project.
+    pub container: Option<&'static str>,
+}
+
+/// The project settings file, whose `[shader_globals]` section declares the
+/// global uniforms.
+pub const PROJECT_FILE: &str = "project.godot";
+const SHADER_GLOBALS: &str = "shader_globals";
+
+/// The functions, structs and uniforms declared in every shader and shader
+/// include under the root directory, and the global uniforms of the project.
+pub struct WorkspaceIndex {
+    root_dir: Option<String>,
+    /// The encoding of the positions of the symbols.
+    encoding: PositionEncoding,
+    include_sources: IncludeSources,
+    files: HashMap<String, Vec<IndexedSymbol>>,
+}
+
+impl WorkspaceIndex {
+    pub fn new(include_sources: IncludeSources) -> Self {
+        Self { root_dir: None, encoding: PositionEncoding::default(), include_sources, files: HashMap::new() }
+    }
+
+    /// Drops the current index and parses every file under `root_dir`, with
+    /// positions counted in `encoding`.
+    pub fn index_root(&mut self, root_dir: &str, encoding: PositionEncoding) {
+        self.root_dir = Some(root_dir.to_string());
+        self.encoding = encoding;
+        self.files.clear();
+
+        for path in project_files(root_dir, &["gdshader", "gdshaderinc"]) {
+            if let (Some(path), Ok(code)) = (path.to_str(), fs::read_to_string(&path)) {
+                self.index_file(path, &code);
+            }
+        }
+
+        let project_file = Path::new(root_dir).join(PROJECT_FILE);
+        if let (Some(path), Ok(code)) = (project_file.to_str(), fs::read_to_string(&project_file)) {
+            self.index_project_settings(path, &code);
+        }
+    }
+
+    pub fn index_file(&mut self, path: &str, code: &str) {
+        let root_dir = self.root_dir.as_deref();
+        let mut stream = TokenStream::preprocessed(code, None, self.encoding, root_dir, &self.include_sources, HashMap::new());
+        let tree = parse_tokens(&mut stream);
+        let source = stream.get_source();
+        let symbol = |identifier: Token, kind: SymbolKind| IndexedSymbol {
+            name: source.token_text(identifier),
+            kind,
+            range: identifier.range,
+            container: None
+        };
+
+        let symbols = tree.iter().filter_map(|node| match node {
+            TopLevelNode::Function(x) => Some(symbol(x.identifier, SymbolKind::FUNCTION)),
+            TopLevelNode::Struct(x) => Some(symbol(x.identifier, SymbolKind::STRUCT)),
+            TopLevelNode::Uniform(x) => {
+                let is_global = x.global_instance.is_some_and(|x| source.token_text(x) == "global");
+                let container = if is_global { Some(SHADER_GLOBALS) } else { None };
+                Some(IndexedSymbol { container, ..symbol(x.value.identifier, SymbolKind::VARIABLE) })
+            }
+            _ => None
+        }).collect();
+        self.files.insert(path.to_string(), symbols);
+    }
+
+    /// Indexes the global uniforms of the `[shader_globals]` section of a
+    /// project file, each of which starts a line as `name={`.
+    pub fn index_project_settings(&mut self, path: &str, code: &str) {
+        let mut in_section = false;
+        let mut symbols = vec![];
+        for (line, text) in code.lines().enumerate() {
+            if text.starts_with('[') {
+                in_section = text.trim_end() == format!("[{}]", SHADER_GLOBALS);
+                continue;
+            }
+            let name = match text.split_once('=') {
+                Some((name, _)) if in_section => name,
+                _ => continue
+            };
+            let is_identifier = name.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+                && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_');
+            if is_identifier {
+                let start = Position::new(line as u32, 0);
+                symbols.push(IndexedSymbol {
+                    name: name.to_string(),
+                    kind: SymbolKind::VARIABLE,
+                    range: Range::new(start, Position::new(line as u32, name.len() as u32)),
+                    container: Some(SHADER_GLOBALS)
+                });
+            }
+        }
+        self.files.insert(path.to_string(), symbols);
+    }
+
+    pub fn remove_file(&mut self, path: &str) {
+        self.files.remove(path);
+    }
+
+    /// Finds symbols whose names fuzzily match `query`, best matches first.
+    pub fn search(&self, query: &str) -> Vec<SymbolInformation> {
+        let mut matches = vec![];
+        for (path, symbols) in &self.files {
+            let uri = if let Ok(uri) = Url::from_file_path(path) { uri } else { continue };
+            for symbol in symbols {
+                if let Some(score) = fuzzy_score(query, &symbol.name) {
+                    matches.push((score, create_symbol_information(symbol, &uri)));
+                }
+            }
+        }
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        matches.into_iter().map(|(_, x)| x).collect()
+    }
+}
+
+#[allow(deprecated)]
+fn create_symbol_information(symbol: &IndexedSymbol, uri: &Url) -> SymbolInformation {
+    SymbolInformation {
+        name: symbol.name.clone(),
+        kind: symbol.kind,
+        tags: None,
+        deprecated: None,
+        location: Location::new(uri.clone(), symbol.range),
+        container_name: symbol.container.map(|x| x.to_string())
+    }
+}
+
+/// Scores `name` against `query` as a case-insensitive subsequence, or
+/// returns `None` if it does not match. Consecutive characters and the
+/// starts of words score higher, and shorter names win ties.
+fn fuzzy_score(query: &str, name: &str) -> Option<i32> {
+    let name: Vec<char> = name.chars().collect();
+    let mut score = 0;
+    let mut next = 0;
+    let mut previous: Option<usize> = None;
+    for query_char in query.chars().map(|x| x.to_ascii_lowercase()) {
+        let found = (next..name.len()).find(|&i| name[i].to_ascii_lowercase() == query_char)?;
+        score += 10;
+        if previous.is_some_and(|x| x + 1 == found) {
+            score += 15;
+        }
+        let word_start = found == 0
+            || name[found - 1] == '_'
+            || name[found].is_uppercase() && name[found - 1].is_lowercase();
+        if word_start {
+            score += 10;
+        }
+        previous = Some(found);
+        next = found + 1;
+    }
+    Some(score - name.len() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_matches_are_ordered_by_how_well_they_match() {
+        assert_eq!(fuzzy_score("xyz", "get_color"), None);
+        assert_eq!(fuzzy_score("GC", "get_color"), fuzzy_score("gc", "get_color"));
+        // Consecutive characters.
+        assert!(fuzzy_score("col", "colxxxxx") > fuzzy_score("col", "cxoxlxxx"));
+        // The starts of words, after an underscore or in camel case.
+        assert!(fuzzy_score("gc", "get_color") > fuzzy_score("gc", "getxcolor"));
+        assert!(fuzzy_score("gc", "getColor") > fuzzy_score("gc", "getcolor"));
+        // Shorter names win ties.
+        assert!(fuzzy_score("col", "color") > fuzzy_score("col", "colors"));
+    }
+
+    #[test]
+    fn project_settings_declare_the_global_uniforms() {
+        let code = "\
+config_version=5
+
+[application]
+
+config/name=\"Game\"
+
+[shader_globals]
+
+wind_strength={
+\"type\": \"float\",
+\"value\": 1.0
+}
+noise_texture={
+\"type\": \"sampler2D\",
+\"value\": \"\"
+}
+
+[rendering]
+
+textures/canvas_textures/default_texture_filter=0
+";
+        let mut index = WorkspaceIndex::new(IncludeSources::new());
+        index.index_project_settings("/project/project.godot", code);
+        let symbols = &index.files["/project/project.godot"];
+        let found: Vec<(&str, Range)> = symbols.iter().map(|x| (x.name.as_str(), x.range)).collect();
+        assert_eq!(found, vec![
+            ("wind_strength", Range::new(Position::new(8, 0), Position::new(8, 13))),
+            ("noise_texture", Range::new(Position::new(12, 0), Position::new(12, 13))),
+        ]);
+        assert!(symbols.iter().all(|x| x.container == Some(SHADER_GLOBALS)));
+    }
+
+    #[test]
+    fn hidden_directories_are_not_indexed() {
+        let root = std::env::temp_dir().join(format!("gdshader-lsp-index-{}", std::process::id()));
+        _ = fs::remove_dir_all(&root);
+        for (path, code) in [
+            ("a.gdshader", "shader_type spatial;\nvoid visible() {}\n"),
+            ("lib/b.gdshaderinc", "struct Shared { float x; };\n"),
+            (".godot/c.gdshader", "shader_type spatial;\nvoid hidden() {}\n"),
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, code).unwrap();
+        }
+
+        let mut index = WorkspaceIndex::new(IncludeSources::new());
+        index.index_root(&root.to_string_lossy(), PositionEncoding::default());
+        let names: Vec<String> = index.search("").into_iter().map(|x| x.name).collect();
+        assert_eq!(names, vec!["Shared", "visible"]);
+        _ = fs::remove_dir_all(&root);
+    }
+}